@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use crate::sinks::LedSink;
+
+use super::Animation;
+use crate::command::AnimationParams;
+use crate::utils::{rgb_to_raw, scaled_wait_time, Ticker};
+
+/// This struct represents the whole segment blinking in a single colour,
+/// white by default. It is used to identify the controller.
+pub struct Blink {
+    length: i32,
+    color: (u8, u8, u8),
+    lit: bool,
+    speed: f64,
+    ticker: Ticker,
+    running: bool,  // Becomes false when the animation should stop
+}
+
+impl Blink {
+    pub fn new(length: i32, params: &AnimationParams) -> Blink {
+        Blink {
+            length,
+            color: params.color.unwrap_or((255, 255, 255)),
+            lit: false,
+            speed: params.speed,
+            ticker: Ticker::default(),
+            running: false,
+        }
+    }
+
+    /// Lights the segment up or turns it off
+    fn draw(&self, sink: &mut dyn LedSink) {
+        let led = if self.lit { rgb_to_raw(self.color) } else { [0, 0, 0, 0] };
+        let leds = sink.leds_mut(0);
+        for x in 0..self.length {
+            leds[x as usize] = led;
+        }
+    }
+}
+
+impl Animation for Blink {
+    fn next_frame(&mut self, sink: &mut dyn LedSink, dt: Duration) -> bool {
+        if !self.running {
+            // Stops right away, a blink has nothing to fade
+            self.lit = false;
+            self.draw(sink);
+            return false;
+        }
+
+        if self.ticker.steps(dt, self.wait_time()) % 2 == 1 {
+            self.lit = !self.lit;
+        }
+        self.draw(sink);
+
+        true
+    }
+
+    fn start(&mut self) {
+        self.running = true;
+        self.lit = true;
+    }
+
+    fn stop(&mut self) {
+        self.running = false;
+    }
+
+    fn stopping(&self) -> bool {
+        !self.running
+    }
+
+    fn name(&self) -> &str {
+        "blink"
+    }
+
+    fn wait_time(&self) -> u64 {
+        scaled_wait_time(250, self.speed)
+    }
+}
@@ -1,7 +1,10 @@
-use rs_ws281x::Controller;
+use std::time::Duration;
+
+use crate::sinks::LedSink;
 
 use super::Animation;
-use crate::utils::hue_to_rgb;
+use crate::command::AnimationParams;
+use crate::utils::{hue_to_rgb, rgb_to_raw, scaled_wait_time, Ticker};
 
 enum STATUS {
     OFF,
@@ -13,6 +16,9 @@ pub struct Chase {
     status: STATUS,  // 0 off, 1 build up, 2 fade out
     current_index: i32,
     strip_length: i32,
+    color: Option<(u8, u8, u8)>,  // Replaces the hue gradient when set
+    speed: f64,
+    ticker: Ticker,
     running: bool,  // Becomes false when the animation should stop
 }
 
@@ -20,18 +26,32 @@ pub struct Chase {
 // 1. Build up: The first led is lit up, then the second, then the third, etc.
 // 2. Fade out: The first led is turned off, then the second, then the third, etc.
 impl Chase {
-    pub fn new(strip_length: i32) -> Chase {
+    pub fn new(strip_length: i32, params: &AnimationParams) -> Chase {
         Chase {
             status: STATUS::OFF,
             current_index: 0,
             strip_length,
+            color: params.color,
+            speed: params.speed,
+            ticker: Ticker::default(),
             running: false,
         }
     }
-}
 
-impl Animation for Chase {
-    fn next_frame(&mut self, controller: &mut Controller) -> bool {
+    /// Returns the colour of the led at the given index
+    fn color_at(&self, index: i32) -> [u8; 4] {
+        match self.color {
+            Some(color) => rgb_to_raw(color),
+            None => {
+                let angle = (index as f32 / self.strip_length as f32) * 360.0;
+                let res = hue_to_rgb(angle as f64, 1.0, 0.5);
+                [res.0, res.1, res.2, 0]
+            }
+        }
+    }
+
+    /// Advances the animation by one step
+    fn step(&mut self, sink: &mut dyn LedSink) -> bool {
         match self.status {
             STATUS::OFF => {
                 self.running = false;
@@ -39,11 +59,10 @@ impl Animation for Chase {
             },
             STATUS::BUILDUP => {
                 self.running = true;
-                let leds = controller.leds_mut(0);
+                let color = self.color_at(self.current_index);
+                let leds = sink.leds_mut(0);
                 if self.current_index < self.strip_length && leds[self.current_index as usize] == [0, 0, 0, 0] {
-                    let angle = (self.current_index as f32 / self.strip_length as f32) * 360.0;
-                    let res = hue_to_rgb(angle as f64, 1.0, 0.5);
-                    leds[self.current_index as usize] = [res.0, res.1, res.2, 0];
+                    leds[self.current_index as usize] = color;
                     if self.current_index > 0 {
                         leds[(self.current_index - 1) as usize] = [0, 0, 0, 0];
                     }
@@ -60,20 +79,19 @@ impl Animation for Chase {
                 true
             },
             STATUS::FADEOUT => {
-                let leds = controller.leds_mut(0);
+                let color = self.color_at(self.current_index);
+                let leds = sink.leds_mut(0);
                 if self.current_index >= 0 {
                     // Light up previous led & light off the current index's one
                     leds[self.current_index as usize] = [0, 0, 0, 0];
                     if self.current_index > 0 {
-                        let angle = (self.current_index as f32 / self.strip_length as f32) * 360.0;
-                        let res = hue_to_rgb(angle as f64, 1.0, 0.5);
-                        leds[(self.current_index - 1) as usize] = [res.0, res.1, res.2, 0];
+                        leds[(self.current_index - 1) as usize] = color;
                     }
                     self.current_index -= 1;
                     true
                 } else {
                     // find first leds that is lit up
-                    let leds = controller.leds_mut(0);
+                    let leds = sink.leds_mut(0);
                     for index in 0..self.strip_length {
                         if leds[index as usize] != [0, 0, 0, 0] {
                             self.current_index = index;
@@ -89,6 +107,20 @@ impl Animation for Chase {
             },
         }
     }
+}
+
+impl Animation for Chase {
+    fn next_frame(&mut self, sink: &mut dyn LedSink, dt: Duration) -> bool {
+        let mut running = true;
+        for _ in 0..self.ticker.steps(dt, self.wait_time()) {
+            running = self.step(sink);
+            if !running {
+                break;
+            }
+        }
+
+        running
+    }
 
     fn start(&mut self) -> () {
         self.running = true;
@@ -110,9 +142,92 @@ impl Animation for Chase {
 
     fn wait_time(&self) -> u64 {
         if self.running {
-            10
+            scaled_wait_time(10, self.speed)
         } else {
             0
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sinks::FrameBuffer;
+
+    const DT: Duration = Duration::from_millis(10);
+    const OFF: [u8; 4] = [0, 0, 0, 0];
+    /// `#ff8800` in the driver's layout
+    const C: [u8; 4] = [0x00, 0x88, 0xff, 0];
+
+    fn chase(length: i32) -> Chase {
+        let params = AnimationParams { color: Some((0xff, 0x88, 0x00)), ..AnimationParams::default() };
+        let mut chase = Chase::new(length, &params);
+        chase.start();
+        chase
+    }
+
+    #[test]
+    fn builds_up_then_fades_out() {
+        let mut sink = FrameBuffer::new(&[3]);
+        let mut chase = chase(3);
+
+        let expected = [
+            // Build up, each LED running to the end of the strip
+            [C, OFF, OFF],
+            [OFF, C, OFF],
+            [OFF, OFF, C],
+            [OFF, OFF, C],
+            [C, OFF, C],
+            [OFF, C, C],
+            [OFF, C, C],
+            [C, C, C],
+            [C, C, C],
+            // Fade out, each LED running back to the start of the strip
+            [C, C, C],
+            [OFF, C, C],
+            [OFF, C, C],
+            [C, OFF, C],
+            [OFF, OFF, C],
+            [OFF, OFF, C],
+            [OFF, C, OFF],
+            [C, OFF, OFF],
+            [OFF, OFF, OFF],
+            // And again
+            [OFF, OFF, OFF],
+            [C, OFF, OFF],
+        ];
+        for (i, leds) in expected.iter().enumerate() {
+            assert!(chase.next_frame(&mut sink, DT), "stopped at frame {}", i);
+            sink.render().unwrap();
+            assert_eq!(sink.leds(0), leds, "frame {}", i);
+        }
+        assert_eq!(sink.frames(), expected.len());
+    }
+
+    #[test]
+    fn uses_the_hue_gradient_without_colour() {
+        let mut sink = FrameBuffer::new(&[4]);
+        let mut chase = Chase::new(4, &AnimationParams::default());
+        chase.start();
+
+        chase.next_frame(&mut sink, DT);
+        assert_eq!(sink.leds(0), &[[255, 0, 0, 0], OFF, OFF, OFF]);
+        chase.next_frame(&mut sink, DT);
+        assert_eq!(sink.leds(0), &[OFF, [127, 255, 0, 0], OFF, OFF]);
+    }
+
+    #[test]
+    fn fades_out_at_once_when_stopped() {
+        let mut sink = FrameBuffer::new(&[3]);
+        let mut chase = chase(3);
+        for _ in 0..8 {
+            chase.next_frame(&mut sink, DT);
+        }
+        assert_eq!(sink.leds(0), &[C, C, C]);
+
+        chase.stop();
+        assert!(chase.stopping());
+        assert!(!chase.next_frame(&mut sink, DT));
+        assert_eq!(sink.leds(0), &[OFF; 3]);
+    }
+}
@@ -0,0 +1,330 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::sinks::LedSink;
+
+use super::Animation;
+use crate::command::{AnimationParams, MAX_SPEED};
+use crate::config::AnimationDefinition;
+use crate::utils::{parse_hex_color, scaled_wait_time, Ticker};
+
+const MAX_LEVEL: u16 = 127;
+/// Number of steps of a cycle of the motion, at the speed of 1.0
+const CYCLE_STEPS: f64 = 250.0;
+/// Chance of a LED to sparkle at each step, at the speed of 1.0
+const SPARKLE_CHANCE: f64 = 0.01;
+/// Part of its light a sparkle keeps at each step
+const SPARKLE_DECAY: f64 = 0.93;
+
+/// How the colours of a declared animation move
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Motion {
+    /// The gradient stays in place
+    Static,
+    /// The gradient scrolls along the segment, looping
+    Scroll,
+    /// The whole gradient fades in and out
+    Breathe,
+    /// Random LEDs light up in the colour of the gradient at their position, then fade out
+    Sparkle,
+}
+
+/// An animation defined in the configuration, checked and ready to be rendered
+pub struct Design {
+    name: String,
+    /// Positions along the segment and their colours, sorted by position
+    stops: Vec<(f64, (u8, u8, u8))>,
+    /// Whether the last colour blends back into the first one
+    wrap: bool,
+    motion: Motion,
+    speed: f64,
+    reversed: bool,
+}
+
+impl Design {
+    /// Checks an animation definition and compiles it
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name the animation is registered under
+    /// * `definition` - The definition of the configuration
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Design, String>` - The compiled animation, or the reason it is invalid
+    pub fn compile(name: &str, definition: &AnimationDefinition) -> Result<Design, String> {
+        let color = |c: &str| parse_hex_color(c).ok_or(format!("color must be formatted as #rrggbb, got `{}`", c));
+
+        let motion = match definition.motion.as_str() {
+            "static" => Motion::Static,
+            "scroll" => Motion::Scroll,
+            "breathe" => Motion::Breathe,
+            "sparkle" => Motion::Sparkle,
+            other => return Err(format!("unknown motion `{}`, use static, scroll, breathe or sparkle", other)),
+        };
+        let reversed = match definition.direction.as_str() {
+            "forward" => false,
+            "reverse" => true,
+            other => return Err(format!("unknown direction `{}`, use forward or reverse", other)),
+        };
+        if !definition.speed.is_finite() || definition.speed <= 0.0 || definition.speed > MAX_SPEED {
+            return Err(format!("speed must be in ]0, {}], got {}", MAX_SPEED, definition.speed));
+        }
+
+        let (mut stops, wrap) = match (definition.gradient.is_empty(), definition.palette.is_empty()) {
+            (false, true) => {
+                let stops = definition.gradient.iter().map(|stop| {
+                    if !(0.0..=1.0).contains(&stop.at) {
+                        return Err(format!("gradient positions must be in [0, 1], got {}", stop.at));
+                    }
+                    Ok((stop.at, color(&stop.color)?))
+                }).collect::<Result<Vec<_>, String>>()?;
+                (stops, motion == Motion::Scroll)
+            },
+            (true, false) => {
+                let count = definition.palette.len() as f64;
+                let stops = definition.palette.iter().enumerate()
+                    .map(|(i, c)| Ok((i as f64 / count, color(c)?)))
+                    .collect::<Result<Vec<_>, String>>()?;
+                (stops, true)
+            },
+            _ => return Err("either a gradient or a palette is needed".to_string()),
+        };
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        Ok(Design {
+            name: name.to_string(),
+            stops,
+            wrap,
+            motion,
+            speed: definition.speed,
+            reversed,
+        })
+    }
+
+    /// Returns the colour of the gradient at a position along the segment, in [0, 1[
+    fn color_at(&self, position: f64) -> (u8, u8, u8) {
+        let (first, last) = (self.stops[0], self.stops[self.stops.len() - 1]);
+        let mix = |a: (f64, (u8, u8, u8)), b: (f64, (u8, u8, u8)), position: f64| {
+            let t = if b.0 > a.0 { (position - a.0) / (b.0 - a.0) } else { 0.0 };
+            let channel = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+            (channel(a.1.0, b.1.0), channel(a.1.1, b.1.1), channel(a.1.2, b.1.2))
+        };
+
+        if position < first.0 || position >= last.0 {
+            if !self.wrap {
+                return if position < first.0 { first.1 } else { last.1 };
+            }
+            // Between the last stop and the first one of the next loop
+            let position = if position < first.0 { position + 1.0 } else { position };
+            return mix(last, (first.0 + 1.0, first.1), position);
+        }
+
+        let next = self.stops.iter().position(|s| s.0 > position).unwrap_or(self.stops.len() - 1);
+        mix(self.stops[next.saturating_sub(1)], self.stops[next], position)
+    }
+}
+
+/// This struct renders an animation defined in the configuration: a gradient
+/// or a palette along the segment, moving in one of the `Motion` ways
+pub struct Declarative {
+    length: i32,
+    design: Arc<Design>,
+    /// Progress through the current cycle of the motion, in [0, 1[
+    phase: f64,
+    /// Light left of the sparkle of each LED
+    sparkles: Vec<f64>,
+    level: u16,  // Fades in when starting and out when stopping
+    stopping: bool,
+    speed: f64,
+    ticker: Ticker,
+    running: bool,  // Becomes false when the animation should stop
+}
+
+impl Declarative {
+    pub fn new(length: i32, params: &AnimationParams, design: Arc<Design>) -> Declarative {
+        Declarative {
+            length,
+            design,
+            phase: 0.0,
+            sparkles: vec![0.0; length.max(0) as usize],
+            level: 0,
+            stopping: false,
+            speed: params.speed,
+            ticker: Ticker::default(),
+            running: false,
+        }
+    }
+
+    /// Advances the animation by one step
+    fn step(&mut self, sink: &mut dyn LedSink) -> bool {
+        if self.stopping {
+            if self.level > 0 {
+                self.level -= 1;
+            } else {
+                self.running = false;
+            }
+        } else if self.level < MAX_LEVEL {
+            self.level += 1;
+        }
+        self.phase = (self.phase + self.design.speed / CYCLE_STEPS).fract();
+
+        let design = &self.design;
+        let breath = match design.motion {
+            Motion::Breathe => 0.1 + 0.9 * (1.0 - (2.0 * PI * self.phase).cos()) / 2.0,
+            _ => 1.0,
+        };
+        let level = self.level as f64 / MAX_LEVEL as f64;
+        let leds = sink.leds_mut(0);
+        for x in 0..self.length {
+            let position = (x as f64 + 0.5) / self.length as f64;
+            let position = match design.motion {
+                Motion::Scroll if design.reversed => (position + self.phase).fract(),
+                Motion::Scroll => (position - self.phase).rem_euclid(1.0),
+                _ => position,
+            };
+
+            let intensity = match design.motion {
+                Motion::Sparkle => {
+                    let sparkle = &mut self.sparkles[x as usize];
+                    *sparkle *= SPARKLE_DECAY;
+                    if rand::random::<f64>() < SPARKLE_CHANCE * design.speed {
+                        *sparkle = 1.0;
+                    }
+                    *sparkle
+                },
+                _ => breath,
+            };
+
+            let color = design.color_at(position);
+            let scale = |c: u8| (c as f64 * intensity * level) as u8;
+            leds[x as usize] = [scale(color.2), scale(color.1), scale(color.0), 0];
+        }
+
+        self.running
+    }
+}
+
+impl Animation for Declarative {
+    fn next_frame(&mut self, sink: &mut dyn LedSink, dt: Duration) -> bool {
+        let mut running = true;
+        for _ in 0..self.ticker.steps(dt, self.wait_time()) {
+            running = self.step(sink);
+            if !running {
+                break;
+            }
+        }
+
+        running
+    }
+
+    fn start(&mut self) {
+        self.running = true;
+        self.stopping = false;
+        self.level = 0;
+    }
+
+    fn stop(&mut self) {
+        self.stopping = true;
+    }
+
+    fn stopping(&self) -> bool {
+        self.stopping
+    }
+
+    fn name(&self) -> &str {
+        &self.design.name
+    }
+
+    fn wait_time(&self) -> u64 {
+        scaled_wait_time(20, self.speed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sinks::FrameBuffer;
+
+    fn compile(definition: &str) -> Result<Design, String> {
+        let definition: AnimationDefinition = toml::from_str(definition).unwrap();
+        Design::compile("test", &definition)
+    }
+
+    fn rejection(definition: &str) -> String {
+        compile(definition).err().expect("the definition should be rejected")
+    }
+
+    #[test]
+    fn rejects_invalid_definitions() {
+        let palette = r##"palette = ["#ff0000"]"##;
+        assert!(rejection(&format!("{}\nmotion = \"spin\"", palette)).contains("unknown motion `spin`"));
+        assert!(rejection(&format!("{}\ndirection = \"up\"", palette)).contains("unknown direction `up`"));
+        for speed in ["0.0", "-1.0", "11.0", "nan", "inf"] {
+            assert!(rejection(&format!("{}\nspeed = {}", palette, speed)).starts_with("speed must be"), "{}", speed);
+        }
+        assert!(rejection(r##"palette = ["red"]"##).contains("`red`"));
+        assert!(rejection(r##"gradient = [{ at = 1.5, color = "#ff0000" }]"##).contains("gradient positions"));
+        assert!(rejection(r##"gradient = [{ at = -0.1, color = "#ff0000" }]"##).contains("gradient positions"));
+
+        let both = r##"
+            palette = ["#ff0000"]
+            gradient = [{ at = 0.0, color = "#0000ff" }]
+        "##;
+        assert_eq!(rejection(both), "either a gradient or a palette is needed");
+        assert_eq!(rejection(""), "either a gradient or a palette is needed");
+    }
+
+    #[test]
+    fn interpolates_between_gradient_stops() {
+        let design = compile(r##"
+            gradient = [{ at = 0.75, color = "#c86432" }, { at = 0.25, color = "#000000" }]
+        "##).unwrap();
+
+        assert_eq!(design.color_at(0.5), (100, 50, 25));
+        assert_eq!(design.color_at(0.25), (0, 0, 0));
+        // A static gradient keeps its end colours beyond the stops
+        assert_eq!(design.color_at(0.1), (0, 0, 0));
+        assert_eq!(design.color_at(0.9), (200, 100, 50));
+    }
+
+    #[test]
+    fn palette_wraps_from_the_last_colour_to_the_first() {
+        let design = compile(r##"palette = ["#ff0000", "#0000ff"]"##).unwrap();
+
+        assert_eq!(design.color_at(0.0), (255, 0, 0));
+        assert_eq!(design.color_at(0.5), (0, 0, 255));
+        assert_eq!(design.color_at(0.25), (128, 0, 128));
+        assert_eq!(design.color_at(0.75), (128, 0, 128));
+        assert_eq!(design.color_at(0.999), (254, 0, 1));
+    }
+
+    #[test]
+    fn single_colour_palette_is_uniform() {
+        let design = compile(r##"palette = ["#10a0ff"]"##).unwrap();
+
+        for position in [0.0, 0.3, 0.5, 0.99] {
+            assert_eq!(design.color_at(position), (0x10, 0xa0, 0xff));
+        }
+    }
+
+    #[test]
+    fn static_motion_renders_the_gradient() {
+        let design = compile(r##"
+            gradient = [{ at = 0.0, color = "#ff0000" }, { at = 1.0, color = "#0000ff" }]
+        "##).unwrap();
+        let mut sink = FrameBuffer::new(&[4]);
+        let mut animation = Declarative::new(4, &AnimationParams::default(), Arc::new(design));
+        animation.start();
+        for _ in 0..3 {
+            assert!(animation.next_frame(&mut sink, Duration::from_secs(1)));
+        }
+
+        let frame = [[32, 0, 223, 0], [96, 0, 159, 0], [159, 0, 96, 0], [223, 0, 32, 0]];
+        assert_eq!(sink.leds(0), frame);
+        // The gradient does not move
+        assert!(animation.next_frame(&mut sink, Duration::from_secs(1)));
+        assert_eq!(sink.leds(0), frame);
+    }
+}
@@ -1,16 +1,24 @@
-use rs_ws281x::Controller;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::command::AnimationParams;
+use crate::sinks::LedSink;
+
+/// Builds an animation for a segment of the given length
+pub type AnimationFactory = Arc<dyn Fn(i32, &AnimationParams) -> Box<dyn Animation>>;
 
 pub trait Animation {
-    /// Computes and renders the next frame of the animation to the controller
+    /// Computes and renders the next frame of the animation to the sink
     ///
     /// # Arguments
     ///
-    /// * `controller` - The controller to render the next frame to
+    /// * `sink` - The LED sink to render the next frame to
+    /// * `dt` - The time elapsed since the previous frame
     ///
     /// # Returns
     ///
     /// * `bool` - True if the animation is still running, false otherwise
-    fn next_frame(&mut self, controller: &mut Controller) -> bool;
+    fn next_frame(&mut self, sink: &mut dyn LedSink, dt: Duration) -> bool;
 
     /// Starts the animation
     fn start(&mut self) -> ();
@@ -24,16 +32,24 @@ pub trait Animation {
     /// Returns the name of the animation
     fn name(&self) -> &str;
 
-    /// Time to wait between to animation frames
+    /// Duration of a step of the animation, in milliseconds
     fn wait_time(&self) -> u64;
 }
 
+mod blink;
 mod chase;
+mod declarative;
 mod off;
+mod progress;
 mod rainbow;
+mod solid;
 mod static_rainbow;
 
+pub use blink::Blink;
 pub use chase::Chase;
+pub use declarative::{Declarative, Design};
 pub use off::Off;
+pub use progress::Progress;
 pub use rainbow::Rainbow;
+pub use solid::Solid;
 pub use static_rainbow::SRainbow;
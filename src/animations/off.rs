@@ -1,4 +1,6 @@
-use rs_ws281x::Controller;
+use std::time::Duration;
+
+use crate::sinks::LedSink;
 use super::Animation;
 
 /// This structure represents the off animation
@@ -15,8 +17,8 @@ impl Off {
 }
 
 impl Animation for Off {
-    fn next_frame(&mut self, controller: &mut Controller) -> bool {
-        let leds = controller.leds_mut(0);
+    fn next_frame(&mut self, sink: &mut dyn LedSink, _dt: Duration) -> bool {
+        let leds = sink.leds_mut(0);
         for led in leds.iter_mut() {
             *led = [0, 0, 0, 0];
         }
@@ -44,3 +46,40 @@ impl Animation for Off {
         20
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sinks::FrameBuffer;
+
+    const DT: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn clears_the_strip_every_frame() {
+        let mut sink = FrameBuffer::new(&[3]);
+        sink.leds_mut(0).fill([1, 2, 3, 4]);
+        let mut off = Off::new();
+        off.start();
+
+        assert!(off.next_frame(&mut sink, DT));
+        assert_eq!(sink.leds(0), &[[0, 0, 0, 0]; 3]);
+
+        sink.leds_mut(0)[1] = [9, 9, 9, 9];
+        assert!(off.next_frame(&mut sink, Duration::ZERO));
+        assert_eq!(sink.leds(0), &[[0, 0, 0, 0]; 3]);
+    }
+
+    #[test]
+    fn stops_right_away() {
+        let mut sink = FrameBuffer::new(&[3]);
+        let mut off = Off::new();
+        off.start();
+        assert!(!off.stopping());
+
+        off.stop();
+        assert!(off.stopping());
+        assert!(!off.next_frame(&mut sink, DT));
+        sink.render().unwrap();
+        assert_eq!(sink.frames(), 1);
+    }
+}
@@ -0,0 +1,173 @@
+use std::time::Duration;
+
+use crate::sinks::LedSink;
+
+use super::Animation;
+use crate::command::AnimationParams;
+use crate::printer::{PhaseColors, PrintPhase, PrintProgress};
+use crate::utils::{scaled_wait_time, Ticker};
+
+const MAX_LEVEL: u16 = 127;
+/// Number of steps of a pulse of the paused and error phases
+const PULSE_STEPS: u16 = 100;
+
+/// This struct represents the progress of the print, filling the segment in
+/// proportion to it in the colour of the phase of the print. A paused print
+/// breathes, a failed one blinks, and a done one lights the whole segment.
+/// The colour of the command replaces the printing colour.
+pub struct Progress {
+    length: i32,
+    progress: PrintProgress,
+    colors: PhaseColors,
+    level: u16,  // Fades in when starting and out when stopping
+    pulse: u16,
+    stopping: bool,
+    speed: f64,
+    ticker: Ticker,
+    running: bool,  // Becomes false when the animation should stop
+}
+
+impl Progress {
+    pub fn new(length: i32, params: &AnimationParams, progress: PrintProgress, colors: PhaseColors) -> Progress {
+        Progress {
+            length,
+            progress,
+            colors: PhaseColors {
+                printing: params.color.unwrap_or(colors.printing),
+                ..colors
+            },
+            level: 0,
+            pulse: 0,
+            stopping: false,
+            speed: params.speed,
+            ticker: Ticker::default(),
+            running: false,
+        }
+    }
+
+    /// Advances the animation by one step
+    fn step(&mut self, sink: &mut dyn LedSink) -> bool {
+        if self.stopping {
+            if self.level > 0 {
+                self.level -= 1;
+            } else {
+                self.running = false;
+            }
+        } else if self.level < MAX_LEVEL {
+            self.level += 1;
+        }
+        self.pulse = (self.pulse + 1) % PULSE_STEPS;
+
+        let (phase, progress) = self.progress.get();
+        let intensity = match phase {
+            PrintPhase::Paused => 0.3 + 0.7 * (2.0 * self.pulse as f64 / PULSE_STEPS as f64 - 1.0).abs(),
+            PrintPhase::Error if self.pulse >= PULSE_STEPS / 2 => 0.2,
+            _ => 1.0,
+        };
+        let filled = match phase {
+            PrintPhase::Done | PrintPhase::Error => self.length as f64,
+            _ => progress / 100.0 * self.length as f64,
+        };
+
+        let color = self.colors.of(phase).unwrap_or((0, 0, 0));
+        let leds = sink.leds_mut(0);
+        for x in 0..self.length {
+            // The LED at the edge of the filled part is partially lit
+            let amount = (filled - x as f64).clamp(0.0, 1.0) * intensity * self.level as f64 / MAX_LEVEL as f64;
+            let scale = |c: u8| (c as f64 * amount) as u8;
+            leds[x as usize] = [scale(color.2), scale(color.1), scale(color.0), 0];
+        }
+
+        self.running
+    }
+}
+
+impl Animation for Progress {
+    fn next_frame(&mut self, sink: &mut dyn LedSink, dt: Duration) -> bool {
+        let mut running = true;
+        for _ in 0..self.ticker.steps(dt, self.wait_time()) {
+            running = self.step(sink);
+            if !running {
+                break;
+            }
+        }
+
+        running
+    }
+
+    fn start(&mut self) {
+        self.running = true;
+        self.stopping = false;
+        self.level = 0;
+    }
+
+    fn stop(&mut self) {
+        self.stopping = true;
+    }
+
+    fn stopping(&self) -> bool {
+        self.stopping
+    }
+
+    fn name(&self) -> &str {
+        "progress"
+    }
+
+    fn wait_time(&self) -> u64 {
+        scaled_wait_time(20, self.speed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sinks::FrameBuffer;
+
+    const COLORS: PhaseColors = PhaseColors {
+        heating: (255, 0, 0),
+        printing: (0, 0, 200),
+        paused: (255, 255, 0),
+        done: (0, 200, 0),
+        error: (200, 0, 0),
+    };
+
+    /// Runs the animation until it faded in, and returns the frame
+    fn render(progress: &PrintProgress, params: &AnimationParams, length: i32) -> Vec<[u8; 4]> {
+        let mut sink = FrameBuffer::new(&[length]);
+        let mut animation = Progress::new(length, params, progress.clone(), COLORS);
+        animation.start();
+        for _ in 0..3 {
+            assert!(animation.next_frame(&mut sink, Duration::from_secs(1)));
+        }
+        sink.leds(0).to_vec()
+    }
+
+    #[test]
+    fn fills_the_segment_in_proportion() {
+        let progress = PrintProgress::default();
+        progress.set_job(PrintPhase::Printing, Some(25.0));
+
+        let leds = render(&progress, &AnimationParams::default(), 10);
+        assert_eq!(leds[..4], [[200, 0, 0, 0], [200, 0, 0, 0], [100, 0, 0, 0], [0, 0, 0, 0]]);
+        assert!(leds[4..].iter().all(|led| *led == [0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn done_fills_the_whole_segment() {
+        let progress = PrintProgress::default();
+        progress.set_job(PrintPhase::Done, Some(100.0));
+        assert_eq!(render(&progress, &AnimationParams::default(), 4), [[0, 200, 0, 0]; 4]);
+
+        progress.set_job(PrintPhase::Idle, Some(0.0));
+        assert_eq!(render(&progress, &AnimationParams::default(), 4), [[0, 0, 0, 0]; 4]);
+    }
+
+    #[test]
+    fn uses_the_colour_of_the_command() {
+        let progress = PrintProgress::default();
+        progress.set_job(PrintPhase::Printing, Some(100.0));
+        let params = AnimationParams { color: Some((10, 20, 30)), ..AnimationParams::default() };
+
+        assert_eq!(render(&progress, &params, 2), [[30, 20, 10, 0]; 2]);
+    }
+}
@@ -1,36 +1,40 @@
-use rs_ws281x::Controller;
+use std::time::Duration;
+
+use crate::sinks::LedSink;
 
 use super::Animation;
-use crate::utils::hue_to_rgb;
+use crate::command::AnimationParams;
+use crate::utils::{hue_to_rgb, scaled_wait_time, Ticker};
 
 /// This struct represents a simple rainbow animation
 pub struct Rainbow {
     angle: i32,
-    strip_length: i32,
-    wheel_length: i32,
+    length: i32,
+    speed: f64,
+    ticker: Ticker,
     running: bool,  // Becomes false when the animation should stop
 }
 
 impl Rainbow {
-    pub fn new(strip_length: i32, wheel_length: i32) -> Rainbow {
+    pub fn new(length: i32, params: &AnimationParams) -> Rainbow {
         Rainbow {
             angle: 0,
-            strip_length,
-            wheel_length,
+            length,
+            speed: params.speed,
+            ticker: Ticker::default(),
             running: false,
         }
     }
-}
 
-impl Animation for Rainbow {
-    fn next_frame(&mut self, controller: &mut Controller) -> bool {
+    /// Advances the animation by one step
+    fn step(&mut self, sink: &mut dyn LedSink) -> bool {
         self.angle = (self.angle + 1) % 360;
         let mut still_running = self.running;
 
         {
-            let leds = controller.leds_mut(0);
+            let leds = sink.leds_mut(0);
             let mut last_led = [0, 0, 0, 0];
-            for index in 0..self.wheel_length {
+            for index in 0..self.length {
                 let current_led = leds[index as usize];
                 leds[index as usize] = last_led;
                 last_led = current_led;
@@ -38,34 +42,32 @@ impl Animation for Rainbow {
                     still_running = true;
                 }
             }
-            for index in self.wheel_length..self.strip_length {
-                if leds[index as usize][0] < 127 && self.running {
-                    for elem in 0..3 {
-                        leds[index as usize][elem] += 1;
-                    }
-                } else if leds[index as usize][0] > 0 && !self.running {
-                    for elem in 0..3 {
-                        leds[index as usize][elem] -= 1;
-                    }
-                } else {
-                    leds[index as usize] = [127, 127, 127, 0];
-                }
-                if leds[index as usize] != [0, 0, 0, 0] {
-                    still_running = true;
-                }
-            }
         }
         if self.running {
-            let leds = controller.leds_mut(0);
+            let leds = sink.leds_mut(0);
             let res = hue_to_rgb(self.angle as f64, 1.0, 0.5);
             leds[0] = [res.2, res.1, res.0, 0];
         } else {
-            let leds = controller.leds_mut(0);
+            let leds = sink.leds_mut(0);
             leds[0] = [0, 0, 0, 0]; // Turn off the first led (will propagate to the rest of the strip)
         }
 
         still_running
     }
+}
+
+impl Animation for Rainbow {
+    fn next_frame(&mut self, sink: &mut dyn LedSink, dt: Duration) -> bool {
+        let mut running = true;
+        for _ in 0..self.ticker.steps(dt, self.wait_time()) {
+            running = self.step(sink);
+            if !running {
+                break;
+            }
+        }
+
+        running
+    }
 
     fn start(&mut self) -> () {
         self.running = true;
@@ -84,6 +86,80 @@ impl Animation for Rainbow {
     }
 
     fn wait_time(&self) -> u64 {
-        20
+        scaled_wait_time(20, self.speed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sinks::FrameBuffer;
+
+    const DT: Duration = Duration::from_millis(20);
+    const OFF: [u8; 4] = [0, 0, 0, 0];
+
+    fn frame(rainbow: &mut Rainbow, sink: &mut FrameBuffer, dt: Duration) -> bool {
+        let running = rainbow.next_frame(sink, dt);
+        sink.render().unwrap();
+        running
+    }
+
+    #[test]
+    fn pushes_the_next_hue_at_the_start_of_the_strip() {
+        let mut sink = FrameBuffer::new(&[3]);
+        let mut rainbow = Rainbow::new(3, &AnimationParams::default());
+        rainbow.start();
+
+        assert!(frame(&mut rainbow, &mut sink, DT));
+        assert_eq!(sink.leds(0), &[[0, 4, 255, 0], OFF, OFF]);
+
+        assert!(frame(&mut rainbow, &mut sink, DT));
+        assert_eq!(sink.leds(0), &[[0, 8, 255, 0], [0, 4, 255, 0], OFF]);
+
+        assert!(frame(&mut rainbow, &mut sink, DT));
+        assert_eq!(sink.leds(0), &[[0, 12, 255, 0], [0, 8, 255, 0], [0, 4, 255, 0]]);
+        assert_eq!(sink.frames(), 3);
+    }
+
+    #[test]
+    fn steps_with_the_elapsed_time() {
+        let mut sink = FrameBuffer::new(&[3]);
+        let mut rainbow = Rainbow::new(3, &AnimationParams::default());
+        rainbow.start();
+
+        // Too short for a step, then enough for the step left over and two more
+        assert!(frame(&mut rainbow, &mut sink, Duration::from_millis(10)));
+        assert_eq!(sink.leds(0), &[OFF; 3]);
+        assert!(frame(&mut rainbow, &mut sink, Duration::from_millis(50)));
+        assert_eq!(sink.leds(0), &[[0, 12, 255, 0], [0, 8, 255, 0], [0, 4, 255, 0]]);
+    }
+
+    #[test]
+    fn doubles_the_steps_at_twice_the_speed() {
+        let mut sink = FrameBuffer::new(&[3]);
+        let params = AnimationParams { speed: 2.0, ..AnimationParams::default() };
+        let mut rainbow = Rainbow::new(3, &params);
+        rainbow.start();
+
+        assert!(frame(&mut rainbow, &mut sink, DT));
+        assert_eq!(sink.leds(0), &[[0, 8, 255, 0], [0, 4, 255, 0], OFF]);
+    }
+
+    #[test]
+    fn runs_dark_leds_down_the_strip_when_stopping() {
+        let mut sink = FrameBuffer::new(&[3]);
+        let mut rainbow = Rainbow::new(3, &AnimationParams::default());
+        rainbow.start();
+        frame(&mut rainbow, &mut sink, DT * 3);
+
+        rainbow.stop();
+        assert!(rainbow.stopping());
+        assert!(frame(&mut rainbow, &mut sink, DT));
+        assert_eq!(sink.leds(0), &[OFF, [0, 12, 255, 0], [0, 8, 255, 0]]);
+        assert!(frame(&mut rainbow, &mut sink, DT));
+        assert_eq!(sink.leds(0), &[OFF, OFF, [0, 12, 255, 0]]);
+        assert!(frame(&mut rainbow, &mut sink, DT));
+        assert_eq!(sink.leds(0), &[OFF; 3]);
+        assert!(!frame(&mut rainbow, &mut sink, DT));
     }
 }
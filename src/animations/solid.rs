@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use crate::sinks::LedSink;
+
+use super::Animation;
+use crate::command::AnimationParams;
+use crate::utils::{scaled_wait_time, Ticker};
+
+enum Status {
+    FadeIn,
+    Ongoing,
+    FadeOut
+}
+
+const MAX_LEVEL: u16 = 127;
+
+/// This struct represents a steady colour, fading in and out. Without a colour
+/// it lights the segment in white, like the print plate used to be.
+pub struct Solid {
+    length: i32,
+    color: (u8, u8, u8),
+    status: Status,
+    level: u16,
+    speed: f64,
+    ticker: Ticker,
+    running: bool,  // Becomes false when the animation should stop
+}
+
+impl Solid {
+    pub fn new(length: i32, params: &AnimationParams) -> Solid {
+        Solid {
+            length,
+            color: params.color.unwrap_or((127, 127, 127)),
+            status: Status::FadeIn,
+            level: 0,
+            speed: params.speed,
+            ticker: Ticker::default(),
+            running: false,
+        }
+    }
+
+    /// Advances the animation by one step
+    fn step(&mut self, sink: &mut dyn LedSink) -> bool {
+        match self.status {
+            Status::FadeIn => {
+                if self.level < MAX_LEVEL {
+                    self.level += 1;
+                } else {
+                    self.status = Status::Ongoing;
+                }
+            },
+            Status::FadeOut => {
+                if self.level > 0 {
+                    self.level -= 1;
+                } else {
+                    self.running = false;
+                }
+            },
+            _ => {}
+        }
+
+        let scale = |c: u8| (c as u16 * self.level / MAX_LEVEL) as u8;
+        let led = [scale(self.color.2), scale(self.color.1), scale(self.color.0), 0];
+        let leds = sink.leds_mut(0);
+        for x in 0..self.length {
+            leds[x as usize] = led;
+        }
+
+        self.running
+    }
+}
+
+impl Animation for Solid {
+    fn next_frame(&mut self, sink: &mut dyn LedSink, dt: Duration) -> bool {
+        let mut running = true;
+        for _ in 0..self.ticker.steps(dt, self.wait_time()) {
+            running = self.step(sink);
+            if !running {
+                break;
+            }
+        }
+
+        running
+    }
+
+    fn start(&mut self) {
+        self.running = true;
+        self.level = 0;
+        self.status = Status::FadeIn;
+    }
+
+    fn stop(&mut self) {
+        self.status = Status::FadeOut;
+    }
+
+    fn stopping(&self) -> bool {
+        matches!(self.status, Status::FadeOut)
+    }
+
+    fn name(&self) -> &str {
+        "solid"
+    }
+
+    fn wait_time(&self) -> u64 {
+        scaled_wait_time(20, self.speed)
+    }
+}
@@ -1,7 +1,10 @@
-use rs_ws281x::Controller;
+use std::time::Duration;
+
+use crate::sinks::LedSink;
 
 use super::Animation;
-use crate::utils::hue_to_rgb;
+use crate::command::AnimationParams;
+use crate::utils::{hue_to_rgb, scaled_wait_time, Ticker};
 
 enum STATUS {
     FADEIN,
@@ -14,37 +17,29 @@ const MAX_BRIGHTNESS: f64 = 127.0;
 /// This struct represents a simple srainbow animation
 pub struct SRainbow {
     angle: i32,
-    strip_length: i32,
-    wheel_length: i32,
+    length: i32,
     status: STATUS,
     brightness: u8,
+    speed: f64,
+    ticker: Ticker,
     running: bool,  // Becomes false when the animation should stop
 }
 
 impl SRainbow {
-    pub fn new(strip_length: i32, wheel_length: i32) -> SRainbow {
+    pub fn new(length: i32, params: &AnimationParams) -> SRainbow {
         SRainbow {
             angle: 0,
-            strip_length,
-            wheel_length,
+            length,
             status: STATUS::FADEIN,
             brightness: 0,
+            speed: params.speed,
+            ticker: Ticker::default(),
             running: false,
         }
     }
-}
-
-fn brightnessed(color: (u8, u8, u8), brightness: u8) -> [u8; 4] {
-    [
-        ((color.0 as f64) * (brightness as f64) / MAX_BRIGHTNESS) as u8,
-        ((color.1 as f64) * (brightness as f64) / MAX_BRIGHTNESS) as u8,
-        ((color.2 as f64) * (brightness as f64) / MAX_BRIGHTNESS) as u8,
-        0
-    ]
-}
 
-impl Animation for SRainbow {
-    fn next_frame(&mut self, controller: &mut Controller) -> bool {
+    /// Advances the animation by one step
+    fn step(&mut self, sink: &mut dyn LedSink) -> bool {
         self.angle = (self.angle + 1) % 360;
 
         match self.status {
@@ -65,17 +60,37 @@ impl Animation for SRainbow {
             _ => {}
         }
 
-        let leds = controller.leds_mut(0);
+        let leds = sink.leds_mut(0);
         let res = hue_to_rgb(self.angle as f64, 1.0, 0.5);
-        for x in 0..self.wheel_length {
+        for x in 0..self.length {
             leds[x as usize] = brightnessed(res, self.brightness);
         }
-        for x in self.wheel_length..self.strip_length {
-            leds[x as usize] = brightnessed((127, 127, 127), self.brightness);
-        }
 
         self.running
     }
+}
+
+fn brightnessed(color: (u8, u8, u8), brightness: u8) -> [u8; 4] {
+    [
+        ((color.0 as f64) * (brightness as f64) / MAX_BRIGHTNESS) as u8,
+        ((color.1 as f64) * (brightness as f64) / MAX_BRIGHTNESS) as u8,
+        ((color.2 as f64) * (brightness as f64) / MAX_BRIGHTNESS) as u8,
+        0
+    ]
+}
+
+impl Animation for SRainbow {
+    fn next_frame(&mut self, sink: &mut dyn LedSink, dt: Duration) -> bool {
+        let mut running = true;
+        for _ in 0..self.ticker.steps(dt, self.wait_time()) {
+            running = self.step(sink);
+            if !running {
+                break;
+            }
+        }
+
+        running
+    }
 
     fn start(&mut self) -> () {
         self.running = true;
@@ -96,6 +111,62 @@ impl Animation for SRainbow {
     }
 
     fn wait_time(&self) -> u64 {
-        20
+        scaled_wait_time(20, self.speed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sinks::FrameBuffer;
+
+    const DT: Duration = Duration::from_millis(20);
+
+    fn frame(srainbow: &mut SRainbow, sink: &mut FrameBuffer, dt: Duration) -> bool {
+        let running = srainbow.next_frame(sink, dt);
+        sink.render().unwrap();
+        running
+    }
+
+    #[test]
+    fn fades_in_with_the_same_colour_on_the_whole_strip() {
+        let mut sink = FrameBuffer::new(&[3]);
+        let mut srainbow = SRainbow::new(3, &AnimationParams::default());
+        srainbow.start();
+
+        assert!(frame(&mut srainbow, &mut sink, DT));
+        assert_eq!(sink.leds(0), &[[2, 0, 0, 0]; 3]);
+        assert!(frame(&mut srainbow, &mut sink, DT));
+        assert_eq!(sink.leds(0), &[[4, 0, 0, 0]; 3]);
+
+        // Fully faded in after 127 steps, at a hue of 127 degrees
+        for _ in 2..127 {
+            assert!(frame(&mut srainbow, &mut sink, DT));
+        }
+        assert_eq!(sink.leds(0), &[[0, 255, 29, 0]; 3]);
+        assert_eq!(sink.frames(), 127);
+    }
+
+    #[test]
+    fn fades_out_then_stops() {
+        let mut sink = FrameBuffer::new(&[2]);
+        let mut srainbow = SRainbow::new(2, &AnimationParams::default());
+        srainbow.start();
+        for _ in 0..127 {
+            frame(&mut srainbow, &mut sink, DT);
+        }
+
+        srainbow.stop();
+        assert!(srainbow.stopping());
+        assert!(frame(&mut srainbow, &mut sink, DT));
+        // A hue of 128 degrees, one step darker than full brightness
+        assert_eq!(sink.leds(0), &[[0, 252, 32, 0]; 2]);
+
+        let mut steps = 1;
+        while frame(&mut srainbow, &mut sink, DT) {
+            steps += 1;
+        }
+        assert_eq!(steps, 127);
+        assert_eq!(sink.leds(0), &[[0, 0, 0, 0]; 2]);
     }
 }
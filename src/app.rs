@@ -1,187 +1,853 @@
-use std::path::Path;
 use std::{thread, time};
-use std::sync::{Arc, Mutex};
-use rs_ws281x::{ControllerBuilder, ChannelBuilder, StripType};
-use std::env;
-use std::collections::HashMap;
+use std::sync::{mpsc, Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use rs_ws281x::{ControllerBuilder, ChannelBuilder};
+use std::collections::{BTreeMap, HashMap};
 use log::{info, error, warn};
 
-use rumqttc::{MqttOptions, Client, QoS};
-use super::animations;
-use super::config::Config;
+use rumqttc::{LastWill, MqttOptions, Client, QoS, SubscribeFilter};
+use serde_json::{json, Value};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use super::animations::{self, AnimationFactory};
+use super::command::{AnimationCommand, Command, Targets, RESERVED_NAMES};
+use super::compositor::SegmentStack;
+use super::persist::StateFile;
+use super::scene::Scenes;
+use super::schedule::{Schedule, SystemClock};
+use super::scheduler::Scheduler;
+use super::config::{AnimationDefinition, Config, Segment};
+use super::homeassistant;
+use super::control::ControlSocket;
+use super::dmx::{DmxMapping, DmxReceiver, Protocol};
+use super::http::{HttpApi, Preview};
+use super::preview::FrameStream;
+use super::printer::{PhaseColors, PrintProgress, PrinterApi, PrinterPoller};
+use super::realtime::RealtimeFrame;
+use super::wled::{WledInfo, WledReceiver};
+use super::state::{availability_topic, uptime, ControllerState, SegmentState, SharedState, StateReporter, OFFLINE, ONLINE};
+use super::sinks::{strip_type, LedSink, TerminalSink};
 
-type AnimationFactory = Arc<dyn Fn() -> Box<dyn animations::Animation>>;
+/// Layer the identify blink is drawn on, above any other
+const IDENTIFY_LAYER: usize = usize::MAX;
+/// Number of seconds the strip blinks when identifying the controller
+const IDENTIFY_DURATION: f64 = 3.0;
+/// Longest time the animations are given to fade out when shutting down
+const SHUTDOWN_TIMEOUT: time::Duration = time::Duration::from_secs(3);
+/// Longest time given to the last MQTT messages to be sent when shutting down
+const MQTT_FLUSH_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+/// Exit status when the controller could not start
+const EXIT_FAILURE: i32 = 1;
+
+/// Registers the handlers of the signals stopping the controller. A first
+/// signal asks for a graceful shutdown, a second one terminates right away.
+///
+/// # Returns
+///
+/// * `Arc<AtomicUsize>` - The number of the signal received, 0 until then
+fn register_shutdown_signals() -> Arc<AtomicUsize> {
+    let received = Arc::new(AtomicUsize::new(0));
+    let shutting_down = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        // The handlers run in order, so the first one only exits on the second signal
+        let registered = signal_hook::flag::register_conditional_shutdown(signal, 128 + signal, Arc::clone(&shutting_down))
+            .and_then(|_| signal_hook::flag::register(signal, Arc::clone(&shutting_down)))
+            .and_then(|_| signal_hook::flag::register_usize(signal, Arc::clone(&received), signal as usize));
+        if let Err(e) = registered {
+            warn!("Unable to register the handler of signal {}: {}", signal, e);
+        }
+    }
+
+    received
+}
 
 pub struct App {
     config: Config,
     animation_factories: HashMap<String, AnimationFactory>,
-    current_animation: Box<dyn animations::Animation>,
-    next_animation_name: Arc<Mutex<String>>
+    stacks: Vec<SegmentStack>,
+    /// What commands can refer to, shared with the threads parsing them
+    targets: Arc<RwLock<Targets>>,
+    commands: mpsc::Sender<Command>,
+    /// The commands received since the last frame, applied before the next one
+    pending_commands: mpsc::Receiver<Command>,
+    /// The named scenes, configured or saved at runtime
+    scenes: Scenes,
+    /// The last state of the segments, restored on startup
+    state_file: StateFile,
+    /// Runs the commands of the `[[schedule]]` rules, unless suspended by a manual command
+    schedule: Schedule,
+    state_reporter: Option<StateReporter>,
+    /// The last published state, read by the HTTP API
+    state: SharedState,
+    /// The rendered frames, streamed by the HTTP API when the preview is enabled
+    frames: Option<FrameStream>,
+    /// Frames streamed by lighting software, shown instead of the animations
+    realtime: RealtimeFrame,
+    realtime_source: Option<&'static str>,
+    /// The progress of the print, shown by the `progress` animation
+    print_progress: PrintProgress,
+    started_at: time::Instant,
+    dropped_frames: u64,
 }
 
-impl App {
-    pub fn new() -> App {
-        let config = Config::default();
+/// Builds the factories of all the available animations, indexed by name
+///
+/// # Arguments
+///
+/// * `print_progress` - The progress of the print, shown by the `progress` animation
+/// * `colors` - The colours of the phases of a print
+/// * `definitions` - The animations defined in the configuration, the invalid ones being logged and ignored
+fn build_animation_factories(print_progress: &PrintProgress, colors: PhaseColors, definitions: &BTreeMap<String, AnimationDefinition>) -> HashMap<String, AnimationFactory> {
+    let print_progress = print_progress.clone();
+    let mut animation_factories: HashMap<String, AnimationFactory> = HashMap::new();
+    animation_factories.insert("rainbow".to_string(), Arc::new(|length, params| Box::new(animations::Rainbow::new(length, params))));
+    animation_factories.insert("srainbow".to_string(), Arc::new(|length, params| Box::new(animations::SRainbow::new(length, params))));
+    animation_factories.insert("off".to_string(), Arc::new(|_, _| Box::new(animations::Off::new())));
+    animation_factories.insert("chase".to_string(), Arc::new(|length, params| Box::new(animations::Chase::new(length, params))));
+    animation_factories.insert("solid".to_string(), Arc::new(|length, params| Box::new(animations::Solid::new(length, params))));
+    animation_factories.insert("blink".to_string(), Arc::new(|length, params| Box::new(animations::Blink::new(length, params))));
+    animation_factories.insert("progress".to_string(), Arc::new(move |length, params| Box::new(animations::Progress::new(length, params, print_progress.clone(), colors))));
 
-        let wheel_length = config.get_wheel_length();
-        let strip_length = config.get_strip_length();
+    for (name, definition) in definitions {
+        if animation_factories.contains_key(name) {
+            error!("Ignoring animation {}: it has the name of a built-in animation", name);
+            continue;
+        }
+        if RESERVED_NAMES.contains(&name.as_str()) {
+            error!("Ignoring animation {}: it has the name of a command", name);
+            continue;
+        }
+        match animations::Design::compile(name, definition) {
+            Ok(design) => {
+                let design = Arc::new(design);
+                animation_factories.insert(name.clone(), Arc::new(move |length, params| Box::new(animations::Declarative::new(length, params, Arc::clone(&design)))));
+            },
+            Err(e) => error!("Ignoring animation {}: {}", name, e),
+        }
+    }
+
+    animation_factories
+}
 
-        // Build animation factories
-        let mut animation_factories: HashMap<String, AnimationFactory> = HashMap::new();
-        animation_factories.insert("rainbow".to_string(), Arc::new(move || Box::new(animations::Rainbow::new(strip_length, wheel_length))));
-        animation_factories.insert("srainbow".to_string(), Arc::new(move || Box::new(animations::SRainbow::new(strip_length, wheel_length))));
-        animation_factories.insert("off".to_string(), Arc::new(move || Box::new(animations::Off::new())));
-        animation_factories.insert("chase".to_string(), Arc::new(move || Box::new(animations::Chase::new(wheel_length))));
+impl App {
+    pub fn new(config: Config) -> App {
+        let print_progress = PrintProgress::default();
+        let animation_factories = build_animation_factories(&print_progress, PhaseColors::new(config.get_printer()), config.get_animations());
 
-        let off_animation = animation_factories.get("off").unwrap()();
+        let stacks: Vec<SegmentStack> = config.get_segments().into_iter()
+            .map(|segment| SegmentStack::new(segment, &animation_factories))
+            .collect();
+        let scenes = Scenes::load(config.get_scenes(), &config.get_scenes_path());
+        let mut targets = Targets {
+            animations: animation_factories.keys().cloned().collect(),
+            segments: stacks.iter().map(|s| s.segment.clone()).collect(),
+            scenes: vec![],
+        };
+        targets.scenes = scenes.names(&targets);
+        let (commands, pending_commands) = mpsc::channel();
+        let realtime = RealtimeFrame::new(&config.get_channel_lengths());
+        let schedule = Schedule::new(config.get_schedule(), &targets, config.get_location(), config.get_schedule_override(), Box::new(SystemClock));
+        let state_file = StateFile::load(&config.get_state_path());
 
         App {
             config,
             animation_factories,
-            current_animation: off_animation,
-            next_animation_name: Arc::new(Mutex::new("".to_string())),
+            stacks,
+            targets: Arc::new(RwLock::new(targets)),
+            commands,
+            pending_commands,
+            scenes,
+            state_file,
+            schedule,
+            state_reporter: None,
+            state: SharedState::default(),
+            frames: None,
+            realtime,
+            realtime_source: None,
+            print_progress,
+            started_at: time::Instant::now(),
+            dropped_frames: 0,
         }
     }
 
-    #[allow(dead_code)]
-    pub fn from_file<T>(config_path: T) -> App
-    where
-        T: AsRef<Path>,
-    {
-        let config = Config::from_file(config_path);
-
-        let wheel_length = config.get_wheel_length();
-        let strip_length = config.get_strip_length();
+    /// Returns what commands sent to this controller can refer to
+    fn targets(&self) -> Targets {
+        match self.targets.read() {
+            Ok(t) => t.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
 
-        // Build animation factories
-        let mut animation_factories: HashMap<String, AnimationFactory> = HashMap::new();
-        animation_factories.insert("rainbow".to_string(), Arc::new(move || Box::new(animations::Rainbow::new(strip_length, wheel_length))));
-        animation_factories.insert("srainbow".to_string(), Arc::new(move || Box::new(animations::SRainbow::new(strip_length, wheel_length))));
-        animation_factories.insert("off".to_string(), Arc::new(move || Box::new(animations::Off::new())));
-        animation_factories.insert("chase".to_string(), Arc::new(move || Box::new(animations::Chase::new(wheel_length))));
+    pub fn start_mqtt_listener(&mut self) {
+        let commands = self.commands.clone();
+        let shared_targets = Arc::clone(&self.targets);
+        let animations = self.targets().animations;
 
-        let off_animation = animation_factories.get("off").unwrap()();
+        // MQTT
+        let device_name = self.config.get_device_name().to_string();
+        let mqtt_host = self.config.get_mqtt_host().to_string();
+        let mqtt_port = self.config.get_mqtt_port();
+        let mqtt_channel = self.config.get_mqtt_channel().to_string();
 
-        App {
-            config,
-            animation_factories,
-            current_animation: off_animation,
-            next_animation_name: Arc::new(Mutex::new("".to_string())),
-        }
-    }
+        let printer = self.config.get_printer();
+        let octoprint_topic = if printer.mqtt { Some(printer.mqtt_topic.trim_end_matches('/').to_string()) } else { None };
+        let print_progress = self.print_progress.clone();
 
-    pub fn start_mqtt_listener(&self) {
-        let next_animation_name = Arc::clone(&self.next_animation_name);
+        let state = self.state.clone();
+        let started_at = self.started_at;
+        let homeassistant = self.config.get_homeassistant_discovery();
+        let homeassistant_topic = homeassistant::command_topic(&mqtt_channel);
+        let discovery_config = homeassistant::discovery_config(&device_name, &mqtt_channel, &animations);
+        let discovery_topic = homeassistant::discovery_topic(&device_name);
 
-        thread::spawn(move || {
-            // MQTT
-            let device_name = env::var("DEVICE_NAME").unwrap_or("DDD_leds".to_string());
-            let mqtt_host = env::var("MQTT_HOST").unwrap_or("localhost".to_string());
-            let mqtt_port = env::var("MQTT_PORT").unwrap_or("1883".to_string()).parse::<u16>().unwrap_or(1883);
-            let mqtt_channel = env::var("MQTT_CHANNEL").unwrap_or("home/leds".to_string());
+        // The broker marks the controller offline if it goes away without disconnecting
+        let availability_topic = availability_topic(&mqtt_channel);
+        let mut mqttoptions = MqttOptions::new(device_name, mqtt_host, mqtt_port);
+        mqttoptions.set_keep_alive(time::Duration::new(60, 0));
+        mqttoptions.set_last_will(LastWill::new(&availability_topic, OFFLINE, QoS::AtLeastOnce, true));
 
-            let mut mqttoptions = MqttOptions::new(device_name, mqtt_host, mqtt_port);
-            mqttoptions.set_keep_alive(time::Duration::new(60, 0));
+        let (mut client, mut connection) = Client::new(mqttoptions, 10);
+        let (disconnected, disconnection) = mpsc::channel();
+        self.state_reporter = Some(StateReporter::new(client.clone(), &mqtt_channel, homeassistant, disconnection));
 
-            let (mut client, mut connection) = Client::new(mqttoptions, 10);
-            client.subscribe(&mqtt_channel, QoS::AtLeastOnce).unwrap();
+        let mut subscriptions = vec![SubscribeFilter::new(mqtt_channel.clone(), QoS::AtLeastOnce)];
+        if homeassistant {
+            subscriptions.push(SubscribeFilter::new(homeassistant_topic.clone(), QoS::AtLeastOnce));
+        }
+        if let Some(topic) = &octoprint_topic {
+            for filter in ["progress/printing", "event/+", "temperature/+"] {
+                subscriptions.push(SubscribeFilter::new(format!("{}/{}", topic, filter), QoS::AtMostOnce));
+            }
+        }
 
+        thread::spawn(move || {
             for notification in connection.iter() {
-                if let Ok(event) = notification {
-                    if let rumqttc::Event::Incoming(rumqttc::Packet::Publish(p)) = event {
+                match notification {
+                    Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_))) => {
+                        // The broker does not keep the session, so the subscriptions are made again on each connection
+                        if let Err(e) = client.try_subscribe_many(subscriptions.clone()) {
+                            warn!("Unable to subscribe to the command topics: {}", e);
+                        }
+                        if let Err(e) = client.try_publish(&availability_topic, QoS::AtLeastOnce, true, ONLINE) {
+                            warn!("Unable to publish the availability: {}", e);
+                        }
+                        if homeassistant {
+                            info!("Publishing Home Assistant discovery config on {}", discovery_topic);
+                            if let Err(e) = client.try_publish(&discovery_topic, QoS::AtLeastOnce, true, discovery_config.as_str()) {
+                                warn!("Unable to publish Home Assistant discovery config: {}", e);
+                            }
+                        }
+                    },
+                    Ok(rumqttc::Event::Incoming(rumqttc::Packet::Publish(p))) => {
                         if let Ok(s) = std::str::from_utf8(&p.payload) {
-                            let mut next_animation_name = match next_animation_name.lock() {
-                                Ok(n) => n,
+                            // Messages of the OctoPrint MQTT plugin update the progress of the print
+                            let octoprint = octoprint_topic.as_ref()
+                                .and_then(|topic| p.topic.strip_prefix(topic.as_str()))
+                                .and_then(|topic| topic.strip_prefix('/'));
+                            if let Some(topic) = octoprint {
+                                if let Err(e) = print_progress.update_octoprint(topic, s) {
+                                    warn!("{}", e);
+                                }
+                                continue;
+                            }
+
+                            let targets = match shared_targets.read() {
+                                Ok(t) => t.clone(),
+                                Err(e) => e.into_inner().clone(),
+                            };
+                            // Home Assistant commands only carry the fields that changed, so they apply on top of the running animation
+                            let parsed = if p.topic == homeassistant_topic {
+                                let base = homeassistant::base_command(&state.get(&started_at));
+                                homeassistant::parse_command(s, &base, &targets)
+                            } else {
+                                Command::parse(s, &targets)
+                            };
+                            let command = match parsed {
+                                Ok(c) => c,
                                 Err(e) => {
-                                    error!("Unable to lock next_animation_name: {}", e);
+                                    warn!("Rejected command `{}`: {}", s, e);
                                     continue;
                                 }
                             };
-                            *next_animation_name = s.to_string();
+
+                            if commands.send(command).is_err() {
+                                error!("The animation loop is gone, stopping the MQTT listener");
+                                return;
+                            }
                         }
+                    },
+                    Ok(rumqttc::Event::Outgoing(rumqttc::Outgoing::Disconnect)) => {
+                        let _ = disconnected.send(());
+                        return;
+                    },
+                    Ok(_) => {},
+                    Err(error) => {
+                        // The connection iterator reconnects on the next call, the subscriptions following the ConnAck
+                        warn!("Connection error {}\nTrying to reconnect...", error.to_string());
+                        continue;
                     }
-                } else if let Err(error) = notification {
-                    warn!("Connection error {}\nTrying to reconnect...", error.to_string());
-                    client.subscribe(&mqtt_channel, QoS::AtLeastOnce).unwrap();
-                    continue;
                 }
             }
         });
     }
 
-    pub fn run(&mut self) {
-        let mut controller: rs_ws281x::Controller = match ControllerBuilder::new()
-            .freq(800_000)
-            .dma(10)
+    /// Starts the HTTP API in its own thread, if enabled in the configuration
+    pub fn start_http_server(&mut self) {
+        let http = self.config.get_http();
+        if !http.enabled {
+            return;
+        }
+
+        let bind = http.bind.clone();
+        let preview = if http.preview {
+            let frames = FrameStream::default();
+            self.frames = Some(frames.clone());
+            Some(Preview {
+                frames,
+                layout: json!({
+                    "channels": self.config.get_channel_lengths(),
+                    "segments": self.stacks.iter().map(|s| &s.segment).collect::<Vec<_>>(),
+                }),
+                max_fps: http.preview_fps.max(1),
+            })
+        } else {
+            None
+        };
+        let wled = self.config.get_wled();
+        let wled = if wled.enabled {
+            Some(WledInfo {
+                name: self.config.get_device_name().to_string(),
+                channels: self.channel_lengths(),
+                fps: self.config.get_fps(),
+                udp_port: if wled.udp { wled.udp_bind.rsplit(':').next().and_then(|p| p.parse().ok()).unwrap_or(0) } else { 0 },
+            })
+        } else {
+            None
+        };
+        let api = HttpApi::new(self.commands.clone(), Arc::clone(&self.targets), self.state.clone(), self.started_at, preview, wled);
+        thread::spawn(move || api.serve(&bind));
+    }
+
+    /// Starts the control socket in its own thread, if enabled in the configuration
+    pub fn start_control_socket(&mut self) {
+        let socket = self.config.get_socket();
+        if !socket.enabled {
+            return;
+        }
+
+        let socket = socket.clone();
+        let control = ControlSocket::new(self.commands.clone(), Arc::clone(&self.targets), self.state.clone(), self.started_at);
+        thread::spawn(move || control.serve(&socket));
+    }
+
+    /// Starts the E1.31 or Art-Net receiver in its own thread, if enabled in the configuration
+    pub fn start_dmx_receiver(&mut self) {
+        let dmx = self.config.get_dmx();
+        if !dmx.enabled {
+            return;
+        }
+
+        let protocol = match Protocol::from_name(&dmx.protocol) {
+            Some(p) => p,
+            None => {
+                error!("Unknown DMX protocol `{}`, use e131 or artnet", dmx.protocol);
+                return;
+            }
+        };
+        let channel_length = match self.config.get_channel_lengths().get(dmx.channel) {
+            Some(l) => *l as usize,
+            None => {
+                error!("Invalid DMX configuration: channel {} does not exist", dmx.channel);
+                return;
+            }
+        };
+        let mapping = match DmxMapping::new(dmx, channel_length) {
+            Ok(m) => m,
+            Err(e) => {
+                error!("Invalid DMX configuration: {}", e);
+                return;
+            }
+        };
+
+        let receiver = match DmxReceiver::new(protocol, mapping, dmx, self.realtime.clone()) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Invalid DMX configuration: {}", e);
+                return;
+            }
+        };
+
+        let bind = dmx.bind.clone();
+        thread::spawn(move || receiver.serve(&bind));
+    }
+
+    /// Starts polling the progress of the print in its own thread, if enabled in the configuration
+    pub fn start_printer_poller(&mut self) {
+        let printer = self.config.get_printer();
+        if printer.poll == "none" {
+            return;
+        }
+
+        let api = match PrinterApi::from_name(&printer.poll) {
+            Some(a) => a,
+            None => {
+                error!("Unknown printer API `{}`, use none, moonraker or octoprint", printer.poll);
+                return;
+            }
+        };
+        let poller = match PrinterPoller::new(api, printer, self.print_progress.clone()) {
+            Ok(p) => p,
+            Err(e) => {
+                error!("Invalid printer configuration: {}", e);
+                return;
+            }
+        };
+        thread::spawn(move || poller.serve());
+    }
+
+    /// Starts the WLED UDP realtime receiver in its own thread, if enabled in the configuration
+    pub fn start_wled_receiver(&mut self) {
+        let wled = self.config.get_wled();
+        if !wled.enabled || !wled.udp {
+            return;
+        }
+
+        let bind = wled.udp_bind.clone();
+        let receiver = WledReceiver::new(self.channel_lengths(), self.realtime.clone());
+        thread::spawn(move || receiver.serve(&bind));
+    }
+
+    /// Returns the number of LEDs of each channel
+    fn channel_lengths(&self) -> Vec<usize> {
+        self.config.get_channel_lengths().iter().map(|l| (*l).max(0) as usize).collect()
+    }
+
+    /// Returns the payloads reproducing what the segments run, as saved in a
+    /// scene. The temporary layers and the overlays being removed are left out.
+    fn snapshot(&self) -> Vec<Value> {
+        self.stacks.iter()
+            .flat_map(|stack| stack.layers.iter())
+            .map(|layer| layer.target())
+            .filter(|c| c.layer != IDENTIFY_LAYER && c.duration.is_none() && !(c.layer > 0 && c.animation == "off"))
+            .map(|c| c.to_payload())
+            .collect()
+    }
+
+    /// Publishes the current state of the controller, to the HTTP API and to MQTT if connected.
+    /// The top level fields describe the base layer of the first segment.
+    fn publish_state(&mut self) {
+        if let Some(main) = self.stacks.first() {
+            let state = ControllerState {
+                animation: main.base().animation.name(),
+                stopping: main.base().animation.stopping(),
+                brightness: main.base().command.params.brightness,
+                color: main.base().command.params.color,
+                uptime: uptime(&self.started_at),
+                dropped_frames: self.dropped_frames,
+                realtime: self.realtime_source,
+                segments: self.stacks.iter().map(|s| SegmentState {
+                    segment: &s.segment.name,
+                    channel: s.segment.channel,
+                    animation: s.base().animation.name(),
+                    stopping: s.base().animation.stopping(),
+                    brightness: s.base().command.params.brightness,
+                    color: s.base().command.params.color,
+                    speed: s.base().command.params.speed,
+                    overlays: s.layers[1..].iter().map(|l| l.animation.name()).collect(),
+                }).collect(),
+            };
+
+            self.state.update(&state);
+            if let Some(reporter) = self.state_reporter.as_mut() {
+                reporter.publish(&state);
+            }
+        }
+    }
+
+    /// Runs the animation loop on the strip
+    ///
+    /// # Returns
+    ///
+    /// * `i32` - The exit status of the controller
+    pub fn run(&mut self) -> i32 {
+        let hardware = self.config.get_hardware();
+        if let Err(errors) = hardware.validate() {
+            for e in errors {
+                error!("Invalid hardware configuration: {}", e);
+            }
+            return EXIT_FAILURE;
+        }
+
+        let mut builder = ControllerBuilder::new();
+        builder
+            .freq(hardware.freq)
+            .dma(hardware.dma)
             .channel(
                 0,
                 ChannelBuilder::new()
-                    .pin(18)
+                    .pin(hardware.pin)
                     .count(self.config.get_strip_length())
-                    .strip_type(StripType::Ws2811Rgb)
-                    .brightness(127)
+                    .strip_type(strip_type(&hardware.strip_type).unwrap())
+                    .brightness(hardware.brightness)
                     .build()
-            )
-            .build() {
+            );
+        if let Some(channel1) = &hardware.channel1 {
+            builder.channel(
+                1,
+                ChannelBuilder::new()
+                    .pin(channel1.pin)
+                    .count(channel1.length)
+                    .strip_type(strip_type(&channel1.strip_type).unwrap())
+                    .brightness(channel1.brightness)
+                    .build()
+            );
+        }
+
+        let mut controller: rs_ws281x::Controller = match builder.build() {
             Ok(c) => c,
             Err(e) => {
                 error!("Unable to setup led controller: {}", e);
-                return;
+                return EXIT_FAILURE;
             }
         };
 
-        loop {
-            // Check if current animation is different to the next animation and that the current animation is not stopping
-            if self.current_animation.name().ne(self.next_animation_name.lock().unwrap().as_str()) && !self.current_animation.stopping() {
-                info!("Stopping animation: {}", self.current_animation.name());
-                self.current_animation.stop();
-            }
+        self.run_on(&mut controller)
+    }
 
-            // Save the result of next_frame to a variable so that we can check if the animation has changed
-            let res: bool = self.current_animation.next_frame(&mut controller);
+    /// Runs the animation loop in the terminal instead of on the strip
+    pub fn simulate(&mut self) -> i32 {
+        // The controller may run next to the simulation, its files are left alone
+        self.state_file.set_persistent(false);
+        self.scenes.set_persistent(false);
 
-            // If the animation stopped, we can use next_animation to start the next animation
-            if !res {
-                let next_animation = match self.next_animation_name.lock() {
-                    Ok(n) => n,
-                    Err(e) => {
-                        warn!("Unable to lock next_animation_name: {}", e);
-                        continue;
+        let segments: Vec<Segment> = self.stacks.iter().map(|s| s.segment.clone()).collect();
+        let mut sink = TerminalSink::new(&self.config.get_channel_lengths(), &segments);
+        let status = self.run_on(&mut sink);
+        println!();
+        status
+    }
+
+    /// Applies a command to the segments it targets
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the state of the controller changed right away
+    fn apply(&mut self, command: Command, sink: &mut dyn LedSink, scheduler: &mut Scheduler) -> bool {
+        let factories = &self.animation_factories;
+        let targets = match self.targets.read() {
+            Ok(t) => t.clone(),
+            Err(e) => e.into_inner().clone(),
+        };
+        let selected = |stack: &SegmentStack, segment: &Option<String>, channel: Option<usize>| {
+            segment.as_ref().is_none_or(|name| *name == stack.segment.name)
+                && channel.is_none_or(|channel| channel == stack.segment.channel)
+        };
+
+        match command {
+            Command::SetAnimation(command) => {
+                for command in targets.split(&command) {
+                    if let Some(stack) = self.stacks.iter_mut().find(|s| Some(&s.segment.name) == command.segment.as_ref()) {
+                        stack.push(command, factories);
                     }
+                }
+                false
+            },
+            Command::SetBrightness { segment, channel, brightness } => {
+                for stack in self.stacks.iter_mut().filter(|s| selected(s, &segment, channel)) {
+                    stack.set_brightness(brightness);
+                }
+                true
+            },
+            Command::SetColor { segment, channel, color } => {
+                for stack in self.stacks.iter_mut().filter(|s| selected(s, &segment, channel)) {
+                    stack.set_color(color, factories);
+                }
+                false
+            },
+            Command::Stop => {
+                info!("Stopping all the animations");
+                for stack in self.stacks.iter_mut() {
+                    stack.stop(factories);
+                }
+                false
+            },
+            Command::Reload => {
+                self.reload(sink, scheduler);
+                true
+            },
+            Command::Identify => {
+                info!("Identifying the controller");
+                let mut identify = AnimationCommand::new("blink");
+                identify.layer = IDENTIFY_LAYER;
+                identify.duration = Some(IDENTIFY_DURATION);
+                for stack in self.stacks.iter_mut() {
+                    let mut command = identify.clone();
+                    command.segment = Some(stack.segment.name.clone());
+                    command.channel = Some(stack.segment.channel);
+                    stack.push(command, factories);
+                }
+                false
+            },
+            Command::Scene(name) => match self.scenes.get(&name, &targets) {
+                Ok(commands) => {
+                    info!("Recalling scene {}", name);
+                    let mut changed = false;
+                    for command in commands {
+                        changed |= self.apply(command, sink, scheduler);
+                    }
+                    changed
+                },
+                Err(e) => {
+                    error!("Unable to recall scene {}: {}", name, e);
+                    false
+                },
+            },
+            Command::SaveScene(name) => {
+                let payloads = self.snapshot();
+                match self.scenes.save(&name, payloads) {
+                    Ok(_) => {
+                        if let Ok(mut targets) = self.targets.write() {
+                            targets.scenes = self.scenes.names(&targets);
+                        }
+                    },
+                    Err(e) => error!("Unable to save scene {}: {}", name, e),
+                }
+                false
+            },
+        }
+    }
+
+    /// Starts what the segments run on startup: the state of the previous run,
+    /// nothing or a scene, as configured with `startup`
+    fn startup(&mut self, sink: &mut dyn LedSink, scheduler: &mut Scheduler) {
+        let targets = self.targets();
+        let commands = match self.config.get_startup() {
+            "off" => return,
+            "restore" if self.state_file.commands().is_empty() => return,
+            "restore" => self.state_file.commands().iter().filter_map(|payload| {
+                let payload = match payload {
+                    Value::String(s) => s.clone(),
+                    other => other.to_string(),
                 };
+                Command::parse(&payload, &targets)
+                    .map_err(|e| warn!("Unable to restore `{}`: {}", payload, e))
+                    .ok()
+            }).collect(),
+            scene if targets.scenes.iter().any(|s| s == scene) => vec![Command::Scene(scene.to_string())],
+            other => {
+                warn!("Unknown startup `{}`, use restore, off or the name of a scene", other);
+                return;
+            },
+        };
 
-                // If the next animation is not empty, we can start it
-                if !next_animation.is_empty() {
-                    info!("Starting animation: {}", next_animation);
+        info!("Starting with {}", match self.config.get_startup() {
+            "restore" => "the state of the previous run".to_string(),
+            scene => format!("scene {}", scene),
+        });
+        for command in commands {
+            self.apply(command, sink, scheduler);
+        }
+    }
 
-                    // Get the animation factory from the hashmap
-                    let animation_factory = match self.animation_factories.get(next_animation.as_str()) {
-                        Some(f) => f,
-                        None => {
-                            warn!("Unable to find animation factory for animation: `{}` defaulting to off", next_animation);
-                            // Default to off and set string to off
+    /// Reloads the configuration, keeping the current one if the new one cannot be loaded.
+    /// The hardware and MQTT settings are only applied on restart.
+    fn reload(&mut self, sink: &mut dyn LedSink, scheduler: &mut Scheduler) {
+        let config = match self.config.reload() {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Unable to reload the configuration, keeping the current one: {}", e);
+                return;
+            }
+        };
+        info!("Reloaded the configuration");
 
-                            let mut next_animation_name = match self.next_animation_name.lock() {
-                                Ok(n) => n,
-                                Err(e) => {
-                                    error!("Unable to lock next_animation_name: {}", e);
-                                    continue;
-                                }
-                            };
-                            *next_animation_name = "off".to_string();
+        if config.get_channel_lengths() != self.config.get_channel_lengths() {
+            warn!("The length of the strips changed, restart the controller to apply it");
+        } else {
+            for channel in 0..config.get_channel_lengths().len() {
+                sink.set_brightness(channel, config.get_hardware().max_brightness(channel));
+            }
 
-                            self.animation_factories.get("off").unwrap()
-                        }
-                    };
+            self.animation_factories = build_animation_factories(&self.print_progress, PhaseColors::new(config.get_printer()), config.get_animations());
+
+            // Rebuild the stacks of the segments that changed, the others keep their animations
+            let mut stacks = std::mem::take(&mut self.stacks);
+            self.stacks = config.get_segments().into_iter().map(|segment| {
+                match stacks.iter().position(|s| s.segment == segment) {
+                    Some(i) => stacks.swap_remove(i),
+                    None => SegmentStack::new(segment, &self.animation_factories),
+                }
+            }).collect();
+
+            if let Ok(mut targets) = self.targets.write() {
+                targets.animations = self.animation_factories.keys().cloned().collect();
+                targets.segments = self.stacks.iter().map(|s| s.segment.clone()).collect();
+            }
+        }
 
-                    // Create the new animation
-                    self.current_animation = animation_factory();
-                    self.current_animation.start();
+        self.scenes.set_configured(config.get_scenes());
+        if let Ok(mut targets) = self.targets.write() {
+            targets.scenes = self.scenes.names(&targets);
+        }
+
+        self.schedule = Schedule::new(config.get_schedule(), &self.targets(), config.get_location(), config.get_schedule_override(), Box::new(SystemClock));
+
+        if config.get_fps() != self.config.get_fps() {
+            *scheduler = Scheduler::new(config.get_fps());
+        }
+
+        self.config = config;
+    }
+
+    /// Runs the animation loop, rendering each frame to the given sink, until
+    /// SIGINT or SIGTERM is received. The animations are then faded out, for
+    /// at most `SHUTDOWN_TIMEOUT`, and the strip is turned off.
+    ///
+    /// # Returns
+    ///
+    /// * `i32` - The exit status of the controller, 128 + the number of the signal received
+    pub fn run_on(&mut self, sink: &mut dyn LedSink) -> i32 {
+        let signal = register_shutdown_signals();
+        let mut shutdown_deadline: Option<time::Instant> = None;
+
+        for channel in 0..self.config.get_channel_lengths().len() {
+            sink.set_brightness(channel, self.config.get_hardware().max_brightness(channel));
+        }
+        let mut scheduler = Scheduler::new(self.config.get_fps());
+        self.startup(sink, &mut scheduler);
+        self.publish_state();
+
+        let mut dt = time::Duration::ZERO;
+        let mut render_failed = false;
+        loop {
+            // Apply the commands received since the last frame, then the ones of the schedule
+            let mut changed = false;
+            let mut applied = false;
+            while let Ok(command) = self.pending_commands.try_recv() {
+                if !matches!(command, Command::Reload | Command::Identify | Command::SaveScene(_)) {
+                    self.schedule.suspend();
+                }
+                changed |= self.apply(command, sink, &mut scheduler);
+                applied = true;
+            }
+            if shutdown_deadline.is_none() {
+                for command in self.schedule.poll() {
+                    changed |= self.apply(command, sink, &mut scheduler);
+                    applied = true;
+                }
+            }
+
+            // The state is saved a while after it changed, the changes made meanwhile being saved together
+            let now = time::Instant::now();
+            if applied {
+                self.state_file.touch(now);
+            }
+            if shutdown_deadline.is_none() && self.state_file.is_due(Some(now)) {
+                let snapshot = self.snapshot();
+                self.state_file.save(snapshot);
+            }
+
+            let received = signal.load(Ordering::Relaxed) as i32;
+            if received != 0 && shutdown_deadline.is_none() {
+                info!("Received signal {}, shutting down", received);
+                // Save the pending changes before the segments are turned off
+                if self.state_file.is_due(None) {
+                    let snapshot = self.snapshot();
+                    self.state_file.save(snapshot);
+                }
+                for stack in self.stacks.iter_mut() {
+                    stack.stop(&self.animation_factories);
+                }
+                shutdown_deadline = Some(now + SHUTDOWN_TIMEOUT);
+                changed = true;
+            }
+
+            for stack in self.stacks.iter_mut() {
+                changed |= stack.step(now, dt, &self.animation_factories);
+                stack.blit(sink);
+            }
+
+            // Frames streamed by lighting software replace the animations, which keep running below
+            let source = if shutdown_deadline.is_none() { self.realtime.apply(sink, now) } else { None };
+            if source != self.realtime_source {
+                match source {
+                    Some(s) => info!("Showing the frames received over {}", s),
+                    None => info!("No more realtime frames, showing the animations"),
+                }
+                self.realtime_source = source;
+                changed = true;
+            }
+
+            if changed {
+                self.publish_state();
+            }
+
+            if let Some(deadline) = shutdown_deadline {
+                if now >= deadline || self.stacks.iter().all(|s| s.is_off()) {
+                    self.turn_off(sink);
+                    return 128 + received;
                 }
             }
 
-            controller.render().unwrap();
-            thread::sleep(time::Duration::from_millis(self.current_animation.wait_time()));
+            if let Some(frames) = &self.frames {
+                frames.publish(sink, self.config.get_channel_lengths().len());
+            }
+            // A failure is logged once, until the frames render again
+            match sink.render() {
+                Ok(_) => render_failed = false,
+                Err(e) if !render_failed => {
+                    error!("Unable to render the frame: {}", e);
+                    render_failed = true;
+                },
+                Err(_) => (),
+            }
+
+            dt = scheduler.wait();
+            self.dropped_frames = scheduler.dropped_frames();
         }
     }
+
+    /// Renders a frame with every LED off, then reports the segments off and
+    /// disconnects from the broker
+    fn turn_off(&mut self, sink: &mut dyn LedSink) {
+        for channel in 0..self.config.get_channel_lengths().len() {
+            sink.leds_mut(channel).fill([0, 0, 0, 0]);
+        }
+        if let Err(e) = sink.render() {
+            error!("Unable to turn the strip off: {}", e);
+        }
+        info!("Strip turned off");
+
+        // The animations still fading out are dropped, so that every segment is reported off
+        if !self.stacks.iter().all(|s| s.is_off()) {
+            for stack in self.stacks.iter_mut() {
+                stack.clear(&self.animation_factories);
+            }
+            self.publish_state();
+        }
+        if let Some(reporter) = self.state_reporter.as_mut() {
+            reporter.close(MQTT_FLUSH_TIMEOUT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn declared_animations_can_not_shadow_animations_or_commands() {
+        let definitions: BTreeMap<String, AnimationDefinition> = toml::from_str(r##"
+            sunset = { palette = ["#ff4000", "#4000ff"] }
+            chase = { palette = ["#ff0000"] }
+            status = { palette = ["#00ff00"] }
+            scenes = { palette = ["#0000ff"] }
+        "##).unwrap();
+        let print_progress = PrintProgress::default();
+        let factories = build_animation_factories(&print_progress, PhaseColors::new(&Default::default()), &definitions);
+
+        assert_eq!(factories["sunset"](10, &Default::default()).name(), "sunset");
+        assert_eq!(factories["chase"](10, &Default::default()).name(), "chase");
+        assert!(!factories.contains_key("status"));
+        assert!(!factories.contains_key("scenes"));
+    }
 }
@@ -8,11 +8,19 @@ pub struct Args {
     #[arg(short, long, default_value = "config.toml")]
     pub config_file: String,
 
-    /// Prints the configuration to the console and exits
+    /// Overrides a configuration key, nested keys being dotted (e.g. `--set mqtt_host=broker.local`)
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
+
+    /// Prints the effective configuration, with the source of each value, and exits
     #[arg(short = 'd', long)]
     pub dump_config: bool,
 
     /// Prints the configuration to the console and exits
     #[arg(short = 'D', long)]
     pub dump_default_config: bool,
+
+    /// Renders the strip in the terminal instead of driving the LEDs, without any network service
+    #[arg(short, long)]
+    pub simulate: bool,
 }
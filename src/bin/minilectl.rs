@@ -0,0 +1,242 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::process::exit;
+
+use clap::{Parser, Subcommand};
+use serde_json::{json, Map, Value};
+
+/// Controls a running minileds through its local control socket
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Path to the control socket of minileds
+    #[arg(short, long, default_value = "/run/minileds/minileds.sock")]
+    socket: String,
+
+    #[command(subcommand)]
+    command: Action,
+}
+
+#[derive(Subcommand, Debug)]
+enum Action {
+    /// Runs an animation
+    Set {
+        /// The name of the animation
+        animation: String,
+        /// The segment to run the animation on, all of them if not set
+        #[arg(long)]
+        segment: Option<String>,
+        /// Restricts the animation to the segments of a channel
+        #[arg(long)]
+        channel: Option<usize>,
+        /// A speed multiplier, in ]0, 10]
+        #[arg(long)]
+        speed: Option<f64>,
+        /// A `#rrggbb` colour
+        #[arg(long)]
+        color: Option<String>,
+        /// The brightness in percent
+        #[arg(long)]
+        brightness: Option<u8>,
+        /// The layer the animation is drawn on
+        #[arg(long)]
+        layer: Option<usize>,
+        /// How the layer is blended: normal, add, multiply or max
+        #[arg(long)]
+        blend: Option<String>,
+        /// The opacity of the layer in percent
+        #[arg(long)]
+        opacity: Option<u8>,
+        /// A number of seconds after which the animation stops
+        #[arg(long)]
+        duration: Option<f64>,
+        /// How the running animation is replaced: fade, crossfade, wipe, dissolve or instant
+        #[arg(long)]
+        transition: Option<String>,
+        /// The duration of the transition in seconds
+        #[arg(long)]
+        transition_duration: Option<f64>,
+    },
+    /// Changes the brightness without restarting the animation
+    Brightness {
+        /// The brightness in percent
+        brightness: u8,
+        #[arg(long)]
+        segment: Option<String>,
+        #[arg(long)]
+        channel: Option<usize>,
+    },
+    /// Restarts the animation with another colour
+    Color {
+        /// A `#rrggbb` colour
+        color: String,
+        #[arg(long)]
+        segment: Option<String>,
+        #[arg(long)]
+        channel: Option<usize>,
+    },
+    /// Turns every segment off
+    Stop,
+    /// Reloads the configuration
+    Reload,
+    /// Blinks the whole strip
+    Identify,
+    /// Prints the state of the controller
+    Status,
+    /// Lists the available animations
+    List,
+    /// Recalls a scene
+    Scene {
+        /// The name of the scene
+        name: String,
+    },
+    /// Saves what the segments run as a scene
+    SaveScene {
+        /// The name of the scene, replacing any saved scene of the same name
+        name: String,
+    },
+    /// Lists the scenes
+    Scenes,
+    /// Sends a raw payload, as accepted on the MQTT channel
+    Send {
+        payload: String,
+    },
+}
+
+/// Adds the fields that are set to the JSON object
+fn with_fields(mut object: Map<String, Value>, fields: Vec<(&str, Option<Value>)>) -> Value {
+    for (key, value) in fields {
+        if let Some(value) = value {
+            object.insert(key.to_string(), value);
+        }
+    }
+
+    Value::Object(object)
+}
+
+/// Builds the line sent to the control socket
+fn request(action: Action) -> String {
+    match action {
+        Action::Set { animation, segment, channel, speed, color, brightness, layer, blend, opacity, duration, transition, transition_duration } => {
+            let mut object = Map::new();
+            object.insert("animation".to_string(), json!(animation));
+            with_fields(object, vec![
+                ("segment", segment.map(Value::from)),
+                ("channel", channel.map(Value::from)),
+                ("speed", speed.map(Value::from)),
+                ("color", color.map(Value::from)),
+                ("brightness", brightness.map(Value::from)),
+                ("layer", layer.map(Value::from)),
+                ("blend", blend.map(Value::from)),
+                ("opacity", opacity.map(Value::from)),
+                ("duration", duration.map(Value::from)),
+                ("transition", transition.map(Value::from)),
+                ("transition_duration", transition_duration.map(Value::from)),
+            ]).to_string()
+        },
+        Action::Brightness { brightness, segment, channel } => {
+            let mut object = Map::new();
+            object.insert("command".to_string(), json!("brightness"));
+            object.insert("brightness".to_string(), json!(brightness));
+            with_fields(object, vec![
+                ("segment", segment.map(Value::from)),
+                ("channel", channel.map(Value::from)),
+            ]).to_string()
+        },
+        Action::Color { color, segment, channel } => {
+            let mut object = Map::new();
+            object.insert("command".to_string(), json!("color"));
+            object.insert("color".to_string(), json!(color));
+            with_fields(object, vec![
+                ("segment", segment.map(Value::from)),
+                ("channel", channel.map(Value::from)),
+            ]).to_string()
+        },
+        Action::Stop => "stop".to_string(),
+        Action::Reload => "reload".to_string(),
+        Action::Identify => "identify".to_string(),
+        Action::Status => "status".to_string(),
+        Action::List => "list".to_string(),
+        Action::Scene { name } => json!({"command": "scene", "scene": name}).to_string(),
+        Action::SaveScene { name } => json!({"command": "save_scene", "scene": name}).to_string(),
+        Action::Scenes => "scenes".to_string(),
+        Action::Send { payload } => payload.replace('\n', " "),
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let mut stream = match UnixStream::connect(&args.socket) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Unable to connect to {}: {}", args.socket, e);
+            exit(1);
+        }
+    };
+
+    if let Err(e) = writeln!(stream, "{}", request(args.command)) {
+        eprintln!("Unable to send the command: {}", e);
+        exit(1);
+    }
+
+    let mut response = String::new();
+    if let Err(e) = BufReader::new(stream).read_line(&mut response) {
+        eprintln!("Unable to read the response: {}", e);
+        exit(1);
+    }
+
+    let response: Value = match serde_json::from_str(&response) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Invalid response `{}`: {}", response.trim(), e);
+            exit(1);
+        }
+    };
+
+    if let Some(error) = response.get("error").and_then(Value::as_str) {
+        eprintln!("{}", error);
+        exit(1);
+    }
+    println!("{}", serde_json::to_string_pretty(&response).unwrap_or(response.to_string()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds the line of a command line, as parsed by clap
+    fn line(arguments: &[&str]) -> String {
+        let args = Args::try_parse_from(["minilectl"].iter().chain(arguments)).unwrap();
+        request(args.command)
+    }
+
+    fn object(arguments: &[&str]) -> Value {
+        serde_json::from_str(&line(arguments)).unwrap()
+    }
+
+    #[test]
+    fn sets_only_the_given_fields() {
+        assert_eq!(object(&["set", "chase"]), json!({"animation": "chase"}));
+        assert_eq!(
+            object(&["set", "chase", "--segment", "desk", "--speed", "2", "--color", "#ff8000", "--layer", "1", "--duration", "5"]),
+            json!({"animation": "chase", "segment": "desk", "speed": 2.0, "color": "#ff8000", "layer": 1, "duration": 5.0}),
+        );
+    }
+
+    #[test]
+    fn builds_the_control_commands() {
+        assert_eq!(object(&["brightness", "40", "--channel", "1"]), json!({"command": "brightness", "brightness": 40, "channel": 1}));
+        assert_eq!(object(&["color", "#0000ff", "--segment", "desk"]), json!({"command": "color", "color": "#0000ff", "segment": "desk"}));
+        assert_eq!(object(&["scene", "evening"]), json!({"command": "scene", "scene": "evening"}));
+        assert_eq!(object(&["save-scene", "evening"]), json!({"command": "save_scene", "scene": "evening"}));
+        for word in ["stop", "reload", "identify", "status", "list", "scenes"] {
+            assert_eq!(line(&[word]), word);
+        }
+    }
+
+    #[test]
+    fn sends_raw_payloads_on_a_single_line() {
+        assert_eq!(line(&["send", "{\"animation\":\n\"chase\"}"]), "{\"animation\": \"chase\"}");
+    }
+}
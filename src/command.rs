@@ -0,0 +1,504 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::compositor::BlendMode;
+use crate::config::Segment;
+use crate::transition::TransitionKind;
+use crate::utils::parse_hex_color;
+
+/// The commands of the MQTT channel and the control socket, which no animation or scene can be named after
+pub const RESERVED_NAMES: [&str; 6] = ["stop", "reload", "identify", "status", "list", "scenes"];
+
+/// Parameters that tune how an animation is rendered
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnimationParams {
+    /// Speed multiplier, 1.0 being the animation's natural speed
+    pub speed: f64,
+    /// Colour override as (red, green, blue), if the animation supports one
+    pub color: Option<(u8, u8, u8)>,
+    /// Brightness in percent of the strip's maximum brightness
+    pub brightness: u8,
+}
+
+impl std::default::Default for AnimationParams {
+    fn default() -> Self {
+        AnimationParams {
+            speed: 1.0,
+            color: None,
+            brightness: 100,
+        }
+    }
+}
+
+/// A request to run an animation with the given parameters
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnimationCommand {
+    pub animation: String,
+    /// The segment the animation runs on, all the segments of the channel if not set
+    pub segment: Option<String>,
+    /// The ws281x channel the animation runs on, all the channels if not set
+    pub channel: Option<usize>,
+    /// The layer the animation is drawn on, 0 being the base layer
+    pub layer: usize,
+    /// How the layer is blended onto the layers below it
+    pub blend: BlendMode,
+    /// Opacity of the layer, in percent
+    pub opacity: u8,
+    /// Number of seconds after which the animation stops, if set
+    pub duration: Option<f64>,
+    /// How the layer switches from its current animation to this one
+    pub transition: TransitionKind,
+    /// Duration of the transition, in seconds
+    pub transition_duration: f64,
+    pub params: AnimationParams,
+}
+
+/// What commands can refer to, used to validate them
+#[derive(Clone)]
+pub struct Targets {
+    /// The names of the known animations
+    pub animations: Vec<String>,
+    /// The configured segments
+    pub segments: Vec<Segment>,
+    /// The names of the scenes, configured or saved
+    pub scenes: Vec<String>,
+}
+
+impl Targets {
+    /// Returns the segments the command applies to
+    pub fn select(&self, command: &AnimationCommand) -> Vec<&Segment> {
+        self.select_by(command.segment.as_deref(), command.channel)
+    }
+
+    /// Returns the segments matching the given name and channel, any segment matching when not set
+    pub fn select_by(&self, segment: Option<&str>, channel: Option<usize>) -> Vec<&Segment> {
+        self.segments.iter()
+            .filter(|s| segment.is_none_or(|name| name == s.name))
+            .filter(|s| channel.is_none_or(|channel| channel == s.channel))
+            .collect()
+    }
+
+    /// Checks that at least one segment matches the given name and channel
+    pub fn check(&self, segment: Option<&str>, channel: Option<usize>) -> Result<(), String> {
+        if !self.select_by(segment, channel).is_empty() {
+            return Ok(());
+        }
+
+        Err(match (segment, channel) {
+            (Some(segment), Some(channel)) => format!("segment `{}` is not on channel {}", segment, channel),
+            (Some(segment), None) => format!("unknown segment `{}`", segment),
+            (None, Some(channel)) => format!("no segment on channel {}", channel),
+            (None, None) => "no segment configured".to_string(),
+        })
+    }
+
+    /// Splits the command into one command per segment it applies to
+    pub fn split(&self, command: &AnimationCommand) -> Vec<AnimationCommand> {
+        self.select(command).into_iter().map(|s| AnimationCommand {
+            segment: Some(s.name.clone()),
+            channel: Some(s.channel),
+            ..command.clone()
+        }).collect()
+    }
+}
+
+/// The JSON payload, as received on the MQTT channel
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCommand {
+    animation: String,
+    segment: Option<String>,
+    channel: Option<usize>,
+    layer: Option<usize>,
+    blend: Option<BlendMode>,
+    opacity: Option<u8>,
+    duration: Option<f64>,
+    transition: Option<TransitionKind>,
+    transition_duration: Option<f64>,
+    speed: Option<f64>,
+    color: Option<String>,
+    brightness: Option<u8>,
+}
+
+/// The JSON payload of the commands that do not start an animation,
+/// e.g. `{"command": "brightness", "brightness": 40, "segment": "plate"}`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawControl {
+    command: String,
+    segment: Option<String>,
+    channel: Option<usize>,
+    brightness: Option<u8>,
+    color: Option<String>,
+    scene: Option<String>,
+}
+
+pub const MAX_SPEED: f64 = 10.0;
+const DEFAULT_TRANSITION_DURATION: f64 = 1.0;
+pub const MAX_TRANSITION_DURATION: f64 = 60.0;
+/// Longest time an animation can be run for before it stops, in seconds
+pub const MAX_DURATION: f64 = 7.0 * 24.0 * 3600.0;
+
+impl AnimationCommand {
+    /// Creates a command running the given animation with default parameters
+    pub fn new(animation: &str) -> AnimationCommand {
+        AnimationCommand {
+            animation: animation.to_string(),
+            segment: None,
+            channel: None,
+            layer: 0,
+            blend: BlendMode::Normal,
+            opacity: 100,
+            duration: None,
+            transition: TransitionKind::Fade,
+            transition_duration: DEFAULT_TRANSITION_DURATION,
+            params: AnimationParams::default(),
+        }
+    }
+
+    /// Parses and validates a command payload. The payload is either a JSON
+    /// object such as `{"animation":"chase","speed":2.0,"color":"#ff8800","brightness":80}`
+    /// or the plain name of an animation.
+    ///
+    /// # Arguments
+    ///
+    /// * `payload` - The raw payload to parse
+    /// * `targets` - The animations and segments commands can refer to
+    ///
+    /// # Returns
+    ///
+    /// * `Result<AnimationCommand, String>` - The command, or the reason it was rejected
+    pub fn parse(payload: &str, targets: &Targets) -> Result<AnimationCommand, String> {
+        let payload = payload.trim();
+
+        let command = if payload.starts_with('{') {
+            let raw: RawCommand = serde_json::from_str(payload)
+                .map_err(|e| format!("invalid JSON command: {}", e))?;
+            AnimationCommand::from_raw(raw)?
+        } else {
+            AnimationCommand::new(payload)
+        };
+
+        command.validate(targets)?;
+        Ok(command)
+    }
+
+    /// Checks that the command refers to a known animation and to at least one segment
+    pub fn validate(&self, targets: &Targets) -> Result<(), String> {
+        if self.animation.is_empty() {
+            return Err("no animation given".to_string());
+        }
+        if !targets.animations.contains(&self.animation) {
+            return Err(format!("unknown animation `{}`", self.animation));
+        }
+        targets.check(self.segment.as_deref(), self.channel)
+    }
+
+    /// Builds the JSON payload of the command, as accepted on the MQTT channel.
+    /// The fields left to their default are omitted.
+    pub fn to_payload(&self) -> Value {
+        let defaults = AnimationCommand::new(&self.animation);
+        let mut payload = json!({"animation": self.animation});
+        let mut set = |key: &str, value: Value, default: bool| {
+            if !default {
+                payload[key] = value;
+            }
+        };
+
+        set("segment", json!(self.segment), self.segment.is_none());
+        set("channel", json!(self.channel), self.channel.is_none());
+        set("layer", json!(self.layer), self.layer == defaults.layer);
+        set("blend", json!(self.blend), self.blend == defaults.blend);
+        set("opacity", json!(self.opacity), self.opacity == defaults.opacity);
+        set("duration", json!(self.duration), self.duration.is_none());
+        set("transition", json!(self.transition), self.transition == defaults.transition);
+        set("transition_duration", json!(self.transition_duration), self.transition_duration == defaults.transition_duration);
+        set("speed", json!(self.params.speed), self.params.speed == defaults.params.speed);
+        if let Some((r, g, b)) = self.params.color {
+            set("color", json!(format!("#{:02x}{:02x}{:02x}", r, g, b)), false);
+        }
+        set("brightness", json!(self.params.brightness), self.params.brightness == defaults.params.brightness);
+
+        payload
+    }
+
+    fn from_raw(raw: RawCommand) -> Result<AnimationCommand, String> {
+        let mut params = AnimationParams::default();
+
+        if let Some(speed) = raw.speed {
+            if !speed.is_finite() || speed <= 0.0 || speed > MAX_SPEED {
+                return Err(format!("speed must be in ]0, {}], got {}", MAX_SPEED, speed));
+            }
+            params.speed = speed;
+        }
+
+        if let Some(color) = raw.color {
+            params.color = Some(parse_hex_color(&color)
+                .ok_or(format!("color must be formatted as #rrggbb, got `{}`", color))?);
+        }
+
+        if let Some(brightness) = raw.brightness {
+            if brightness > 100 {
+                return Err(format!("brightness must be in [0, 100], got {}", brightness));
+            }
+            params.brightness = brightness;
+        }
+
+        if let Some(opacity) = raw.opacity {
+            if opacity > 100 {
+                return Err(format!("opacity must be in [0, 100], got {}", opacity));
+            }
+        }
+
+        if let Some(duration) = raw.duration {
+            if !duration.is_finite() || duration <= 0.0 || duration > MAX_DURATION {
+                return Err(format!("duration must be in ]0, {}] seconds, got {}", MAX_DURATION, duration));
+            }
+        }
+
+        if let Some(duration) = raw.transition_duration {
+            if !duration.is_finite() || !(0.0..=MAX_TRANSITION_DURATION).contains(&duration) {
+                return Err(format!("transition_duration must be in [0, {}] seconds, got {}", MAX_TRANSITION_DURATION, duration));
+            }
+        }
+
+        Ok(AnimationCommand {
+            animation: raw.animation.trim().to_string(),
+            segment: raw.segment,
+            channel: raw.channel,
+            layer: raw.layer.unwrap_or(0),
+            blend: raw.blend.unwrap_or_default(),
+            opacity: raw.opacity.unwrap_or(100),
+            duration: raw.duration,
+            transition: raw.transition.unwrap_or_default(),
+            transition_duration: raw.transition_duration.unwrap_or(DEFAULT_TRANSITION_DURATION),
+            params,
+        })
+    }
+}
+
+/// A request sent to the controller. Commands are queued on a channel by the
+/// interfaces receiving them, and applied by the animation loop before each frame.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Runs an animation on the segments and the layer it targets
+    SetAnimation(AnimationCommand),
+    /// Changes the brightness of the targeted segments, in percent, without restarting their animation
+    SetBrightness {
+        segment: Option<String>,
+        channel: Option<usize>,
+        brightness: u8,
+    },
+    /// Restarts the animation of the targeted segments with another colour
+    SetColor {
+        segment: Option<String>,
+        channel: Option<usize>,
+        color: (u8, u8, u8),
+    },
+    /// Turns every layer of every segment off
+    Stop,
+    /// Reloads the configuration
+    Reload,
+    /// Blinks the whole strip, to tell which controller it is
+    Identify,
+    /// Runs the commands of a scene
+    Scene(String),
+    /// Saves what the segments run as a scene
+    SaveScene(String),
+}
+
+impl Command {
+    /// Parses and validates a command payload. Besides animation commands, the
+    /// payload can be `stop`, `reload` or `identify`, or a JSON object with a
+    /// `command` field such as `{"command": "color", "color": "#ff8800"}`.
+    ///
+    /// # Arguments
+    ///
+    /// * `payload` - The raw payload to parse
+    /// * `targets` - The animations and segments commands can refer to
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Command, String>` - The command, or the reason it was rejected
+    pub fn parse(payload: &str, targets: &Targets) -> Result<Command, String> {
+        let payload = payload.trim();
+
+        if payload.starts_with('{') {
+            let value: serde_json::Value = serde_json::from_str(payload)
+                .map_err(|e| format!("invalid JSON command: {}", e))?;
+            if value.get("command").is_none() {
+                return AnimationCommand::parse(payload, targets).map(Command::SetAnimation);
+            }
+
+            let raw: RawControl = serde_json::from_value(value)
+                .map_err(|e| format!("invalid JSON command: {}", e))?;
+            return Command::from_control(raw, targets);
+        }
+
+        // Animations take precedence over the commands and the scenes sharing their name
+        if targets.animations.iter().any(|a| a == payload) {
+            return AnimationCommand::parse(payload, targets).map(Command::SetAnimation);
+        }
+        if targets.scenes.iter().any(|s| s == payload) {
+            return Ok(Command::Scene(payload.to_string()));
+        }
+
+        match payload {
+            "stop" => Ok(Command::Stop),
+            "reload" => Ok(Command::Reload),
+            "identify" => Ok(Command::Identify),
+            _ => AnimationCommand::parse(payload, targets).map(Command::SetAnimation),
+        }
+    }
+
+    fn from_control(raw: RawControl, targets: &Targets) -> Result<Command, String> {
+        targets.check(raw.segment.as_deref(), raw.channel)?;
+
+        match raw.command.as_str() {
+            "brightness" => {
+                let brightness = raw.brightness.ok_or("missing brightness")?;
+                if brightness > 100 {
+                    return Err(format!("brightness must be in [0, 100], got {}", brightness));
+                }
+                Ok(Command::SetBrightness { segment: raw.segment, channel: raw.channel, brightness })
+            },
+            "color" => {
+                let color = raw.color.ok_or("missing color")?;
+                let color = parse_hex_color(&color)
+                    .ok_or(format!("color must be formatted as #rrggbb, got `{}`", color))?;
+                Ok(Command::SetColor { segment: raw.segment, channel: raw.channel, color })
+            },
+            "stop" => Ok(Command::Stop),
+            "reload" => Ok(Command::Reload),
+            "identify" => Ok(Command::Identify),
+            "scene" => {
+                let scene = raw.scene.ok_or("missing scene")?;
+                if !targets.scenes.contains(&scene) {
+                    return Err(format!("unknown scene `{}`", scene));
+                }
+                Ok(Command::Scene(scene))
+            },
+            "save_scene" => {
+                let scene = raw.scene.ok_or("missing scene")?;
+                check_scene_name(&scene, targets)?;
+                Ok(Command::SaveScene(scene))
+            },
+            other => Err(format!("unknown command `{}`", other)),
+        }
+    }
+}
+
+/// Checks that a scene can be recalled by the given name, without being
+/// shadowed by an animation or a command
+pub fn check_scene_name(name: &str, targets: &Targets) -> Result<(), String> {
+    if name.trim().is_empty() || name.trim() != name || name.starts_with('{') {
+        return Err(format!("invalid scene name `{}`", name));
+    }
+    if targets.animations.iter().any(|a| a == name) || RESERVED_NAMES.contains(&name) {
+        return Err(format!("scene `{}` is shadowed by the animation or command of the same name", name));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets() -> Targets {
+        let segment = |name: &str, channel| Segment { name: name.to_string(), channel, start: 0, length: 10, reversed: false };
+        Targets {
+            animations: ["off", "rainbow", "chase"].map(String::from).to_vec(),
+            segments: vec![segment("desk", 0), segment("shelf", 1)],
+            scenes: vec![],
+        }
+    }
+
+    fn parse(payload: &str) -> Result<AnimationCommand, String> {
+        AnimationCommand::parse(payload, &targets())
+    }
+
+    #[test]
+    fn plain_names_run_with_default_parameters() {
+        assert_eq!(parse(" chase\n"), Ok(AnimationCommand::new("chase")));
+    }
+
+    #[test]
+    fn json_commands_set_their_parameters() {
+        let command = parse(r##"{"animation":"chase","segment":"desk","speed":2.5,"color":"#ff8000","brightness":40}"##).unwrap();
+
+        assert_eq!(command.segment.as_deref(), Some("desk"));
+        assert_eq!(command.params, AnimationParams { speed: 2.5, color: Some((255, 128, 0)), brightness: 40 });
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        for payload in [
+            r##"{"animation":"chase","color":"ff8000"}"##,
+            r##"{"animation":"chase","color":"#ff80"}"##,
+            r##"{"animation":"chase","color":"#gg8000"}"##,
+            r#"{"animation":"chase","speed":0}"#,
+            r#"{"animation":"chase","speed":10.5}"#,
+            r#"{"animation":"chase","speed":-1}"#,
+            r#"{"animation":"chase","brightness":101}"#,
+            r#"{"animation":"chase","opacity":150}"#,
+            r#"{"animation":"chase","transition_duration":61}"#,
+            r#"{"animation":"chase","duration":0}"#,
+            r#"{"animation":"chase","duration":1e300}"#,
+        ] {
+            assert!(parse(payload).is_err(), "accepted {}", payload);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_targets_and_fields() {
+        assert_eq!(parse("sparkles"), Err("unknown animation `sparkles`".to_string()));
+        assert_eq!(parse(r#"{"animation":"chase","segment":"wall"}"#), Err("unknown segment `wall`".to_string()));
+        assert_eq!(parse(r#"{"animation":"chase","segment":"desk","channel":1}"#), Err("segment `desk` is not on channel 1".to_string()));
+        assert!(parse(r##"{"animation":"chase","colour":"#ff8000"}"##).unwrap_err().contains("unknown field `colour`"));
+        assert!(parse(r#"{"speed":2}"#).is_err());
+        assert!(parse(r#"{"animation":"chase""#).is_err());
+    }
+
+    #[test]
+    fn payloads_round_trip() {
+        let command = parse(r##"{"animation":"chase","segment":"shelf","layer":2,"opacity":50,"speed":2.0,"color":"#010203"}"##).unwrap();
+        assert_eq!(parse(&command.to_payload().to_string()), Ok(command));
+    }
+
+    #[test]
+    fn routes_plain_commands_and_scenes() {
+        let targets = Targets { scenes: ["evening", "chase"].map(String::from).to_vec(), ..targets() };
+        let command = |payload: &str| Command::parse(payload, &targets);
+
+        assert_eq!(command("stop\n"), Ok(Command::Stop));
+        assert_eq!(command("reload"), Ok(Command::Reload));
+        assert_eq!(command("identify"), Ok(Command::Identify));
+        assert_eq!(command("evening"), Ok(Command::Scene("evening".to_string())));
+        // Animations take precedence over the scenes of the same name
+        assert_eq!(command("chase"), Ok(Command::SetAnimation(AnimationCommand::new("chase"))));
+        assert_eq!(command("sparkles"), Err("unknown animation `sparkles`".to_string()));
+    }
+
+    #[test]
+    fn routes_json_commands() {
+        let targets = Targets { scenes: vec!["evening".to_string()], ..targets() };
+        let command = |payload: &str| Command::parse(payload, &targets);
+
+        assert_eq!(command(r#"{"command":"brightness","segment":"desk","brightness":40}"#),
+                   Ok(Command::SetBrightness { segment: Some("desk".to_string()), channel: None, brightness: 40 }));
+        assert_eq!(command(r##"{"command":"color","channel":1,"color":"#ff8800"}"##),
+                   Ok(Command::SetColor { segment: None, channel: Some(1), color: (255, 136, 0) }));
+        assert_eq!(command(r#"{"command":"scene","scene":"evening"}"#), Ok(Command::Scene("evening".to_string())));
+        assert_eq!(command(r#"{"command":"save_scene","scene":"night"}"#), Ok(Command::SaveScene("night".to_string())));
+        assert_eq!(command(r#"{"command":"stop"}"#), Ok(Command::Stop));
+        assert_eq!(command(r#"{"animation":"chase"}"#), Ok(Command::SetAnimation(AnimationCommand::new("chase"))));
+
+        assert_eq!(command(r#"{"command":"dance"}"#), Err("unknown command `dance`".to_string()));
+        assert_eq!(command(r#"{"command":"brightness","brightness":101}"#), Err("brightness must be in [0, 100], got 101".to_string()));
+        assert_eq!(command(r#"{"command":"color"}"#), Err("missing color".to_string()));
+        assert_eq!(command(r#"{"command":"scene","scene":"night"}"#), Err("unknown scene `night`".to_string()));
+        assert!(command(r#"{"command":"save_scene","scene":"chase"}"#).is_err());
+        assert!(command(r#"{"command":"brightness","segment":"wall","brightness":10}"#).is_err());
+    }
+}
@@ -0,0 +1,471 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::animations::{Animation, AnimationFactory};
+use crate::command::AnimationCommand;
+use crate::config::Segment;
+use crate::sinks::{FrameBuffer, LedSink, RawColor};
+use crate::transition::{Transition, TransitionKind};
+
+/// How a layer is combined with the layers below it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlendMode {
+    /// The layer replaces what is below it
+    #[default]
+    Normal,
+    /// The layer is added to what is below it
+    Add,
+    /// The layer darkens what is below it
+    Multiply,
+    /// The brightest of the layer and of what is below it is kept
+    Max,
+}
+
+/// Blends a LED of a layer onto the LED below it
+///
+/// # Arguments
+///
+/// * `below` - The LED composed from the layers below
+/// * `layer` - The LED of the layer
+/// * `mode` - How the two LEDs are combined
+/// * `opacity` - The opacity of the layer, in percent
+pub fn blend(below: RawColor, layer: RawColor, mode: BlendMode, opacity: u8) -> RawColor {
+    let opacity = opacity.min(100) as u16;
+    let mut out = below;
+    for i in 0..4 {
+        let (b, l) = (below[i] as u16, layer[i] as u16);
+        let blended = match mode {
+            BlendMode::Normal => l,
+            BlendMode::Add => (b + l).min(255),
+            BlendMode::Multiply => b * l / 255,
+            BlendMode::Max => b.max(l),
+        };
+        out[i] = ((b * (100 - opacity) + blended * opacity) / 100) as u8;
+    }
+
+    out
+}
+
+/// Returns when an animation run for the given number of seconds stops, if it is bounded
+fn expiry(now: Instant, duration: Option<f64>) -> Option<Instant> {
+    duration
+        .and_then(|d| Duration::try_from_secs_f64(d).ok())
+        .and_then(|d| now.checked_add(d))
+}
+
+/// What happened to a layer during a frame
+#[derive(Default)]
+pub struct Step {
+    /// An animation was started or stopped
+    pub changed: bool,
+    /// The layer is done and can be removed
+    pub finished: bool,
+}
+
+/// An animation drawn into its own buffer, blended onto the layers below it
+pub struct Layer {
+    pub index: usize,
+    buffer: FrameBuffer,
+    /// The LEDs of the layer, mixed with the outgoing animation during transitions
+    frame: Vec<RawColor>,
+    pub animation: Box<dyn Animation>,
+    transition: Option<Transition>,
+    pub command: AnimationCommand,
+    pending: Option<AnimationCommand>,
+    expires_at: Option<Instant>,
+}
+
+impl Layer {
+    fn new(index: usize, length: i32, command: AnimationCommand, factories: &HashMap<String, AnimationFactory>) -> Layer {
+        Layer {
+            index,
+            buffer: FrameBuffer::new(&[length]),
+            frame: vec![[0, 0, 0, 0]; length.max(0) as usize],
+            animation: factories.get("off").unwrap()(length, &command.params),
+            transition: None,
+            command,
+            pending: None,
+            expires_at: None,
+        }
+    }
+
+    /// Queues a command on the layer. A command only changing how the layer is
+    /// blended is applied right away, without restarting the animation.
+    pub fn push(&mut self, command: AnimationCommand) {
+        let running = !self.animation.stopping();
+        if running && command.animation == self.command.animation && command.params == self.command.params {
+            self.expires_at = expiry(Instant::now(), command.duration);
+            self.command = command;
+            self.pending = None;
+        } else {
+            self.pending = Some(command);
+        }
+    }
+
+    /// Returns the command the layer runs, or the one it switches to next
+    pub fn target(&self) -> &AnimationCommand {
+        self.pending.as_ref().unwrap_or(&self.command)
+    }
+
+    /// Builds the animation of a command, defaulting to off if it does not exist
+    fn build(command: &AnimationCommand, segment: &Segment, factories: &HashMap<String, AnimationFactory>) -> Box<dyn Animation> {
+        let animation_factory = match factories.get(command.animation.as_str()) {
+            Some(f) => f,
+            None => {
+                error!("Unable to find animation factory for animation: `{}` defaulting to off", command.animation);
+                factories.get("off").unwrap()
+            }
+        };
+
+        let mut animation = animation_factory(segment.length, &command.params);
+        animation.start();
+        animation
+    }
+
+    /// Computes the next frame of the layer, switching to the pending command once the current animation stopped
+    fn step(&mut self, now: Instant, dt: Duration, segment: &Segment, factories: &HashMap<String, AnimationFactory>) -> Step {
+        let mut step = Step::default();
+
+        // Turning an overlay off always fades it out, as there is nothing to transition to
+        let transitioning = self.pending.as_ref().is_some_and(|p| {
+            p.transition != TransitionKind::Fade && !(self.index > 0 && p.animation == "off")
+        });
+
+        if transitioning {
+            // Start the next animation right away, the current one keeps running until the end of the transition
+            let next = self.pending.take().unwrap();
+            info!("Switching from animation: {} to {} on segment {} layer {} ({:?})", self.animation.name(), next.animation, segment.name, self.index, next.transition);
+
+            let outgoing = std::mem::replace(&mut self.animation, Layer::build(&next, segment, factories));
+            self.transition = match next.transition {
+                TransitionKind::Instant => None,
+                kind => {
+                    let buffer = std::mem::replace(&mut self.buffer, FrameBuffer::new(&[segment.length]));
+                    Some(Transition::new(kind, outgoing, buffer, next.transition_duration, now))
+                }
+            };
+            self.expires_at = expiry(now, next.duration);
+            self.command = next;
+            step.changed = true;
+        }
+
+        // Stop the current animation if another one is pending or if its time is over
+        let expired = self.expires_at.is_some_and(|e| e <= now);
+        if (self.pending.is_some() || expired) && !self.animation.stopping() {
+            info!("Stopping animation: {} on segment {} layer {}", self.animation.name(), segment.name, self.index);
+            self.animation.stop();
+            self.expires_at = None;
+            step.changed = true;
+        }
+
+        // Save the result of next_frame to a variable so that we can check if the animation has changed
+        let res: bool = self.animation.next_frame(&mut self.buffer, dt);
+
+        // If the animation stopped, we can use the pending command to start the next animation
+        if !res {
+            if self.index > 0 && self.pending.as_ref().is_some_and(|p| p.animation == "off") {
+                // Turning an overlay off removes it
+                self.pending = None;
+                step.finished = true;
+            } else if let Some(next) = self.pending.take() {
+                info!("Starting animation: {} on segment {} layer {}", next.animation, segment.name, self.index);
+
+                // Create the new animation
+                self.animation = Layer::build(&next, segment, factories);
+                self.expires_at = expiry(now, next.duration);
+                self.command = next;
+                step.changed = true;
+            } else if self.index > 0 {
+                step.finished = true;
+            }
+        }
+
+        self.frame.copy_from_slice(self.buffer.leds(0));
+        if let Some(transition) = self.transition.as_mut() {
+            transition.next_frame(dt);
+            transition.mix(&mut self.frame, now);
+            if transition.done(now) {
+                self.transition = None;
+            }
+        }
+
+        step
+    }
+}
+
+/// The stack of layers of a segment, composed into its LEDs
+pub struct SegmentStack {
+    pub segment: Segment,
+    pub layers: Vec<Layer>,
+}
+
+impl SegmentStack {
+    /// Creates a stack holding a single base layer, turned off
+    pub fn new(segment: Segment, factories: &HashMap<String, AnimationFactory>) -> SegmentStack {
+        let mut off_command = AnimationCommand::new("off");
+        off_command.segment = Some(segment.name.clone());
+        off_command.channel = Some(segment.channel);
+
+        SegmentStack {
+            layers: vec![Layer::new(0, segment.length, off_command, factories)],
+            segment,
+        }
+    }
+
+    /// Returns the base layer of the segment
+    pub fn base(&self) -> &Layer {
+        &self.layers[0]
+    }
+
+    /// Queues a command on its layer, creating the layer if needed
+    pub fn push(&mut self, command: AnimationCommand, factories: &HashMap<String, AnimationFactory>) {
+        let index = match self.layers.iter().position(|l| l.index >= command.layer) {
+            Some(i) if self.layers[i].index == command.layer => i,
+            position => {
+                let i = position.unwrap_or(self.layers.len());
+                let mut off_command = command.clone();
+                off_command.animation = "off".to_string();
+                self.layers.insert(i, Layer::new(command.layer, self.segment.length, off_command, factories));
+                i
+            }
+        };
+
+        self.layers[index].push(command);
+    }
+
+    /// Changes the brightness of the base layer, without restarting its animation.
+    /// The animation the layer switches to next, if any, gets it too.
+    pub fn set_brightness(&mut self, brightness: u8) {
+        self.layers[0].command.params.brightness = brightness;
+        if let Some(pending) = self.layers[0].pending.as_mut() {
+            pending.params.brightness = brightness;
+        }
+    }
+
+    /// Restarts the animation of the base layer with another colour
+    pub fn set_color(&mut self, color: (u8, u8, u8), factories: &HashMap<String, AnimationFactory>) {
+        let mut command = self.base().command.clone();
+        command.params.color = Some(color);
+        self.push(command, factories);
+    }
+
+    /// Turns every layer off, the overlays being removed once faded out
+    pub fn stop(&mut self, factories: &HashMap<String, AnimationFactory>) {
+        let indexes: Vec<usize> = self.layers.iter().map(|l| l.index).collect();
+        for index in indexes {
+            let mut off_command = AnimationCommand::new("off");
+            off_command.segment = Some(self.segment.name.clone());
+            off_command.channel = Some(self.segment.channel);
+            off_command.layer = index;
+            self.push(off_command, factories);
+        }
+    }
+
+    /// Drops every layer and turns the base one off right away, without fading out
+    pub fn clear(&mut self, factories: &HashMap<String, AnimationFactory>) {
+        let mut off_command = AnimationCommand::new("off");
+        off_command.segment = Some(self.segment.name.clone());
+        off_command.channel = Some(self.segment.channel);
+
+        let mut base = Layer::new(0, self.segment.length, off_command.clone(), factories);
+        base.animation = Layer::build(&off_command, &self.segment, factories);
+        self.layers = vec![base];
+    }
+
+    /// Returns true once the segment is off, without any overlay
+    pub fn is_off(&self) -> bool {
+        self.layers.len() == 1 && self.base().animation.name() == "off"
+    }
+
+    /// Computes the next frame of the layers, and removes the finished ones
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The time of the frame
+    /// * `dt` - The time elapsed since the previous frame
+    /// * `factories` - The factories of the animations that can be started
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if an animation was started or stopped
+    pub fn step(&mut self, now: Instant, dt: Duration, factories: &HashMap<String, AnimationFactory>) -> bool {
+        let mut changed = false;
+        let segment = &self.segment;
+        self.layers.retain_mut(|layer| {
+            let step = layer.step(now, dt, segment, factories);
+            changed |= step.changed || step.finished;
+            !step.finished
+        });
+
+        changed
+    }
+
+    /// Composes the layers and copies the result to the segment of the sink
+    pub fn blit(&self, sink: &mut dyn LedSink) {
+        let mut composed: Vec<RawColor> = vec![[0, 0, 0, 0]; self.segment.length.max(0) as usize];
+        for layer in &self.layers {
+            let brightness = layer.command.params.brightness as u16;
+            for (led, buffered) in composed.iter_mut().zip(&layer.frame) {
+                let scaled = buffered.map(|c| (c as u16 * brightness / 100) as u8);
+                *led = blend(*led, scaled, layer.command.blend, layer.command.opacity);
+            }
+        }
+
+        let leds = sink.leds_mut(self.segment.channel);
+        for (index, led) in composed.into_iter().enumerate() {
+            let offset = if self.segment.reversed { self.segment.length as usize - 1 - index } else { index };
+            if let Some(target) = leds.get_mut(self.segment.start as usize + offset) {
+                *target = led;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::animations::Off;
+    use crate::command::AnimationParams;
+    use crate::utils::rgb_to_raw;
+
+    const DT: Duration = Duration::from_millis(20);
+
+    /// Fills the segment with the colour of its parameters, and stops right away
+    struct Fill {
+        color: RawColor,
+        running: bool,
+    }
+
+    impl Animation for Fill {
+        fn next_frame(&mut self, sink: &mut dyn LedSink, _dt: Duration) -> bool {
+            sink.leds_mut(0).fill(self.color);
+            self.running
+        }
+
+        fn start(&mut self) {
+            self.running = true;
+        }
+
+        fn stop(&mut self) {
+            self.running = false;
+        }
+
+        fn stopping(&self) -> bool {
+            !self.running
+        }
+
+        fn name(&self) -> &str {
+            "fill"
+        }
+
+        fn wait_time(&self) -> u64 {
+            20
+        }
+    }
+
+    fn factories() -> HashMap<String, AnimationFactory> {
+        let mut factories: HashMap<String, AnimationFactory> = HashMap::new();
+        factories.insert("off".to_string(), Arc::new(|_, _| Box::new(Off::new())));
+        factories.insert("fill".to_string(), Arc::new(|_, params: &AnimationParams| {
+            Box::new(Fill { color: rgb_to_raw(params.color.unwrap_or((0, 0, 0))), running: false })
+        }));
+        factories
+    }
+
+    fn stack(length: i32) -> SegmentStack {
+        SegmentStack::new(Segment { name: "desk".to_string(), channel: 0, start: 1, length, reversed: false }, &factories())
+    }
+
+    fn fill(color: (u8, u8, u8), layer: usize) -> AnimationCommand {
+        let mut command = AnimationCommand::new("fill");
+        command.params.color = Some(color);
+        command.layer = layer;
+        command
+    }
+
+    /// Steps the stack at the given times, and returns the LEDs it composes
+    fn render(stack: &mut SegmentStack, times: &[Instant]) -> Vec<RawColor> {
+        for now in times {
+            stack.step(*now, DT, &factories());
+        }
+        let mut sink = FrameBuffer::new(&[stack.segment.length + 1]);
+        stack.blit(&mut sink);
+        sink.leds(0).to_vec()
+    }
+
+    #[test]
+    fn blends_each_mode() {
+        let (below, layer) = ([100, 200, 50, 0], [200, 100, 0, 255]);
+
+        assert_eq!(blend(below, layer, BlendMode::Normal, 100), layer);
+        assert_eq!(blend(below, layer, BlendMode::Add, 100), [255, 255, 50, 255]);
+        assert_eq!(blend(below, layer, BlendMode::Multiply, 100), [78, 78, 0, 0]);
+        assert_eq!(blend(below, layer, BlendMode::Max, 100), [200, 200, 50, 255]);
+    }
+
+    #[test]
+    fn blends_with_the_opacity() {
+        let (below, layer) = ([100, 200, 50, 0], [200, 100, 0, 255]);
+
+        assert_eq!(blend(below, layer, BlendMode::Normal, 50), [150, 150, 25, 127]);
+        assert_eq!(blend(below, layer, BlendMode::Add, 0), below);
+        assert_eq!(blend(below, layer, BlendMode::Normal, 200), layer);
+    }
+
+    #[test]
+    fn composes_the_layers_onto_the_segment() {
+        let now = Instant::now();
+        let mut stack = stack(2);
+        stack.push(fill((0, 0, 100), 0), &factories());
+        let mut overlay = fill((0, 50, 0), 1);
+        overlay.blend = BlendMode::Add;
+        overlay.params.brightness = 50;
+        stack.push(overlay, &factories());
+
+        assert_eq!(render(&mut stack, &[now, now + DT]), [[0, 0, 0, 0], [100, 25, 0, 0], [100, 25, 0, 0]]);
+    }
+
+    #[test]
+    fn expired_overlays_are_removed() {
+        let now = Instant::now();
+        let mut stack = stack(1);
+        stack.push(fill((0, 0, 100), 0), &factories());
+        let mut overlay = fill((100, 0, 0), 1);
+        overlay.duration = Some(1.0);
+        stack.push(overlay, &factories());
+
+        assert_eq!(render(&mut stack, &[now, now + DT, now + Duration::from_millis(500)])[1], [0, 0, 100, 0]);
+        assert_eq!(stack.layers.len(), 2);
+
+        assert!(stack.step(now + Duration::from_secs(2), DT, &factories()));
+        assert_eq!(stack.layers.len(), 1);
+        assert_eq!(render(&mut stack, &[now + Duration::from_secs(3)])[1], [100, 0, 0, 0]);
+    }
+
+    #[test]
+    fn overlays_turned_off_are_removed() {
+        let now = Instant::now();
+        let mut stack = stack(1);
+        stack.push(fill((100, 0, 0), 2), &factories());
+        render(&mut stack, &[now, now + DT]);
+        assert_eq!(stack.layers.iter().map(|l| l.index).collect::<Vec<_>>(), [0, 2]);
+
+        stack.stop(&factories());
+        render(&mut stack, &[now + DT * 2, now + DT * 3]);
+        assert!(stack.is_off());
+    }
+
+    #[test]
+    fn huge_durations_never_expire() {
+        let now = Instant::now();
+        assert_eq!(expiry(now, Some(1.5)), Some(now + Duration::from_millis(1500)));
+        assert_eq!(expiry(now, Some(1e300)), None);
+        assert_eq!(expiry(now, None), None);
+    }
+}
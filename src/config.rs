@@ -1,10 +1,409 @@
 use serde::{Serialize, Deserialize};
-use std::path::Path;
-use std::fs;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::{env, fmt, fs};
 
 use log::warn;
+use toml::{Table, Value};
 
-#[derive(Serialize, Deserialize)]
+use crate::sinks::strip_type;
+
+/// Environment variables kept for compatibility with older deployments
+const LEGACY_ENV: [(&str, &str); 4] = [
+    ("DEVICE_NAME", "device_name"),
+    ("MQTT_HOST", "mqtt_host"),
+    ("MQTT_PORT", "mqtt_port"),
+    ("MQTT_CHANNEL", "mqtt_channel"),
+];
+
+/// Prefix of the environment variables overriding configuration keys
+const ENV_PREFIX: &str = "MINILEDS_";
+
+/// Frame rate of the animations, matching the 20ms frames they were written for
+const DEFAULT_FPS: u32 = 50;
+const MAX_FPS: u32 = 240;
+
+/// Number of seconds a manual command suspends the schedule for
+const DEFAULT_SCHEDULE_OVERRIDE: f64 = 3600.0;
+/// What the segments run when the controller starts
+const DEFAULT_STARTUP: &str = "restore";
+/// Where the state is saved between runs, in the state directory of the service
+const DEFAULT_STATE_FILE: &str = "/var/lib/minileds/state.toml";
+
+/// Where the value of a configuration key comes from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    Default,
+    File,
+    Environment,
+    CommandLine,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File => write!(f, "file"),
+            Source::Environment => write!(f, "environment"),
+            Source::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// Settings of the second ws281x channel
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelConfig {
+    pub pin: i32,
+    pub length: i32,
+    pub strip_type: String,
+    pub brightness: u8,
+}
+
+impl std::default::Default for ChannelConfig {
+    fn default() -> Self {
+        ChannelConfig {
+            pin: 13,
+            length: 0,
+            strip_type: "ws2811_rgb".to_string(),
+            brightness: 127,
+        }
+    }
+}
+
+/// Settings of the ws281x driver and of the strip
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HardwareConfig {
+    pub pin: i32,
+    pub dma: i32,
+    pub freq: u32,
+    pub strip_type: String,
+    pub brightness: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel1: Option<ChannelConfig>,
+}
+
+impl std::default::Default for HardwareConfig {
+    fn default() -> Self {
+        HardwareConfig {
+            pin: 18,
+            dma: 10,
+            freq: 800_000,
+            strip_type: "ws2811_rgb".to_string(),
+            brightness: 127,
+            channel1: None,
+        }
+    }
+}
+
+/// Settings of the embedded HTTP API
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub enabled: bool,
+    /// Address and port the server listens on
+    pub bind: String,
+    /// Serves a page drawing the strip live, and the WebSocket streaming its frames
+    pub preview: bool,
+    /// The most frames per second streamed to a preview client
+    pub preview_fps: u32,
+}
+
+impl std::default::Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            enabled: false,
+            bind: "0.0.0.0:8080".to_string(),
+            preview: false,
+            preview_fps: 15,
+        }
+    }
+}
+
+/// Settings of the local control socket
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SocketConfig {
+    pub enabled: bool,
+    pub path: String,
+    /// Permissions of the socket, in octal
+    pub mode: String,
+    /// Group the socket belongs to, by name or id, letting its members drive the controller
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+impl std::default::Default for SocketConfig {
+    fn default() -> Self {
+        SocketConfig {
+            enabled: true,
+            path: "/run/minileds/minileds.sock".to_string(),
+            mode: "0660".to_string(),
+            group: None,
+        }
+    }
+}
+
+/// Settings of the E1.31 (sACN) and Art-Net receiver
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DmxConfig {
+    pub enabled: bool,
+    /// `e131` or `artnet`
+    pub protocol: String,
+    /// Address the receiver listens on, the port being the one of the protocol
+    pub bind: String,
+    /// The universe the first LED is in, numbered as in the protocol
+    pub universe: u16,
+    /// The DMX address of the first LED in its universe, from 1 to 512
+    pub start_address: u16,
+    /// Order of the DMX channels of a LED, e.g. `rgb`, `grb` or `rgbw`
+    pub color_order: String,
+    /// The ws281x channel and the first LED the universes are mapped to
+    pub channel: usize,
+    pub start: usize,
+    /// Number of LEDs driven by DMX, the rest of the channel if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
+    /// Number of seconds without data after which the animations are shown again
+    pub timeout: f64,
+}
+
+impl std::default::Default for DmxConfig {
+    fn default() -> Self {
+        DmxConfig {
+            enabled: false,
+            protocol: "e131".to_string(),
+            bind: "0.0.0.0".to_string(),
+            universe: 1,
+            start_address: 1,
+            color_order: "rgb".to_string(),
+            channel: 0,
+            start: 0,
+            length: None,
+            timeout: 5.0,
+        }
+    }
+}
+
+/// Settings of the WLED-compatible interfaces
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WledConfig {
+    /// Serves the WLED JSON API under `/json`, along the HTTP API
+    pub enabled: bool,
+    /// Receives the WLED UDP realtime protocols (WARLS, DRGB, DRGBW and DNRGB)
+    pub udp: bool,
+    /// Address and port the UDP realtime receiver listens on
+    pub udp_bind: String,
+}
+
+impl std::default::Default for WledConfig {
+    fn default() -> Self {
+        WledConfig {
+            enabled: false,
+            udp: true,
+            udp_bind: "0.0.0.0:21324".to_string(),
+        }
+    }
+}
+
+/// Settings of the 3D printer whose print the `progress` animation shows
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrinterConfig {
+    /// Follows the print through the OctoPrint MQTT plugin, on the same broker
+    pub mqtt: bool,
+    /// Base topic of the OctoPrint MQTT plugin
+    pub mqtt_topic: String,
+    /// Polls the print over HTTP: `none`, `moonraker` or `octoprint`
+    pub poll: String,
+    /// Base URL of the Moonraker or OctoPrint HTTP API
+    pub url: String,
+    /// OctoPrint API key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    /// Number of seconds between two polls
+    pub poll_interval: f64,
+    /// `#rrggbb` colours of the phases of a print
+    pub heating_color: String,
+    pub printing_color: String,
+    pub paused_color: String,
+    pub done_color: String,
+    pub error_color: String,
+}
+
+impl std::default::Default for PrinterConfig {
+    fn default() -> Self {
+        PrinterConfig {
+            mqtt: true,
+            mqtt_topic: "octoPrint".to_string(),
+            poll: "none".to_string(),
+            url: "http://localhost:7125".to_string(),
+            api_key: None,
+            poll_interval: 2.0,
+            heating_color: "#ff4000".to_string(),
+            printing_color: "#0060ff".to_string(),
+            paused_color: "#ffb000".to_string(),
+            done_color: "#00ff40".to_string(),
+            error_color: "#ff0000".to_string(),
+        }
+    }
+}
+
+/// A colour of a gradient, at a position along the segment
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GradientStop {
+    /// Position along the segment, from 0 (its start) to 1 (its end)
+    pub at: f64,
+    /// A `#rrggbb` colour
+    pub color: String,
+}
+
+/// An animation defined in the configuration, compiled when it is loaded
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnimationDefinition {
+    /// The colours along the segment, interpolated between the stops
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub gradient: Vec<GradientStop>,
+    /// Colours spread evenly along the segment, looping back to the first one
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub palette: Vec<String>,
+    /// How the colours move: `static`, `scroll`, `breathe` or `sparkle`
+    pub motion: String,
+    /// Speed multiplier, 1.0 being a cycle every 5 seconds
+    pub speed: f64,
+    /// Direction of the scroll: `forward` or `reverse`
+    pub direction: String,
+}
+
+impl std::default::Default for AnimationDefinition {
+    fn default() -> Self {
+        AnimationDefinition {
+            gradient: Vec::new(),
+            palette: Vec::new(),
+            motion: "static".to_string(),
+            speed: 1.0,
+            direction: "forward".to_string(),
+        }
+    }
+}
+
+/// GPIO pins driven by the PWM, PCM and SPI peripherals
+const PWM0_PINS: [i32; 4] = [12, 18, 40, 52];
+const PWM1_PINS: [i32; 5] = [13, 19, 41, 45, 53];
+const PCM_PINS: [i32; 2] = [21, 31];
+const SPI_PINS: [i32; 2] = [10, 38];
+
+/// DMA channels used by the system (5 corrupts the SD card on recent kernels)
+const RESERVED_DMA: [i32; 7] = [0, 1, 2, 3, 5, 6, 7];
+
+/// Frequencies supported by ws281x strips
+const SUPPORTED_FREQS: [u32; 2] = [400_000, 800_000];
+
+impl HardwareConfig {
+    /// Checks that the settings can be used by the driver
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), Vec<String>>` - The list of problems found, if any
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = vec![];
+
+        if PWM1_PINS.contains(&self.pin) {
+            errors.push(format!("pin {} is driven by PWM1, which is only available on channel 1", self.pin));
+        } else if !PWM0_PINS.contains(&self.pin) && !PCM_PINS.contains(&self.pin) && !SPI_PINS.contains(&self.pin) {
+            errors.push(format!(
+                "pin {} can not drive a strip, use one of {:?} (PWM), {:?} (PCM) or {:?} (SPI)",
+                self.pin, PWM0_PINS, PCM_PINS, SPI_PINS
+            ));
+        }
+
+        if !(0..=14).contains(&self.dma) {
+            errors.push(format!("DMA channel {} does not exist, use a channel between 8 and 14", self.dma));
+        } else if RESERVED_DMA.contains(&self.dma) && !SPI_PINS.contains(&self.pin) {
+            errors.push(format!("DMA channel {} is used by the system and can not drive pin {}, use a channel between 8 and 14", self.dma, self.pin));
+        }
+
+        if !SUPPORTED_FREQS.contains(&self.freq) {
+            errors.push(format!("frequency {}Hz is not supported, use one of {:?}", self.freq, SUPPORTED_FREQS));
+        }
+
+        if strip_type(&self.strip_type).is_none() {
+            errors.push(format!("unknown strip type `{}`, expected e.g. `ws2811_grb` or `sk6812_rgbw`", self.strip_type));
+        }
+
+        if let Some(channel1) = &self.channel1 {
+            if !PWM0_PINS.contains(&self.pin) {
+                errors.push(format!("channel 1 requires channel 0 to use a PWM pin ({:?}), not pin {}", PWM0_PINS, self.pin));
+            }
+            if !PWM1_PINS.contains(&channel1.pin) {
+                errors.push(format!("pin {} can not drive channel 1, use one of {:?}", channel1.pin, PWM1_PINS));
+            }
+            if channel1.length <= 0 {
+                errors.push(format!("channel 1 length must be positive, got {}", channel1.length));
+            }
+            if strip_type(&channel1.strip_type).is_none() {
+                errors.push(format!("unknown strip type `{}` for channel 1", channel1.strip_type));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Returns the brightness of the given channel when an animation runs at 100%
+    pub fn max_brightness(&self, channel: usize) -> u8 {
+        match (channel, &self.channel1) {
+            (1, Some(channel1)) => channel1.brightness,
+            _ => self.brightness,
+        }
+    }
+}
+
+/// A rule of the schedule, running a command when a time window opens or at
+/// the minutes matching a cron expression
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScheduleRule {
+    /// Name of the rule in the logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Start and end of the window, as `HH:MM` or relative to the sun, e.g. `sunset-30m`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    /// Days the window opens on, e.g. `["mon", "tue"]`, every day if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<String>,
+    /// A cron expression `minute hour day-of-month month day-of-week`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cron: Option<String>,
+    /// The command run, as accepted on the MQTT channel: plain text or a table
+    pub command: serde_json::Value,
+    /// Brightness in percent when the window opens and when it closes, ramped in between
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ramp: Option<[u8; 2]>,
+}
+
+/// A named part of a channel, animated independently from the rest of the strip
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Segment {
+    pub name: String,
+    #[serde(default)]
+    pub channel: usize,
+    pub start: i32,
+    pub length: i32,
+    /// Whether the animation runs from the end of the segment to its start
+    #[serde(default)]
+    pub reversed: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Config {
     device_name: String,
     mqtt_host: String,
@@ -12,6 +411,48 @@ pub struct Config {
     mqtt_channel: String,
     wheel_length: Option<i32>,
     strip_length: Option<i32>,
+    homeassistant_discovery: Option<bool>,
+    fps: Option<u32>,
+    /// Number of seconds a manual command suspends the schedule for
+    schedule_override: Option<f64>,
+    /// What the segments run on startup: `restore`, `off` or the name of a scene
+    startup: Option<String>,
+    /// The file the state of the segments is saved to, restored on startup
+    state_file: Option<String>,
+    /// Where the controller is, in degrees, to follow the sun
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    latitude: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    longitude: Option<f64>,
+    #[serde(default)]
+    hardware: HardwareConfig,
+    #[serde(default)]
+    http: HttpConfig,
+    #[serde(default)]
+    socket: SocketConfig,
+    #[serde(default)]
+    dmx: DmxConfig,
+    #[serde(default)]
+    wled: WledConfig,
+    #[serde(default)]
+    printer: PrinterConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    segments: Option<Vec<Segment>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    schedule: Vec<ScheduleRule>,
+    /// Animations defined in the configuration, by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    animations: BTreeMap<String, AnimationDefinition>,
+    /// Named lists of payloads, as accepted on the MQTT channel
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    scenes: BTreeMap<String, Vec<serde_json::Value>>,
+    #[serde(skip)]
+    sources: BTreeMap<String, Source>,
+    /// Where the configuration was loaded from, to reload it
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    overrides: Vec<String>,
 }
 
 impl std::default::Default for Config {
@@ -22,30 +463,143 @@ impl std::default::Default for Config {
             mqtt_port: 1883,
             mqtt_channel: "home/leds".to_string(),
             wheel_length: Some(78),
-            strip_length: Some(96)
+            strip_length: Some(96),
+            homeassistant_discovery: Some(true),
+            fps: Some(DEFAULT_FPS),
+            schedule_override: Some(DEFAULT_SCHEDULE_OVERRIDE),
+            startup: Some(DEFAULT_STARTUP.to_string()),
+            state_file: Some(DEFAULT_STATE_FILE.to_string()),
+            latitude: None,
+            longitude: None,
+            hardware: HardwareConfig::default(),
+            http: HttpConfig::default(),
+            socket: SocketConfig::default(),
+            dmx: DmxConfig::default(),
+            wled: WledConfig::default(),
+            printer: PrinterConfig::default(),
+            segments: None,
+            schedule: Vec::new(),
+            animations: BTreeMap::new(),
+            scenes: BTreeMap::new(),
+            sources: BTreeMap::new(),
+            path: PathBuf::new(),
+            overrides: Vec::new(),
         }
     }
 }
 
 impl Config {
-    /// Creates a new config from the given file path. If the file does
-    pub fn from_file<T>(config_path: T) -> Self
+    /// Loads the configuration by layering, from lowest to highest priority:
+    /// the defaults, the given file, the environment and the command line overrides.
+    ///
+    /// # Arguments
+    ///
+    /// * `config_path` - The path of the TOML configuration file
+    /// * `overrides` - `key=value` overrides given on the command line, nested keys being dotted
+    pub fn load<T>(config_path: T, overrides: &[String]) -> Self
     where
         T: AsRef<Path>
     {
-        match fs::read_to_string(config_path) {
-            Ok(config_str) => toml::from_str(config_str.as_str())
-                .unwrap_or_else(|e| {
-                    warn!("Error in configuration file: {}", e.to_string());
-                    Config::default()
-                }),
+        match Config::try_load(config_path.as_ref(), overrides) {
+            Ok(config) => config,
             Err(e) => {
-                warn!("Unable to read config file: {}", e.to_string());
-                Config::default()
+                warn!("{}", e);
+                Config {
+                    path: config_path.as_ref().to_path_buf(),
+                    overrides: overrides.to_vec(),
+                    ..Config::default()
+                }
             }
         }
     }
 
+    /// Loads the configuration again, from the same file and overrides
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Config, String>` - The new configuration, or why it could not be loaded
+    pub fn reload(&self) -> Result<Config, String> {
+        Config::try_load(&self.path, &self.overrides)
+    }
+
+    /// Returns the default configuration with a placeholder for each optional key,
+    /// so that every key that can be configured appears once serialized
+    fn schema() -> Config {
+        let mut schema = Config {
+            latitude: Some(0.0),
+            longitude: Some(0.0),
+            segments: Some(Vec::new()),
+            ..Config::default()
+        };
+        schema.hardware.channel1 = Some(ChannelConfig::default());
+        schema.socket.group = Some(String::new());
+        schema.dmx.length = Some(0);
+        schema.printer.api_key = Some(String::new());
+
+        schema
+    }
+
+    fn try_load(config_path: &Path, overrides: &[String]) -> Result<Config, String> {
+        let (defaults, schema) = match (Value::try_from(Config::default()), Value::try_from(Config::schema())) {
+            (Ok(Value::Table(d)), Ok(Value::Table(s))) => (d, s),
+            _ => return Err("Unable to serialize the default configuration".to_string()),
+        };
+
+        let mut sources = BTreeMap::new();
+        let mut merged = Table::new();
+        merge(&mut merged, &defaults, Source::Default, &mut sources, "");
+
+        if let Some(file) = file_layer(config_path) {
+            merge(&mut merged, &file, Source::File, &mut sources, "");
+        }
+
+        let environment = env_layer(&schema);
+        merge(&mut merged, &environment, Source::Environment, &mut sources, "");
+
+        let command_line = overrides_layer(&schema, overrides);
+        merge(&mut merged, &command_line, Source::CommandLine, &mut sources, "");
+
+        match Value::Table(merged).try_into::<Config>() {
+            Ok(mut config) => {
+                config.check_lengths().map_err(|e| format!("Error in configuration: {}", e))?;
+                config.sources = sources;
+                config.path = config_path.to_path_buf();
+                config.overrides = overrides.to_vec();
+                Ok(config)
+            },
+            Err(e) => Err(format!("Error in configuration: {}", e)),
+        }
+    }
+
+    /// Checks that the strip has LEDs, and that the wheel fits in it
+    fn check_lengths(&self) -> Result<(), String> {
+        let (strip_length, wheel_length) = (self.get_strip_length(), self.get_wheel_length());
+        if strip_length <= 0 {
+            return Err(format!("strip_length must be positive, got {}", strip_length));
+        }
+        if !(0..=strip_length).contains(&wheel_length) {
+            return Err(format!("wheel_length must be between 0 and the strip_length {}, got {}", strip_length, wheel_length));
+        }
+
+        Ok(())
+    }
+
+    pub fn get_device_name(&self) -> &str {
+        &self.device_name
+    }
+
+    pub fn get_mqtt_host(&self) -> &str {
+        &self.mqtt_host
+    }
+
+    pub fn get_mqtt_port(&self) -> u16 {
+        self.mqtt_port
+    }
+
+    pub fn get_mqtt_channel(&self) -> &str {
+        &self.mqtt_channel
+    }
+
     pub fn get_wheel_length(&self) -> i32 {
         match self.wheel_length {
             Some(wl) => wl,
@@ -60,9 +614,426 @@ impl Config {
         }
     }
 
+    pub fn get_homeassistant_discovery(&self) -> bool {
+        self.homeassistant_discovery.unwrap_or(true)
+    }
+
+    /// Returns the frame rate of the animations, falling back to the default when out of range
+    pub fn get_fps(&self) -> u32 {
+        match self.fps {
+            Some(fps) if (1..=MAX_FPS).contains(&fps) => fps,
+            Some(fps) => {
+                warn!("Ignoring fps {}: must be between 1 and {}", fps, MAX_FPS);
+                DEFAULT_FPS
+            },
+            None => DEFAULT_FPS,
+        }
+    }
+
+    /// Returns the number of seconds a manual command suspends the schedule for
+    pub fn get_schedule_override(&self) -> f64 {
+        match self.schedule_override {
+            Some(duration) if duration.is_finite() && duration >= 0.0 => duration,
+            Some(duration) => {
+                warn!("Ignoring schedule_override {}: must be a positive number of seconds", duration);
+                DEFAULT_SCHEDULE_OVERRIDE
+            },
+            None => DEFAULT_SCHEDULE_OVERRIDE,
+        }
+    }
+
+    /// Returns the latitude and longitude, if both are set and valid
+    pub fn get_location(&self) -> Option<(f64, f64)> {
+        match (self.latitude, self.longitude) {
+            (Some(latitude), Some(longitude)) if (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude) => {
+                Some((latitude, longitude))
+            },
+            (None, None) => None,
+            (latitude, longitude) => {
+                warn!("Ignoring the location {:?}, {:?}: both the latitude (-90 to 90) and longitude (-180 to 180) are needed", latitude, longitude);
+                None
+            },
+        }
+    }
+
+    pub fn get_schedule(&self) -> &[ScheduleRule] {
+        &self.schedule
+    }
+
+    pub fn get_animations(&self) -> &BTreeMap<String, AnimationDefinition> {
+        &self.animations
+    }
+
+    pub fn get_scenes(&self) -> &BTreeMap<String, Vec<serde_json::Value>> {
+        &self.scenes
+    }
+
+    /// Returns the file the scenes saved at runtime are stored in, next to the configuration file
+    pub fn get_scenes_path(&self) -> PathBuf {
+        self.path.with_extension("scenes.toml")
+    }
+
+    pub fn get_startup(&self) -> &str {
+        self.startup.as_deref().unwrap_or(DEFAULT_STARTUP)
+    }
+
+    /// Returns the file the last state is stored in
+    pub fn get_state_path(&self) -> PathBuf {
+        PathBuf::from(self.state_file.as_deref().unwrap_or(DEFAULT_STATE_FILE))
+    }
+
+    pub fn get_hardware(&self) -> &HardwareConfig {
+        &self.hardware
+    }
+
+    pub fn get_http(&self) -> &HttpConfig {
+        &self.http
+    }
+
+    pub fn get_socket(&self) -> &SocketConfig {
+        &self.socket
+    }
+
+    pub fn get_dmx(&self) -> &DmxConfig {
+        &self.dmx
+    }
+
+    pub fn get_wled(&self) -> &WledConfig {
+        &self.wled
+    }
+
+    pub fn get_printer(&self) -> &PrinterConfig {
+        &self.printer
+    }
+
+    /// Returns the number of LEDs of each configured channel
+    pub fn get_channel_lengths(&self) -> Vec<i32> {
+        let mut lengths = vec![self.get_strip_length()];
+        if let Some(channel1) = &self.hardware.channel1 {
+            lengths.push(channel1.length);
+        }
+
+        lengths
+    }
+
+    /// Returns the configured segments, skipping the invalid ones. Without any
+    /// configured segment, channel 0 is split into the `wheel` and the `plate`,
+    /// and channel 1 is a single `channel1` segment.
+    pub fn get_segments(&self) -> Vec<Segment> {
+        let lengths = self.get_channel_lengths();
+
+        let segments = match &self.segments {
+            Some(segments) => segments.clone(),
+            None => {
+                let wheel_length = self.get_wheel_length().clamp(0, lengths[0]);
+                let mut segments = vec![
+                    Segment { name: "wheel".to_string(), channel: 0, start: 0, length: wheel_length, reversed: false },
+                    Segment { name: "plate".to_string(), channel: 0, start: wheel_length, length: lengths[0] - wheel_length, reversed: false },
+                ];
+                if lengths.len() > 1 {
+                    segments.push(Segment { name: "channel1".to_string(), channel: 1, start: 0, length: lengths[1], reversed: false });
+                }
+                segments
+            }
+        };
+
+        let mut valid: Vec<Segment> = vec![];
+        for segment in segments {
+            let channel_length = match lengths.get(segment.channel) {
+                Some(l) => *l,
+                None => {
+                    warn!("Ignoring segment `{}`: channel {} is not configured", segment.name, segment.channel);
+                    continue;
+                }
+            };
+            if segment.length <= 0 || segment.start < 0 || segment.start + segment.length > channel_length {
+                warn!("Ignoring segment `{}`: {}..{} does not fit in the {} LEDs of channel {}",
+                    segment.name, segment.start, segment.start + segment.length, channel_length, segment.channel);
+                continue;
+            }
+            if valid.iter().any(|s| s.name == segment.name) {
+                warn!("Ignoring segment `{}`: the name is already used", segment.name);
+                continue;
+            }
+            valid.push(segment);
+        }
+
+        valid
+    }
+
     pub fn dump(&self) {
         println!("{}", toml::to_string(self)
             .unwrap_or(
                 "Error while deserializing the configuration".to_string()));
     }
+
+    /// Prints the configuration, annotating each value with where it comes from
+    pub fn dump_with_sources(&self) {
+        match Value::try_from(self) {
+            Ok(Value::Table(t)) => print!("{}", annotate(&t, &self.sources, "")),
+            _ => println!("Error while deserializing the configuration"),
+        }
+    }
+}
+
+/// Reads the configuration file as a layer, or nothing if it can not be used
+fn file_layer<T>(config_path: T) -> Option<Table>
+where
+    T: AsRef<Path>
+{
+    match fs::read_to_string(config_path) {
+        Ok(config_str) => toml::from_str(config_str.as_str())
+            .map_err(|e| warn!("Error in configuration file: {}", e))
+            .ok(),
+        Err(e) => {
+            warn!("Unable to read config file: {}", e);
+            None
+        }
+    }
+}
+
+/// Builds a layer from the environment. Every key of the schema, optional ones
+/// included, can be set with `MINILEDS_<KEY>`, nested keys being separated by `__`.
+fn env_layer(schema: &Table) -> Table {
+    let mut layer = Table::new();
+
+    for (variable, key) in LEGACY_ENV {
+        if let Ok(raw) = env::var(variable) {
+            set_override(&mut layer, schema, key, &raw, variable);
+        }
+    }
+
+    for key in leaf_keys(schema, "") {
+        let variable = format!("{}{}", ENV_PREFIX, key.replace('.', "__").to_uppercase());
+        if let Ok(raw) = env::var(&variable) {
+            set_override(&mut layer, schema, &key, &raw, &variable);
+        }
+    }
+
+    layer
+}
+
+/// Builds a layer from the `key=value` overrides of the command line
+fn overrides_layer(schema: &Table, overrides: &[String]) -> Table {
+    let mut layer = Table::new();
+
+    for o in overrides {
+        match o.split_once('=') {
+            Some((key, raw)) => set_override(&mut layer, schema, key.trim(), raw.trim(), "--set"),
+            None => warn!("Ignoring override `{}`, expected key=value", o),
+        }
+    }
+
+    layer
+}
+
+/// Parses a raw override into the type the schema gives the key, and sets it in the layer
+fn set_override(layer: &mut Table, schema: &Table, key: &str, raw: &str, origin: &str) {
+    let value = match lookup(schema, key) {
+        Some(Value::String(_)) => Ok(Value::String(raw.to_string())),
+        Some(Value::Integer(_)) => raw.parse::<i64>().map(Value::Integer).map_err(|e| e.to_string()),
+        Some(Value::Float(_)) => raw.parse::<f64>().map(Value::Float).map_err(|e| e.to_string()),
+        Some(Value::Boolean(_)) => raw.parse::<bool>().map(Value::Boolean).map_err(|e| e.to_string()),
+        _ => toml::from_str::<Table>(&format!("value = {}", raw))
+            .map_err(|e| e.to_string())
+            .and_then(|mut t| t.remove("value").ok_or("missing value".to_string()))
+            .or_else(|_| Ok(Value::String(raw.to_string()))),
+    };
+
+    match value {
+        Ok(v) => insert(layer, key, v),
+        Err(e) => warn!("Ignoring invalid value `{}` for `{}` from {}: {}", raw, key, origin, e),
+    }
+}
+
+/// Returns the value at the given dotted key
+fn lookup<'a>(table: &'a Table, key: &str) -> Option<&'a Value> {
+    match key.split_once('.') {
+        Some((head, rest)) => match table.get(head) {
+            Some(Value::Table(t)) => lookup(t, rest),
+            _ => None,
+        },
+        None => table.get(key),
+    }
+}
+
+/// Inserts the value at the given dotted key, creating the intermediate tables
+fn insert(table: &mut Table, key: &str, value: Value) {
+    match key.split_once('.') {
+        Some((head, rest)) => {
+            let entry = table.entry(head).or_insert_with(|| Value::Table(Table::new()));
+            if !entry.is_table() {
+                *entry = Value::Table(Table::new());
+            }
+            if let Value::Table(t) = entry {
+                insert(t, rest, value);
+            }
+        },
+        None => {
+            table.insert(key.to_string(), value);
+        }
+    }
+}
+
+/// Returns the dotted keys of all the non-table values of the table
+fn leaf_keys(table: &Table, prefix: &str) -> Vec<String> {
+    table.iter().flat_map(|(k, v)| match v {
+        Value::Table(t) => leaf_keys(t, &format!("{}{}.", prefix, k)),
+        _ => vec![format!("{}{}", prefix, k)],
+    }).collect()
+}
+
+/// Merges a layer on top of the base table, recording where each value comes from
+fn merge(base: &mut Table, layer: &Table, source: Source, sources: &mut BTreeMap<String, Source>, prefix: &str) {
+    for (k, v) in layer {
+        let key = format!("{}{}", prefix, k);
+        match (base.get_mut(k), v) {
+            (Some(Value::Table(b)), Value::Table(l)) => merge(b, l, source, sources, &format!("{}.", key)),
+            _ => {
+                base.insert(k.clone(), v.clone());
+                match v {
+                    Value::Table(t) => {
+                        for leaf in leaf_keys(t, &format!("{}.", key)) {
+                            sources.insert(leaf, source);
+                        }
+                    },
+                    _ => {
+                        sources.insert(key, source);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Formats the table as TOML, with the source of each value as a comment
+fn annotate(table: &Table, sources: &BTreeMap<String, Source>, prefix: &str) -> String {
+    let mut out = String::new();
+
+    for (k, v) in table.iter().filter(|(_, v)| !v.is_table()) {
+        let source = sources.get(&format!("{}{}", prefix, k)).copied().unwrap_or(Source::Default);
+        out.push_str(&format!("{} = {} # {}\n", k, v, source));
+    }
+
+    for (k, v) in table {
+        if let Value::Table(t) = v {
+            let name = format!("{}{}", prefix, k);
+            out.push_str(&format!("\n[{}]\n", name));
+            out.push_str(&annotate(t, sources, &format!("{}.", name)));
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a configuration file unique to the test, removed when dropped
+    struct ConfigFile(PathBuf);
+
+    impl ConfigFile {
+        fn new(name: &str, content: &str) -> ConfigFile {
+            let path = env::temp_dir().join(format!("minileds-{}-{}.toml", name, std::process::id()));
+            fs::write(&path, content).unwrap();
+            ConfigFile(path)
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    // The environment is shared by the tests, so it is only changed by this one
+    #[test]
+    fn layers_take_precedence_in_order() {
+        let file = ConfigFile::new("layers", r#"
+            device_name = "from-file"
+            mqtt_host = "file.local"
+            mqtt_port = 1884
+            fps = 60
+        "#);
+        let variables = [
+            ("MINILEDS_MQTT_PORT", "1885"),
+            ("MINILEDS_FPS", "100"),
+            ("MINILEDS_LATITUDE", "48.85"),
+            ("MINILEDS_LONGITUDE", "2.35"),
+            ("MINILEDS_DMX__LENGTH", "12"),
+            ("MINILEDS_PRINTER__API_KEY", "1234"),
+            ("MINILEDS_HARDWARE__CHANNEL1__LENGTH", "30"),
+        ];
+        for (variable, value) in variables {
+            env::set_var(variable, value);
+        }
+        let config = Config::load(&file.0, &["fps=30".to_string()]);
+        for (variable, _) in variables {
+            env::remove_var(variable);
+        }
+
+        assert_eq!(config.get_mqtt_channel(), "home/leds");
+        assert_eq!(config.get_device_name(), "from-file");
+        assert_eq!(config.get_mqtt_host(), "file.local");
+        assert_eq!(config.get_mqtt_port(), 1885);
+        assert_eq!(config.get_fps(), 30);
+
+        // Keys without a default value
+        assert_eq!(config.get_location(), Some((48.85, 2.35)));
+        assert_eq!(config.get_dmx().length, Some(12));
+        assert_eq!(config.get_printer().api_key.as_deref(), Some("1234"));
+        assert_eq!(config.get_channel_lengths(), vec![96, 30]);
+
+        assert_eq!(config.sources.get("mqtt_channel"), Some(&Source::Default));
+        assert_eq!(config.sources.get("mqtt_host"), Some(&Source::File));
+        assert_eq!(config.sources.get("mqtt_port"), Some(&Source::Environment));
+        assert_eq!(config.sources.get("fps"), Some(&Source::CommandLine));
+        assert_eq!(config.sources.get("dmx.length"), Some(&Source::Environment));
+    }
+
+    #[test]
+    fn overrides_are_typed_by_the_schema() {
+        let file = ConfigFile::new("overrides", "");
+        let overrides = ["longitude=-3".to_string(), "latitude=51".to_string(), "device_name=42".to_string()];
+        let config = Config::load(&file.0, &overrides);
+
+        assert_eq!(config.get_location(), Some((51.0, -3.0)));
+        assert_eq!(config.get_device_name(), "42");
+    }
+
+    #[test]
+    fn rejects_invalid_lengths() {
+        let load = |content: &str| {
+            let file = ConfigFile::new("lengths", content);
+            Config::try_load(&file.0, &[]).map(|c| c.get_segments().iter().filter(|s| s.channel == 0).count())
+        };
+
+        assert_eq!(load("strip_length = 10\nwheel_length = 4"), Ok(2));
+        assert_eq!(load("strip_length = 10\nwheel_length = 10"), Ok(1));
+        for content in ["strip_length = -5", "strip_length = 0", "strip_length = 10\nwheel_length = 11", "wheel_length = -1"] {
+            assert!(load(content).is_err(), "accepted {}", content);
+        }
+    }
+
+    #[test]
+    fn animation_definitions_reject_unknown_keys() {
+        let definition = |content: &str| toml::from_str::<AnimationDefinition>(content).map(|d| d.motion);
+
+        assert_eq!(definition(r##"palette = ["#ff0000"]
+            motion = "scroll""##), Ok("scroll".to_string()));
+        assert!(definition(r##"palette = ["#ff0000"]
+            motoin = "scroll""##).is_err());
+    }
+
+    #[test]
+    fn schema_has_every_optional_key() {
+        let schema = match Value::try_from(Config::schema()) {
+            Ok(Value::Table(t)) => t,
+            _ => panic!("the schema must serialize to a table"),
+        };
+        for key in ["latitude", "longitude", "segments", "hardware.channel1.pin", "dmx.length", "printer.api_key"] {
+            assert!(lookup(&schema, key).is_some(), "missing {}", key);
+        }
+    }
 }
@@ -0,0 +1,260 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{mpsc, Arc, RwLock};
+use std::thread;
+use std::time::Instant;
+
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use crate::command::{Command, Targets};
+use crate::config::SocketConfig;
+use crate::state::SharedState;
+
+/// Local control interface, listening on a Unix socket. Each line sent by a
+/// client is either a payload accepted on the MQTT channel, `status`, `list` or `scenes`,
+/// and is answered by a single line of JSON.
+#[derive(Clone)]
+pub struct ControlSocket {
+    commands: mpsc::Sender<Command>,
+    targets: Arc<RwLock<Targets>>,
+    state: SharedState,
+    started_at: Instant,
+}
+
+impl ControlSocket {
+    pub fn new(commands: mpsc::Sender<Command>, targets: Arc<RwLock<Targets>>, state: SharedState, started_at: Instant) -> ControlSocket {
+        ControlSocket {
+            commands,
+            targets,
+            state,
+            started_at,
+        }
+    }
+
+    /// Accepts clients until the socket fails, each one in its own thread
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Where the socket is and who can use it. A socket left at
+    ///   its path is replaced, but any other kind of file is kept.
+    pub fn serve(&self, config: &SocketConfig) {
+        let path = Path::new(&config.path);
+        let access = u32::from_str_radix(&config.mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o777)
+            .ok_or(format!("invalid mode `{}`, expected octal permissions such as 0660", config.mode))
+            .and_then(|mode| match &config.group {
+                Some(group) => group_id(group).map(|gid| (mode, Some(gid))),
+                None => Ok((mode, None)),
+            });
+        let (mode, gid) = match access {
+            Ok(access) => access,
+            Err(e) => {
+                warn!("Not listening on the control socket {}: {}", path.display(), e);
+                return;
+            }
+        };
+
+        // A socket left by a previous run would prevent binding
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                if let Err(e) = fs::remove_file(path) {
+                    warn!("Unable to remove the stale control socket {}: {}", path.display(), e);
+                    return;
+                }
+            },
+            Ok(_) => {
+                warn!("Not listening on the control socket {}: the path exists and is not a socket", path.display());
+                return;
+            },
+            Err(_) => {
+                if let Some(parent) = path.parent() {
+                    if let Err(e) = fs::create_dir_all(parent) {
+                        warn!("Unable to create the directory of the control socket {}: {}", path.display(), e);
+                        return;
+                    }
+                }
+            },
+        }
+
+        let listener = match UnixListener::bind(path) {
+            Ok(l) => l,
+            Err(e) => {
+                warn!("Unable to listen on the control socket {}: {}", path.display(), e);
+                return;
+            }
+        };
+        let restricted = std::os::unix::fs::chown(path, None, gid)
+            .and_then(|_| fs::set_permissions(path, fs::Permissions::from_mode(mode)));
+        if let Err(e) = restricted {
+            warn!("Not listening on the control socket {}: unable to set its owner and mode: {}", path.display(), e);
+            let _ = fs::remove_file(path);
+            return;
+        }
+        info!("Control socket listening on {}", path.display());
+
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let control = self.clone();
+                    thread::spawn(move || control.handle(stream));
+                },
+                Err(e) => warn!("Unable to accept a control client: {}", e),
+            }
+        }
+    }
+
+    fn handle(&self, stream: UnixStream) {
+        let mut writer = match stream.try_clone() {
+            Ok(w) => w,
+            Err(e) => {
+                warn!("Unable to answer the control client: {}", e);
+                return;
+            }
+        };
+
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => return,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let response = self.answer(line.trim());
+            if writeln!(writer, "{}", response).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Answers a line sent by a client
+    fn answer(&self, line: &str) -> Value {
+        let targets = match self.targets.read() {
+            Ok(t) => t.clone(),
+            Err(e) => e.into_inner().clone(),
+        };
+
+        match line {
+            "status" => self.state.get(&self.started_at),
+            "list" => {
+                let mut animations = targets.animations;
+                animations.sort();
+                json!(animations)
+            },
+            "scenes" => json!(targets.scenes),
+            _ => match Command::parse(line, &targets) {
+                Ok(command) => match self.commands.send(command) {
+                    Ok(_) => json!({"status": "accepted"}),
+                    Err(_) => json!({"error": "the animation loop is not running"}),
+                },
+                Err(e) => {
+                    warn!("Rejected command `{}`: {}", line, e);
+                    json!({"error": e})
+                },
+            },
+        }
+    }
+}
+
+/// Resolves a group, given by name or id, to its id
+fn group_id(group: &str) -> Result<u32, String> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(gid);
+    }
+
+    let groups = fs::read_to_string("/etc/group").map_err(|e| format!("unable to read /etc/group: {}", e))?;
+    groups.lines()
+        .map(|line| line.split(':').collect::<Vec<&str>>())
+        .find(|fields| fields.first() == Some(&group))
+        .and_then(|fields| fields.get(2).and_then(|gid| gid.parse().ok()))
+        .ok_or(format!("unknown group `{}`", group))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::command::AnimationCommand;
+    use crate::config::Segment;
+
+    /// Serves a control socket in a directory unique to the test, not created yet
+    fn serve(name: &str, mode: &str) -> (std::path::PathBuf, mpsc::Receiver<Command>) {
+        let directory = env::temp_dir().join(format!("minileds-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let path = directory.join("minileds.sock");
+
+        let targets = Targets {
+            animations: ["rainbow", "off", "chase"].map(String::from).to_vec(),
+            segments: vec![Segment { name: "desk".to_string(), channel: 0, start: 0, length: 10, reversed: false }],
+            scenes: vec!["evening".to_string()],
+        };
+        let (commands, received) = mpsc::channel();
+        let control = ControlSocket::new(commands, Arc::new(RwLock::new(targets)), SharedState::default(), Instant::now());
+        let config = SocketConfig { path: path.display().to_string(), mode: mode.to_string(), ..SocketConfig::default() };
+        thread::spawn(move || control.serve(&config));
+
+        for _ in 0..200 {
+            if path.exists() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        (path, received)
+    }
+
+    #[test]
+    fn answers_each_line() {
+        let (path, received) = serve("control", "0600");
+        let stream = UnixStream::connect(&path).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let mut ask = |line: &str| -> Value {
+            writeln!(writer, "{}", line).unwrap();
+            serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap()
+        };
+
+        assert_eq!(ask("list"), json!(["chase", "off", "rainbow"]));
+        assert_eq!(ask("scenes"), json!(["evening"]));
+        assert_eq!(ask("  chase  "), json!({"status": "accepted"}));
+        assert_eq!(received.try_recv(), Ok(Command::SetAnimation(AnimationCommand::new("chase"))));
+        assert_eq!(ask(r#"{"command":"brightness","brightness":40}"#), json!({"status": "accepted"}));
+        assert_eq!(received.try_recv(), Ok(Command::SetBrightness { segment: None, channel: None, brightness: 40 }));
+        assert_eq!(ask("\nsparkles"), json!({"error": "unknown animation `sparkles`"}));
+        assert!(ask("{\"animation\":").get("error").is_some());
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn keeps_files_that_are_not_sockets() {
+        let directory = env::temp_dir().join(format!("minileds-control-file-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("minileds.sock");
+        fs::write(&path, "data").unwrap();
+
+        let (commands, _) = mpsc::channel();
+        let targets = Targets { animations: vec![], segments: vec![], scenes: vec![] };
+        let control = ControlSocket::new(commands, Arc::new(RwLock::new(targets)), SharedState::default(), Instant::now());
+        control.serve(&SocketConfig { path: path.display().to_string(), ..SocketConfig::default() });
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "data");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn resolves_groups() {
+        assert_eq!(group_id("0"), Ok(0));
+        assert_eq!(group_id("root"), Ok(0));
+        assert!(group_id("no-such-group-here").is_err());
+    }
+}
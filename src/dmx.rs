@@ -0,0 +1,409 @@
+use std::net::{Ipv4Addr, UdpSocket};
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::config::DmxConfig;
+use crate::realtime::RealtimeFrame;
+use crate::sinks::RawColor;
+
+/// Number of channels in a DMX universe
+const UNIVERSE_SIZE: usize = 512;
+/// Longest time the frames are shown without new data
+const MAX_TIMEOUT: Duration = Duration::from_secs(365 * 24 * 3600);
+
+/// The protocols carrying DMX universes over UDP
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    E131,
+    ArtNet,
+}
+
+impl Protocol {
+    pub fn from_name(name: &str) -> Option<Protocol> {
+        match name {
+            "e131" | "sacn" => Some(Protocol::E131),
+            "artnet" => Some(Protocol::ArtNet),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protocol::E131 => "e131",
+            Protocol::ArtNet => "artnet",
+        }
+    }
+
+    fn port(&self) -> u16 {
+        match self {
+            Protocol::E131 => 5568,
+            Protocol::ArtNet => 6454,
+        }
+    }
+
+    /// Extracts the universe and the DMX data of a packet
+    ///
+    /// # Returns
+    ///
+    /// * `Option<(u16, &[u8])>` - The universe and its channels, none if the packet carries no DMX data
+    pub fn parse<'a>(&self, packet: &'a [u8]) -> Option<(u16, &'a [u8])> {
+        match self {
+            Protocol::E131 => parse_e131(packet),
+            Protocol::ArtNet => parse_artnet(packet),
+        }
+    }
+}
+
+/// Parses an E1.31 data packet (ANSI E1.31, section 4)
+fn parse_e131(packet: &[u8]) -> Option<(u16, &[u8])> {
+    const ACN_ID: &[u8] = b"ASC-E1.17\0\0\0";
+    const VECTOR_ROOT_E131_DATA: u32 = 0x04;
+    const VECTOR_E131_DATA_PACKET: u32 = 0x02;
+    const VECTOR_DMP_SET_PROPERTY: u8 = 0x02;
+    const PREVIEW_DATA: u8 = 0x80;
+
+    let be_u16 = |at: usize| u16::from_be_bytes([packet[at], packet[at + 1]]);
+    let be_u32 = |at: usize| u32::from_be_bytes([packet[at], packet[at + 1], packet[at + 2], packet[at + 3]]);
+
+    if packet.len() < 126
+        || &packet[4..16] != ACN_ID
+        || be_u32(18) != VECTOR_ROOT_E131_DATA
+        || be_u32(40) != VECTOR_E131_DATA_PACKET
+        || packet[117] != VECTOR_DMP_SET_PROPERTY
+        || packet[112] & PREVIEW_DATA != 0
+        || packet[125] != 0 // Only the default start code carries levels
+    {
+        return None;
+    }
+
+    let universe = be_u16(113);
+    let count = (be_u16(123) as usize).saturating_sub(1);
+    Some((universe, &packet[126..(126 + count).min(packet.len())]))
+}
+
+/// Parses an Art-Net ArtDmx packet (Art-Net 4, OpDmx)
+fn parse_artnet(packet: &[u8]) -> Option<(u16, &[u8])> {
+    const ID: &[u8] = b"Art-Net\0";
+    const OP_DMX: u16 = 0x5000;
+
+    if packet.len() < 18 || &packet[0..8] != ID || u16::from_le_bytes([packet[8], packet[9]]) != OP_DMX {
+        return None;
+    }
+
+    // The port address is made of the net (bits 8-14) and the sub-net and universe (bits 0-7)
+    let universe = u16::from_le_bytes([packet[14], packet[15]]) & 0x7fff;
+    let length = u16::from_be_bytes([packet[16], packet[17]]) as usize;
+    Some((universe, &packet[18..(18 + length).min(packet.len())]))
+}
+
+/// Maps the channels of consecutive DMX universes onto the LEDs. Each LED uses
+/// one DMX channel per colour, and never spans two universes.
+pub struct DmxMapping {
+    universe: u16,
+    /// Index of the first DMX channel of the first universe
+    offset: usize,
+    /// The colour component, as an index in (red, green, blue, white), of each DMX channel of a LED
+    order: Vec<usize>,
+    length: usize,
+}
+
+impl DmxMapping {
+    /// Checks the configuration and builds the mapping
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The settings of the receiver
+    /// * `channel_length` - The number of LEDs of the ws281x channel the universes are mapped to
+    pub fn new(config: &DmxConfig, channel_length: usize) -> Result<DmxMapping, String> {
+        if !(1..=UNIVERSE_SIZE as u16).contains(&config.start_address) {
+            return Err(format!("start address {} must be between 1 and {}", config.start_address, UNIVERSE_SIZE));
+        }
+
+        let order: Vec<usize> = config.color_order.chars().filter_map(|c| "rgbw".find(c)).collect();
+        let valid_order = order.len() == config.color_order.len()
+            && (0..3).all(|c| order.iter().filter(|o| **o == c).count() == 1)
+            && order.iter().filter(|o| **o == 3).count() <= 1;
+        if !valid_order {
+            return Err(format!("colour order `{}` must contain r, g and b once, and w at most once", config.color_order));
+        }
+
+        if config.start >= channel_length {
+            return Err(format!("start LED {} is past the end of channel {}", config.start, config.channel));
+        }
+        let length = config.length.unwrap_or(channel_length - config.start).min(channel_length - config.start);
+
+        Ok(DmxMapping {
+            universe: config.universe,
+            offset: config.start_address as usize - 1,
+            order,
+            length,
+        })
+    }
+
+    /// Returns the number of universes the LEDs span
+    pub fn universes(&self) -> usize {
+        let first = (UNIVERSE_SIZE - self.offset) / self.order.len();
+        1 + self.length.saturating_sub(first).div_ceil(UNIVERSE_SIZE / self.order.len())
+    }
+
+    /// Converts the data of a universe into LEDs
+    ///
+    /// # Returns
+    ///
+    /// * `Option<(usize, Vec<RawColor>)>` - The index of the first LED of the universe and its LEDs,
+    ///   none if the universe is not mapped
+    pub fn map(&self, universe: u16, data: &[u8]) -> Option<(usize, Vec<RawColor>)> {
+        let index = universe.checked_sub(self.universe)? as usize;
+        let per_universe = UNIVERSE_SIZE / self.order.len();
+        let first_universe = (UNIVERSE_SIZE - self.offset) / self.order.len();
+        let (first_led, offset, capacity) = match index {
+            0 => (0, self.offset, first_universe),
+            _ => (first_universe + (index - 1) * per_universe, 0, per_universe),
+        };
+        if first_led >= self.length {
+            return None;
+        }
+
+        let count = capacity.min(self.length - first_led);
+        let leds = data.get(offset..)?
+            .chunks_exact(self.order.len())
+            .take(count)
+            .map(|channels| {
+                let mut rgbw = [0; 4];
+                for (component, level) in self.order.iter().zip(channels) {
+                    rgbw[*component] = *level;
+                }
+                [rgbw[2], rgbw[1], rgbw[0], rgbw[3]]
+            })
+            .collect();
+
+        Some((first_led, leds))
+    }
+}
+
+/// Receives DMX universes over UDP and writes them to the realtime frame
+pub struct DmxReceiver {
+    protocol: Protocol,
+    mapping: DmxMapping,
+    channel: usize,
+    start: usize,
+    timeout: Duration,
+    realtime: RealtimeFrame,
+}
+
+impl DmxReceiver {
+    /// Checks the timeout of the configuration and builds the receiver
+    ///
+    /// # Returns
+    ///
+    /// * `Result<DmxReceiver, String>` - The receiver, or the reason the configuration is invalid
+    pub fn new(protocol: Protocol, mapping: DmxMapping, config: &DmxConfig, realtime: RealtimeFrame) -> Result<DmxReceiver, String> {
+        let timeout = Duration::try_from_secs_f64(config.timeout)
+            .ok()
+            .filter(|t| *t <= MAX_TIMEOUT)
+            .ok_or(format!("timeout {} must be a number of seconds between 0 and {}", config.timeout, MAX_TIMEOUT.as_secs()))?;
+
+        Ok(DmxReceiver {
+            protocol,
+            mapping,
+            channel: config.channel,
+            start: config.start,
+            timeout,
+            realtime,
+        })
+    }
+
+    /// Receives packets until the socket fails
+    ///
+    /// # Arguments
+    ///
+    /// * `bind` - The address to listen on, the port being the one of the protocol
+    pub fn serve(&self, bind: &str) {
+        let socket = match UdpSocket::bind((bind, self.protocol.port())) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Unable to listen for {} on {}:{}: {}", self.protocol.name(), bind, self.protocol.port(), e);
+                return;
+            }
+        };
+        info!("Listening for {} on {}:{}", self.protocol.name(), bind, self.protocol.port());
+
+        // E1.31 senders usually multicast each universe on 239.255.<universe>
+        if self.protocol == Protocol::E131 {
+            let first = self.mapping.universe;
+            for u in first..first.saturating_add(self.mapping.universes() as u16) {
+                let [high, low] = u.to_be_bytes();
+                if let Err(e) = socket.join_multicast_v4(&Ipv4Addr::new(239, 255, high, low), &Ipv4Addr::UNSPECIFIED) {
+                    warn!("Unable to join the multicast group of universe {}: {}", u, e);
+                }
+            }
+        }
+
+        let mut buffer = [0; 1500];
+        loop {
+            let size = match socket.recv(&mut buffer) {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("Unable to receive {} packets: {}", self.protocol.name(), e);
+                    return;
+                }
+            };
+
+            let mapped = self.protocol.parse(&buffer[..size])
+                .and_then(|(universe, data)| self.mapping.map(universe, data));
+            if let Some((first_led, leds)) = mapped {
+                self.realtime.write(self.channel, self.start + first_led, &leds, self.protocol.name(), self.timeout);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds an E1.31 data packet carrying the given levels
+    fn e131(universe: u16, levels: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; 126];
+        packet[4..16].copy_from_slice(b"ASC-E1.17\0\0\0");
+        packet[18..22].copy_from_slice(&4u32.to_be_bytes());
+        packet[40..44].copy_from_slice(&2u32.to_be_bytes());
+        packet[113..115].copy_from_slice(&universe.to_be_bytes());
+        packet[117] = 0x02;
+        packet[123..125].copy_from_slice(&(levels.len() as u16 + 1).to_be_bytes());
+        packet.extend_from_slice(levels);
+        packet
+    }
+
+    /// Builds an Art-Net ArtDmx packet carrying the given levels
+    fn artnet(port_address: u16, levels: &[u8]) -> Vec<u8> {
+        let mut packet = b"Art-Net\0".to_vec();
+        packet.extend_from_slice(&0x5000u16.to_le_bytes());
+        packet.extend_from_slice(&[0, 14, 0, 0]);
+        packet.extend_from_slice(&port_address.to_le_bytes());
+        packet.extend_from_slice(&(levels.len() as u16).to_be_bytes());
+        packet.extend_from_slice(levels);
+        packet
+    }
+
+    fn mapping(color_order: &str, start_address: u16, length: usize) -> DmxMapping {
+        let config = DmxConfig {
+            color_order: color_order.to_string(),
+            start_address,
+            universe: 3,
+            ..DmxConfig::default()
+        };
+        DmxMapping::new(&config, length).unwrap()
+    }
+
+    #[test]
+    fn parses_e131_data() {
+        let packet = e131(7, &[10, 20, 30]);
+        assert_eq!(Protocol::E131.parse(&packet), Some((7, &[10, 20, 30][..])));
+
+        // A count past the end of the packet is cut to the levels received
+        let mut truncated = packet.clone();
+        truncated[123..125].copy_from_slice(&100u16.to_be_bytes());
+        assert_eq!(Protocol::E131.parse(&truncated), Some((7, &[10, 20, 30][..])));
+    }
+
+    #[test]
+    fn ignores_other_e131_packets() {
+        let packet = e131(7, &[10, 20, 30]);
+        let with = |at: usize, value: u8| {
+            let mut p = packet.clone();
+            p[at] = value;
+            p
+        };
+
+        assert_eq!(Protocol::E131.parse(&packet[..125]), None);
+        assert_eq!(Protocol::E131.parse(&with(4, b'X')), None);
+        assert_eq!(Protocol::E131.parse(&with(21, 0x08)), None); // Extended root vector, e.g. discovery
+        assert_eq!(Protocol::E131.parse(&with(112, 0x80)), None); // Preview data
+        assert_eq!(Protocol::E131.parse(&with(125, 0xdd)), None); // Per-address priorities
+    }
+
+    #[test]
+    fn parses_artnet_data() {
+        let packet = artnet(0x0102, &[1, 2, 3, 4]);
+        assert_eq!(Protocol::ArtNet.parse(&packet), Some((0x0102, &[1, 2, 3, 4][..])));
+        assert_eq!(Protocol::ArtNet.parse(&artnet(0x8005, &[1])), Some((5, &[1][..])));
+
+        let mut truncated = packet.clone();
+        truncated.truncate(20);
+        assert_eq!(Protocol::ArtNet.parse(&truncated), Some((0x0102, &[1, 2][..])));
+    }
+
+    #[test]
+    fn ignores_other_artnet_packets() {
+        let mut poll = artnet(1, &[1, 2, 3]);
+        poll[8..10].copy_from_slice(&0x2000u16.to_le_bytes());
+        assert_eq!(Protocol::ArtNet.parse(&poll), None);
+        assert_eq!(Protocol::ArtNet.parse(&artnet(1, &[])[..17]), None);
+        assert_eq!(Protocol::ArtNet.parse(&e131(1, &[1, 2, 3])), None);
+        assert_eq!(Protocol::E131.parse(&artnet(1, &[1, 2, 3])), None);
+    }
+
+    #[test]
+    fn maps_consecutive_universes() {
+        // 170 RGB LEDs fit in a universe
+        let mapping = mapping("grb", 1, 200);
+        assert_eq!(mapping.universes(), 2);
+
+        let (first, leds) = mapping.map(3, &[10, 20, 30].repeat(171)).unwrap();
+        assert_eq!((first, leds.len()), (0, 170));
+        assert_eq!(leds[0], [30, 10, 20, 0]);
+
+        let (first, leds) = mapping.map(4, &[10, 20, 30].repeat(170)).unwrap();
+        assert_eq!((first, leds.len()), (170, 30));
+
+        assert_eq!(mapping.map(2, &[10, 20, 30]), None);
+        assert_eq!(mapping.map(5, &[10, 20, 30]), None);
+    }
+
+    #[test]
+    fn maps_from_the_start_address() {
+        let mapping = mapping("rgbw", 5, 300);
+        assert_eq!(mapping.universes(), 3);
+
+        // The first universe holds 127 LEDs after the 4 channels skipped
+        let (first, leds) = mapping.map(3, &[0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+        assert_eq!(first, 0);
+        assert_eq!(leds, vec![[3, 2, 1, 4]]);
+
+        let (first, leds) = mapping.map(4, &[1, 2, 3, 4]).unwrap();
+        assert_eq!((first, leds), (127, vec![[3, 2, 1, 4]]));
+    }
+
+    #[test]
+    fn rejects_invalid_timeouts() {
+        let receiver = |timeout: f64| {
+            let config = DmxConfig { timeout, ..DmxConfig::default() };
+            DmxReceiver::new(Protocol::E131, mapping("rgb", 1, 10), &config, RealtimeFrame::new(&[10])).map(|r| r.timeout)
+        };
+
+        assert_eq!(receiver(0.0), Ok(Duration::ZERO));
+        assert_eq!(receiver(2.5), Ok(Duration::from_millis(2500)));
+        for timeout in [-1.0, f64::NAN, f64::INFINITY, 1e30] {
+            assert!(receiver(timeout).is_err(), "accepted {}", timeout);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_mappings() {
+        let config = |order: &str, start_address: u16, start: usize| DmxConfig {
+            color_order: order.to_string(),
+            start_address,
+            start,
+            ..DmxConfig::default()
+        };
+
+        assert!(DmxMapping::new(&config("rgb", 0, 0), 10).is_err());
+        assert!(DmxMapping::new(&config("rgb", 513, 0), 10).is_err());
+        assert!(DmxMapping::new(&config("rgbb", 1, 0), 10).is_err());
+        assert!(DmxMapping::new(&config("rgx", 1, 0), 10).is_err());
+        assert!(DmxMapping::new(&config("rgb", 1, 10), 10).is_err());
+        assert!(DmxMapping::new(&config("wrgb", 1, 9), 10).is_ok());
+    }
+}
@@ -0,0 +1,293 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::command::{AnimationCommand, Command, Targets, MAX_TRANSITION_DURATION};
+use crate::state::{availability_topic, ControllerState};
+use crate::transition::TransitionKind;
+
+/// Animation started when Home Assistant turns the light on without an effect
+pub const DEFAULT_EFFECT: &str = "srainbow";
+
+/// Returns the topic Home Assistant accepts commands on
+pub fn command_topic(mqtt_channel: &str) -> String {
+    format!("{}/ha/set", mqtt_channel)
+}
+
+/// Returns the topic the Home Assistant state is published on
+pub fn state_topic(mqtt_channel: &str) -> String {
+    format!("{}/ha/state", mqtt_channel)
+}
+
+/// Returns the discovery topic of the light entity
+pub fn discovery_topic(device_name: &str) -> String {
+    format!("homeassistant/light/{}/config", object_id(device_name))
+}
+
+/// Home Assistant only accepts `[a-zA-Z0-9_-]` in object ids
+fn object_id(device_name: &str) -> String {
+    device_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect()
+}
+
+/// Builds the discovery config of a JSON schema light entity
+///
+/// # Arguments
+///
+/// * `device_name` - The name of the device, also used as unique id
+/// * `mqtt_channel` - The channel the controller listens on
+/// * `animations` - The names of the available animations, exposed as effects
+pub fn discovery_config(device_name: &str, mqtt_channel: &str, animations: &[String]) -> String {
+    let mut effects = animations.to_vec();
+    effects.sort();
+
+    json!({
+        "name": device_name,
+        "unique_id": object_id(device_name),
+        "schema": "json",
+        "command_topic": command_topic(mqtt_channel),
+        "state_topic": state_topic(mqtt_channel),
+        "availability_topic": availability_topic(mqtt_channel),
+        "brightness": true,
+        "brightness_scale": 255,
+        "supported_color_modes": ["rgb"],
+        "effect": true,
+        "effect_list": effects,
+        "device": {
+            "identifiers": [object_id(device_name)],
+            "name": device_name,
+            "model": "MiniLEDs",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        },
+    })
+    .to_string()
+}
+
+#[derive(Deserialize)]
+struct HaColor {
+    r: u8,
+    g: u8,
+    b: u8,
+}
+
+/// A command sent by Home Assistant using the JSON schema
+#[derive(Deserialize)]
+struct HaCommand {
+    state: Option<String>,
+    brightness: Option<u8>,
+    rgb_color: Option<[u8; 3]>,
+    color: Option<HaColor>,
+    effect: Option<String>,
+    /// Transition duration, in seconds
+    transition: Option<f64>,
+}
+
+/// Rebuilds the command running on the base layer of the first segment from
+/// the state of the controller, an animation fading out being reported as off
+///
+/// # Arguments
+///
+/// * `state` - The state of the controller, as published on MQTT
+pub fn base_command(state: &Value) -> AnimationCommand {
+    let animation = match state["animation"].as_str() {
+        Some(animation) if !state["stopping"].as_bool().unwrap_or(false) => animation,
+        _ => "off",
+    };
+
+    let mut command = AnimationCommand::new(animation);
+    command.params.brightness = state["brightness"].as_u64().map_or(100, |b| b.min(100) as u8);
+    command.params.color = serde_json::from_value(state["color"].clone()).ok();
+    command.params.speed = state["segments"][0]["speed"].as_f64().unwrap_or(1.0);
+    command
+}
+
+/// Parses a Home Assistant command into a command for the whole strip.
+/// Fields that are not part of the command are kept from the current one,
+/// and a command only changing the brightness does not restart the animation.
+///
+/// # Arguments
+///
+/// * `payload` - The raw JSON payload
+/// * `last` - The command running on the base layer, as built by `base_command`
+/// * `targets` - The animations and segments commands can refer to
+pub fn parse_command(payload: &str, last: &AnimationCommand, targets: &Targets) -> Result<Command, String> {
+    let raw: HaCommand = serde_json::from_str(payload)
+        .map_err(|e| format!("invalid Home Assistant command: {}", e))?;
+
+    let mut command = AnimationCommand {
+        segment: None,
+        channel: None,
+        transition: TransitionKind::Fade,
+        ..last.clone()
+    };
+
+    if let Some(transition) = raw.transition {
+        if !transition.is_finite() || transition < 0.0 {
+            return Err(format!("invalid transition `{}`", transition));
+        }
+        command.transition = TransitionKind::Crossfade;
+        command.transition_duration = transition.min(MAX_TRANSITION_DURATION);
+    }
+
+    match raw.state.as_deref() {
+        Some("OFF") => {
+            command.animation = "off".to_string();
+            return Ok(Command::SetAnimation(command));
+        },
+        Some("ON") | None => {},
+        Some(other) => return Err(format!("unknown state `{}`", other)),
+    }
+
+    let brightness_only = raw.effect.is_none() && raw.rgb_color.is_none() && raw.color.is_none();
+    if let (true, Some(brightness)) = (brightness_only && last.animation != "off", raw.brightness) {
+        return Ok(Command::SetBrightness {
+            segment: None,
+            channel: None,
+            brightness: ((brightness as u16 * 100 + 127) / 255) as u8,
+        });
+    }
+
+    if let Some(effect) = raw.effect {
+        if !targets.animations.contains(&effect) {
+            return Err(format!("unknown effect `{}`", effect));
+        }
+        command.animation = effect;
+    } else if command.animation == "off" {
+        command.animation = DEFAULT_EFFECT.to_string();
+    }
+
+    if let Some(brightness) = raw.brightness {
+        command.params.brightness = ((brightness as u16 * 100 + 127) / 255) as u8;
+    }
+
+    if let Some([r, g, b]) = raw.rgb_color {
+        command.params.color = Some((r, g, b));
+    } else if let Some(color) = raw.color {
+        command.params.color = Some((color.r, color.g, color.b));
+    }
+
+    command.validate(targets)?;
+    Ok(Command::SetAnimation(command))
+}
+
+/// Builds the JSON schema state payload for the given controller state
+pub fn state_payload(state: &ControllerState) -> String {
+    let on = state.animation != "off" && !state.stopping;
+    let mut payload = json!({
+        "state": if on { "ON" } else { "OFF" },
+        "brightness": (state.brightness as u16 * 255 / 100) as u8,
+        "effect": state.animation,
+        "color_mode": "rgb",
+    });
+    if let Some((r, g, b)) = state.color {
+        payload["color"] = json!({"r": r, "g": g, "b": b});
+    }
+
+    payload.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Segment;
+
+    fn targets() -> Targets {
+        Targets {
+            animations: ["off", "rainbow", "srainbow", "chase"].map(String::from).to_vec(),
+            segments: vec![Segment { name: "strip".to_string(), channel: 0, start: 0, length: 10, reversed: false }],
+            scenes: vec![],
+        }
+    }
+
+    fn running(animation: &str) -> AnimationCommand {
+        let mut command = AnimationCommand::new(animation);
+        command.params.brightness = 40;
+        command.params.color = Some((1, 2, 3));
+        command.params.speed = 2.0;
+        command
+    }
+
+    fn animation(command: Command) -> AnimationCommand {
+        match command {
+            Command::SetAnimation(c) => c,
+            other => panic!("expected an animation, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn brightness_alone_keeps_the_animation() {
+        let command = parse_command(r#"{"state":"ON","brightness":128}"#, &running("chase"), &targets()).unwrap();
+        assert_eq!(command, Command::SetBrightness { segment: None, channel: None, brightness: 50 });
+    }
+
+    #[test]
+    fn brightness_alone_turns_on_the_default_effect() {
+        let command = animation(parse_command(r#"{"brightness":255}"#, &AnimationCommand::new("off"), &targets()).unwrap());
+        assert_eq!(command.animation, DEFAULT_EFFECT);
+        assert_eq!(command.params.brightness, 100);
+    }
+
+    #[test]
+    fn colour_keeps_the_other_fields() {
+        let command = animation(parse_command(r#"{"state":"ON","color":{"r":255,"g":0,"b":16}}"#, &running("chase"), &targets()).unwrap());
+        assert_eq!(command.animation, "chase");
+        assert_eq!(command.params.color, Some((255, 0, 16)));
+        assert_eq!(command.params.brightness, 40);
+        assert_eq!(command.params.speed, 2.0);
+
+        let command = animation(parse_command(r#"{"rgb_color":[0,255,0],"brightness":255}"#, &running("chase"), &targets()).unwrap());
+        assert_eq!(command.params.color, Some((0, 255, 0)));
+        assert_eq!(command.params.brightness, 100);
+    }
+
+    #[test]
+    fn effect_keeps_the_other_fields() {
+        let command = animation(parse_command(r#"{"effect":"rainbow"}"#, &running("chase"), &targets()).unwrap());
+        assert_eq!(command.animation, "rainbow");
+        assert_eq!(command.params, running("chase").params);
+        assert_eq!((command.segment, command.channel), (None, None));
+        assert_eq!(command.transition, TransitionKind::Fade);
+
+        assert!(parse_command(r#"{"effect":"nope"}"#, &running("chase"), &targets()).is_err());
+    }
+
+    #[test]
+    fn off_keeps_the_parameters() {
+        let command = animation(parse_command(r#"{"state":"OFF","transition":2}"#, &running("chase"), &targets()).unwrap());
+        assert_eq!(command.animation, "off");
+        assert_eq!(command.params, running("chase").params);
+        assert_eq!(command.transition, TransitionKind::Crossfade);
+        assert_eq!(command.transition_duration, 2.0);
+
+        assert!(parse_command(r#"{"state":"DIM"}"#, &running("chase"), &targets()).is_err());
+        assert!(parse_command(r#"{"transition":-1}"#, &running("chase"), &targets()).is_err());
+    }
+
+    #[test]
+    fn transitions_are_bounded() {
+        let command = animation(parse_command(r#"{"effect":"chase","transition":2.5}"#, &running("rainbow"), &targets()).unwrap());
+        assert_eq!((command.transition, command.transition_duration), (TransitionKind::Crossfade, 2.5));
+
+        let command = animation(parse_command(r#"{"effect":"chase","transition":1e300}"#, &running("rainbow"), &targets()).unwrap());
+        assert_eq!(command.transition_duration, MAX_TRANSITION_DURATION);
+        assert!(parse_command(r#"{"effect":"chase","transition":-1}"#, &running("rainbow"), &targets()).is_err());
+    }
+
+    #[test]
+    fn base_command_reads_the_first_segment() {
+        let state = json!({
+            "animation": "chase",
+            "stopping": false,
+            "brightness": 40,
+            "color": [1, 2, 3],
+            "segments": [{"segment": "strip", "speed": 2.0}],
+        });
+        assert_eq!(base_command(&state), running("chase"));
+
+        let mut stopping = state.clone();
+        stopping["stopping"] = json!(true);
+        assert_eq!(base_command(&stopping).animation, "off");
+        assert_eq!(base_command(&Value::Null), AnimationCommand::new("off"));
+    }
+}
@@ -0,0 +1,389 @@
+use std::io::Read;
+use std::sync::{mpsc, Arc, RwLock};
+use std::time::Instant;
+
+use log::{info, warn};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+use tungstenite::handshake::derive_accept_key;
+
+use crate::command::{AnimationCommand, Command, Targets};
+use crate::preview::{FrameStream, PREVIEW_PAGE};
+use crate::state::SharedState;
+use crate::wled::{self, WledInfo};
+
+/// Largest body accepted in a request, in bytes
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+/// The frames streamed by the preview, and how
+pub struct Preview {
+    pub frames: FrameStream,
+    /// The channels and segments of the strip
+    pub layout: Value,
+    /// The most frames per second sent to a client
+    pub max_fps: u32,
+}
+
+/// The REST API of the controller. Commands received over HTTP are parsed like
+/// the MQTT ones, and sent to the animation loop on the same channel.
+///
+/// * `GET /animations` - The names of the available animations
+/// * `GET /state` - The state of the controller, as published on MQTT
+/// * `POST /animation` - Runs an animation, the body being an MQTT animation command
+/// * `POST /brightness` - Changes the brightness, e.g. `{"brightness": 40, "segment": "plate"}`
+/// * `POST /command` - Any payload accepted on the MQTT channel
+/// * `GET /scenes` - The names of the scenes
+/// * `POST /scene` - Recalls a scene, e.g. `{"scene": "evening"}`
+/// * `POST /scenes` - Saves what the segments run as a scene, e.g. `{"scene": "evening"}`
+///
+/// When the preview is enabled:
+///
+/// * `GET /preview` - A page drawing the strip live
+/// * `GET /frames?fps=<n>` - A WebSocket streaming the rendered frames
+///
+/// When the WLED API is enabled:
+///
+/// * `GET /json` - The WLED state, info, effects and palettes
+/// * `GET /json/state`, `/json/info`, `/json/eff` and `/json/pal` - Each of them
+/// * `POST /json` or `/json/state` - Changes the WLED state
+pub struct HttpApi {
+    commands: mpsc::Sender<Command>,
+    targets: Arc<RwLock<Targets>>,
+    state: SharedState,
+    started_at: Instant,
+    preview: Option<Preview>,
+    wled: Option<WledInfo>,
+}
+
+impl HttpApi {
+    pub fn new(commands: mpsc::Sender<Command>, targets: Arc<RwLock<Targets>>, state: SharedState, started_at: Instant, preview: Option<Preview>, wled: Option<WledInfo>) -> HttpApi {
+        HttpApi {
+            commands,
+            targets,
+            state,
+            started_at,
+            preview,
+            wled,
+        }
+    }
+
+    /// Serves the requests until the server fails
+    ///
+    /// # Arguments
+    ///
+    /// * `bind` - The address and port to listen on
+    pub fn serve(&self, bind: &str) {
+        let server = match Server::http(bind) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Unable to start the HTTP server on {}: {}", bind, e);
+                return;
+            }
+        };
+        info!("HTTP API listening on {}", bind);
+
+        for request in server.incoming_requests() {
+            self.handle(request);
+        }
+    }
+
+    fn targets(&self) -> Targets {
+        match self.targets.read() {
+            Ok(t) => t.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    fn handle(&self, mut request: Request) {
+        let path = request.url().split('?').next().unwrap_or("").trim_end_matches('/').to_string();
+        if let (Method::Get, "/preview" | "/frames", Some(preview)) = (request.method(), path.as_str(), &self.preview) {
+            if path == "/preview" {
+                let content_type = Header::from_bytes(&b"Content-Type"[..], &b"text/html; charset=utf-8"[..]).unwrap();
+                if let Err(e) = request.respond(Response::from_string(PREVIEW_PAGE).with_header(content_type)) {
+                    warn!("Unable to send the HTTP response: {}", e);
+                }
+            } else {
+                stream_frames(request, preview);
+            }
+            return;
+        }
+
+        // Read after the upgrades, whose body is the rest of the connection
+        let (status, payload) = match read_body(request.as_reader()) {
+            Ok(body) => self.route(request.method(), &path, &body),
+            Err(error) => error,
+        };
+
+        respond(request, status, payload);
+    }
+
+    /// Answers a request to the REST or the WLED API
+    ///
+    /// # Returns
+    ///
+    /// * `(u16, Value)` - The HTTP status and the body of the response
+    fn route(&self, method: &Method, path: &str, body: &str) -> (u16, Value) {
+        match (method, path) {
+            (Method::Get, "/animations") => {
+                let mut animations = self.targets().animations;
+                animations.sort();
+                (200, json!(animations))
+            },
+            (Method::Get, "/state") => (200, self.state.get(&self.started_at)),
+            (Method::Post, "/animation") => {
+                let parsed = AnimationCommand::parse(body, &self.targets()).map(Command::SetAnimation);
+                self.send(parsed, body)
+            },
+            (Method::Post, "/brightness") => {
+                let parsed = self.parse_control("brightness", body);
+                self.send(parsed, body)
+            },
+            (Method::Get, "/scenes") => (200, json!(self.targets().scenes)),
+            (Method::Post, "/scene") => {
+                let parsed = self.parse_control("scene", body);
+                self.send(parsed, body)
+            },
+            (Method::Post, "/scenes") => {
+                let parsed = self.parse_control("save_scene", body);
+                self.send(parsed, body)
+            },
+            (Method::Post, "/command") => {
+                let parsed = Command::parse(body, &self.targets());
+                self.send(parsed, body)
+            },
+            (method, "/json" | "/json/state" | "/json/info" | "/json/eff" | "/json/pal") if self.wled.is_some() => {
+                self.wled(method, path, body)
+            },
+            (_, "/animations" | "/state" | "/animation" | "/brightness" | "/command" | "/scene" | "/scenes") => {
+                (405, json!({"error": format!("method {} not allowed", method)}))
+            },
+            _ => (404, json!({"error": format!("no such endpoint `{}`", path)})),
+        }
+    }
+
+    /// Parses a JSON object body as the fields of a control command, e.g. `{"brightness": 40}`
+    fn parse_control(&self, command: &str, body: &str) -> Result<Command, String> {
+        match serde_json::from_str::<Value>(body) {
+            Ok(Value::Object(mut fields)) => {
+                fields.insert("command".to_string(), json!(command));
+                Command::parse(&Value::Object(fields).to_string(), &self.targets())
+            },
+            Ok(_) => Err("the body must be a JSON object".to_string()),
+            Err(e) => Err(format!("invalid JSON body: {}", e)),
+        }
+    }
+
+    /// Sends a parsed command to the animation loop
+    ///
+    /// # Returns
+    ///
+    /// * `(u16, Value)` - The HTTP status and the body of the response
+    fn send(&self, parsed: Result<Command, String>, body: &str) -> (u16, Value) {
+        match parsed {
+            Ok(command) => match self.commands.send(command) {
+                Ok(_) => (202, json!({"status": "accepted"})),
+                Err(_) => (503, json!({"error": "the animation loop is not running"})),
+            },
+            Err(e) => {
+                warn!("Rejected command `{}`: {}", body, e);
+                (400, json!({"error": e}))
+            },
+        }
+    }
+
+    /// Answers a request to the WLED API
+    ///
+    /// # Returns
+    ///
+    /// * `(u16, Value)` - The HTTP status and the body of the response
+    fn wled(&self, method: &Method, path: &str, body: &str) -> (u16, Value) {
+        let info = match &self.wled {
+            Some(i) => i,
+            None => return (404, json!({"error": format!("no such endpoint `{}`", path)})),
+        };
+        let state = self.state.get(&self.started_at);
+        let targets = self.targets();
+
+        match (method, path) {
+            (Method::Get, "/json") => (200, json!({
+                "state": wled::state(&state, &targets, info),
+                "info": wled::info(&state, &targets, info),
+                "effects": wled::effects(&targets),
+                "palettes": wled::palettes(),
+            })),
+            (Method::Get, "/json/state") => (200, wled::state(&state, &targets, info)),
+            (Method::Get, "/json/info") => (200, wled::info(&state, &targets, info)),
+            (Method::Get, "/json/eff") => (200, json!(wled::effects(&targets))),
+            (Method::Get, "/json/pal") => (200, wled::palettes()),
+            (Method::Post, "/json" | "/json/state") => match wled::parse_state(body, &state, &targets) {
+                Ok(commands) => {
+                    if commands.into_iter().any(|c| self.commands.send(c).is_err()) {
+                        return (503, json!({"error": "the animation loop is not running"}));
+                    }
+                    (200, json!({"success": true}))
+                },
+                Err(e) => {
+                    warn!("Rejected WLED state `{}`: {}", body, e);
+                    (400, json!({"error": e}))
+                },
+            },
+            _ => (405, json!({"error": format!("method {} not allowed", method)})),
+        }
+    }
+}
+
+/// Upgrades the request to a WebSocket streaming the rendered frames. The
+/// client can ask for fewer frames per second than the maximum with `?fps=<n>`.
+fn stream_frames(request: Request, preview: &Preview) {
+    let key = request.headers().iter()
+        .find(|h| h.field.equiv("Sec-WebSocket-Key"))
+        .map(|h| h.value.to_string());
+    let key = match key {
+        Some(k) => k,
+        None => {
+            respond(request, 400, json!({"error": "expected a WebSocket upgrade request"}));
+            return;
+        }
+    };
+
+    let fps = request.url().split_once("?fps=")
+        .and_then(|(_, fps)| fps.parse::<u32>().ok())
+        .unwrap_or(preview.max_fps)
+        .clamp(1, preview.max_fps);
+
+    let response = Response::empty(StatusCode(101))
+        .with_header(Header::from_bytes(&b"Upgrade"[..], &b"websocket"[..]).unwrap())
+        .with_header(Header::from_bytes(&b"Connection"[..], &b"Upgrade"[..]).unwrap())
+        .with_header(Header::from_bytes(&b"Sec-WebSocket-Accept"[..], derive_accept_key(key.as_bytes()).as_bytes()).unwrap());
+    let stream = request.upgrade("websocket", response);
+
+    if let Err(e) = preview.frames.serve(stream, preview.layout.clone(), fps) {
+        warn!("Refused to stream frames: {}", e);
+    }
+}
+
+/// Reads the body of a request, up to `MAX_BODY_SIZE`
+///
+/// # Returns
+///
+/// * `Result<String, (u16, Value)>` - The body, or the HTTP status and the body of the error response
+fn read_body(reader: impl Read) -> Result<String, (u16, Value)> {
+    let mut body = String::new();
+    if let Err(e) = reader.take(MAX_BODY_SIZE + 1).read_to_string(&mut body) {
+        return Err((400, json!({"error": format!("unable to read the body: {}", e)})));
+    }
+    if body.len() as u64 > MAX_BODY_SIZE {
+        return Err((413, json!({"error": format!("the body is larger than {} bytes", MAX_BODY_SIZE)})));
+    }
+
+    Ok(body)
+}
+
+/// Sends a JSON response to the request
+fn respond(request: Request, status: u16, payload: Value) {
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    let response = Response::from_string(payload.to_string())
+        .with_status_code(status)
+        .with_header(content_type);
+
+    if let Err(e) = request.respond(response) {
+        warn!("Unable to send the HTTP response: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::config::Segment;
+    use crate::state::ControllerState;
+
+    fn api() -> (HttpApi, mpsc::Receiver<Command>) {
+        let targets = Targets {
+            animations: ["rainbow", "off", "chase"].map(String::from).to_vec(),
+            segments: vec![Segment { name: "desk".to_string(), channel: 0, start: 0, length: 10, reversed: false }],
+            scenes: vec!["evening".to_string()],
+        };
+        let (commands, received) = mpsc::channel();
+        let api = HttpApi::new(commands, Arc::new(RwLock::new(targets)), SharedState::default(), Instant::now(), None, None);
+        (api, received)
+    }
+
+    #[test]
+    fn lists_the_animations_and_scenes() {
+        let (api, _) = api();
+
+        assert_eq!(api.route(&Method::Get, "/animations", ""), (200, json!(["chase", "off", "rainbow"])));
+        assert_eq!(api.route(&Method::Get, "/scenes", ""), (200, json!(["evening"])));
+    }
+
+    #[test]
+    fn returns_the_state() {
+        let (api, _) = api();
+        api.state.update(&ControllerState {
+            animation: "chase",
+            stopping: false,
+            brightness: 40,
+            color: None,
+            uptime: 0,
+            dropped_frames: 3,
+            realtime: None,
+            segments: vec![],
+        });
+
+        let (status, state) = api.route(&Method::Get, "/state", "");
+        assert_eq!(status, 200);
+        assert_eq!((state["animation"].as_str(), state["brightness"].as_u64(), state["dropped_frames"].as_u64()), (Some("chase"), Some(40), Some(3)));
+    }
+
+    #[test]
+    fn sends_the_commands_to_the_animation_loop() {
+        let (api, received) = api();
+
+        assert_eq!(api.route(&Method::Post, "/animation", r#"{"animation":"chase","speed":2}"#).0, 202);
+        let mut expected = AnimationCommand::new("chase");
+        expected.params.speed = 2.0;
+        assert_eq!(received.try_recv(), Ok(Command::SetAnimation(expected)));
+
+        assert_eq!(api.route(&Method::Post, "/brightness", r#"{"brightness":40,"segment":"desk"}"#).0, 202);
+        assert_eq!(received.try_recv(), Ok(Command::SetBrightness { segment: Some("desk".to_string()), channel: None, brightness: 40 }));
+
+        assert_eq!(api.route(&Method::Post, "/scene", r#"{"scene":"evening"}"#).0, 202);
+        assert_eq!(received.try_recv(), Ok(Command::Scene("evening".to_string())));
+
+        assert_eq!(api.route(&Method::Post, "/command", "stop").0, 202);
+        assert_eq!(received.try_recv(), Ok(Command::Stop));
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        let (api, received) = api();
+
+        assert_eq!(api.route(&Method::Post, "/animation", r#"{"animation":"chase""#).0, 400);
+        assert_eq!(api.route(&Method::Post, "/animation", r#"{"animation":"sparkles"}"#).0, 400);
+        assert_eq!(api.route(&Method::Post, "/brightness", "not json").0, 400);
+        assert_eq!(api.route(&Method::Post, "/brightness", "[40]").0, 400);
+        assert_eq!(api.route(&Method::Post, "/brightness", r#"{"brightness":140}"#).0, 400);
+        assert_eq!(api.route(&Method::Get, "/animation", "").0, 405);
+        assert_eq!(api.route(&Method::Get, "/json", "").0, 404);
+        assert!(received.try_recv().is_err());
+    }
+
+    #[test]
+    fn reports_the_stopped_animation_loop() {
+        let (api, received) = api();
+        drop(received);
+
+        assert_eq!(api.route(&Method::Post, "/command", "stop").0, 503);
+    }
+
+    #[test]
+    fn bounds_the_body() {
+        let body = "x".repeat(MAX_BODY_SIZE as usize);
+
+        assert_eq!(read_body(Cursor::new(body.clone())), Ok(body.clone()));
+        assert_eq!(read_body(Cursor::new(body + "x")).unwrap_err().0, 413);
+        assert_eq!(read_body(Cursor::new(vec![0xff, 0xfe])).unwrap_err().0, 400);
+    }
+}
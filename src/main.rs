@@ -2,10 +2,28 @@ use colog;
 use clap::Parser;
 
 mod animations;
+mod command;
+mod compositor;
 mod config;
+mod control;
+mod dmx;
+mod homeassistant;
+mod http;
 mod app;
 mod args;
+mod preview;
+mod persist;
+mod printer;
+mod realtime;
+mod scene;
+mod schedule;
+mod scheduler;
+mod sinks;
+mod state;
+mod sun;
+mod transition;
 mod utils;
+mod wled;
 
 use app::App;
 use config::Config;
@@ -18,10 +36,21 @@ fn main(){
     if args.dump_default_config {
         Config::default().dump();
     } else if args.dump_config {
-        Config::from_file(&args.config_file).dump();
+        Config::load(&args.config_file, &args.overrides).dump_with_sources();
     } else {
-        let mut app = App::new();
-        app.start_mqtt_listener();
-        app.run();
+        let mut app = App::new(Config::load(&args.config_file, &args.overrides));
+        // The simulation runs alongside the controller, so it must not take its ports nor its commands
+        let status = if args.simulate {
+            app.simulate()
+        } else {
+            app.start_mqtt_listener();
+            app.start_http_server();
+            app.start_control_socket();
+            app.start_dmx_receiver();
+            app.start_wled_receiver();
+            app.start_printer_poller();
+            app.run()
+        };
+        std::process::exit(status);
     }
 }
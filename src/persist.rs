@@ -0,0 +1,144 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::utils::write_atomically;
+
+/// Delay between a change and its write, the changes made meanwhile being written together
+const SAVE_DELAY: Duration = Duration::from_secs(5);
+
+/// The content of the state file
+#[derive(Default, PartialEq, Serialize, Deserialize)]
+struct SavedState {
+    /// The payloads reproducing what the segments ran
+    commands: Vec<Value>,
+}
+
+/// The last state of the segments, written to a file to be restored on the
+/// next start. Writes are delayed, so that a burst of changes is written once.
+pub struct StateFile {
+    path: PathBuf,
+    /// Whether the state is written, or only kept in memory
+    persistent: bool,
+    /// What was last written, or read on startup
+    saved: SavedState,
+    /// When the pending changes are due to be written, if any
+    due: Option<Instant>,
+}
+
+impl StateFile {
+    /// Reads the state saved by the previous run, if any
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file the state is stored in, which may not exist yet
+    pub fn load(path: &Path) -> StateFile {
+        let saved = match fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content).unwrap_or_else(|e| {
+                warn!("Ignoring the saved state of {}: {}", path.display(), e);
+                SavedState::default()
+            }),
+            Err(_) => SavedState::default(),
+        };
+
+        StateFile {
+            path: path.to_path_buf(),
+            persistent: true,
+            saved,
+            due: None,
+        }
+    }
+
+    /// Keeps the state in memory only, e.g. for a simulation that must not
+    /// replace the state of the controller
+    pub fn set_persistent(&mut self, persistent: bool) {
+        self.persistent = persistent;
+    }
+
+    /// Returns the payloads saved by the previous run
+    pub fn commands(&self) -> &[Value] {
+        &self.saved.commands
+    }
+
+    /// Records that the state changed, to be written once the delay is over
+    pub fn touch(&mut self, now: Instant) {
+        self.due.get_or_insert(now + SAVE_DELAY);
+    }
+
+    /// Checks whether changes are waiting to be written, at the given time or at all if none
+    pub fn is_due(&self, now: Option<Instant>) -> bool {
+        self.due.is_some_and(|due| now.is_none_or(|now| due <= now))
+    }
+
+    /// Writes the state if it changed since it was last written. The file is
+    /// replaced atomically, so that a power loss never leaves it half written,
+    /// and its directory is created if needed.
+    ///
+    /// # Arguments
+    ///
+    /// * `commands` - The payloads reproducing what the segments run
+    pub fn save(&mut self, commands: Vec<Value>) {
+        self.due = None;
+        let state = SavedState { commands };
+        if state == self.saved || !self.persistent {
+            return;
+        }
+
+        let written = toml::to_string(&state)
+            .map_err(|e| e.to_string())
+            .and_then(|content| {
+                if let Some(directory) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                    fs::create_dir_all(directory).map_err(|e| e.to_string())?;
+                }
+                write_atomically(&self.path, &content).map_err(|e| e.to_string())
+            });
+        match written {
+            Ok(_) => self.saved = state,
+            Err(e) => warn!("Unable to save the state to {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn saved_state_is_restored() {
+        let path = env::temp_dir().join(format!("minileds-state-{}", std::process::id())).join("state.toml");
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+        let commands = vec![
+            json!({"animation": "rainbow", "segment": "desk", "brightness": 40}),
+            json!({"animation": "chase", "layer": 1, "color": "#ff8000"}),
+        ];
+
+        let mut state = StateFile::load(&path);
+        assert!(state.commands().is_empty());
+        state.touch(Instant::now());
+        assert!(state.is_due(None));
+        state.save(commands.clone());
+        assert!(!state.is_due(None));
+
+        assert_eq!(StateFile::load(&path).commands(), commands);
+        assert!(!path.with_extension("tmp").exists());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn simulated_state_is_not_written() {
+        let path = env::temp_dir().join(format!("minileds-simulated-state-{}.toml", std::process::id()));
+        let mut state = StateFile::load(&path);
+        state.set_persistent(false);
+        state.save(vec![json!("rainbow")]);
+
+        assert!(!path.exists());
+    }
+}
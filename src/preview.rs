@@ -0,0 +1,184 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::info;
+use serde_json::Value;
+use tiny_http::ReadWrite;
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+
+use crate::sinks::LedSink;
+
+/// The page drawing the strip from the frame stream, served by the HTTP API
+pub const PREVIEW_PAGE: &str = include_str!("../resources/preview.html");
+
+/// Most clients streaming frames at the same time, each one using a thread
+const MAX_CLIENTS: usize = 8;
+
+/// How long a client waits for a frame before checking that it is still connected
+const FRAME_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The last rendered frame, as consecutive (red, green, blue) bytes for every LED of every channel
+#[derive(Default)]
+struct Frame {
+    sequence: u64,
+    leds: Vec<u8>,
+}
+
+/// Shares the rendered frames with the WebSocket clients. The animation loop
+/// publishes every frame, and each client sends the last one at its own pace.
+#[derive(Clone, Default)]
+pub struct FrameStream {
+    frame: Arc<(Mutex<Frame>, Condvar)>,
+    clients: Arc<AtomicUsize>,
+}
+
+impl FrameStream {
+    /// Copies the LEDs of the sink into the shared frame, if anyone is watching
+    ///
+    /// # Arguments
+    ///
+    /// * `sink` - The sink holding the frame about to be rendered
+    /// * `channels` - The number of channels of the sink
+    pub fn publish(&self, sink: &dyn LedSink, channels: usize) {
+        if self.clients.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+
+        let (frame, updated) = &*self.frame;
+        let mut frame = match frame.lock() {
+            Ok(f) => f,
+            Err(e) => e.into_inner(),
+        };
+        frame.leds.clear();
+        for channel in 0..channels {
+            for led in sink.leds(channel) {
+                // The white LED lights the three colours up
+                frame.leds.extend([led[2], led[1], led[0]].map(|c| c.saturating_add(led[3])));
+            }
+        }
+        frame.sequence += 1;
+        updated.notify_all();
+    }
+
+    /// Waits for a frame newer than the given one
+    ///
+    /// # Returns
+    ///
+    /// * `Option<(u64, Vec<u8>)>` - The sequence number and the LEDs of the frame, none on timeout
+    fn next(&self, after: u64, timeout: Duration) -> Option<(u64, Vec<u8>)> {
+        let (frame, updated) = &*self.frame;
+        let frame = match frame.lock() {
+            Ok(f) => f,
+            Err(e) => e.into_inner(),
+        };
+        let (frame, _) = match updated.wait_timeout_while(frame, timeout, |f| f.sequence <= after) {
+            Ok(r) => r,
+            Err(e) => e.into_inner(),
+        };
+
+        if frame.sequence > after {
+            Some((frame.sequence, frame.leds.clone()))
+        } else {
+            None
+        }
+    }
+
+    /// Streams the frames to a client, in its own thread
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The connection of the client, once upgraded to WebSocket
+    /// * `layout` - The channels and segments of the strip, sent before the first frame
+    /// * `fps` - The most frames per second sent to the client
+    pub fn serve(&self, stream: Box<dyn ReadWrite + Send>, layout: Value, fps: u32) -> Result<(), String> {
+        if self.clients.fetch_add(1, Ordering::Relaxed) >= MAX_CLIENTS {
+            self.clients.fetch_sub(1, Ordering::Relaxed);
+            return Err(format!("already streaming to {} clients", MAX_CLIENTS));
+        }
+
+        let frames = self.clone();
+        thread::spawn(move || {
+            info!("Streaming frames at up to {} fps", fps);
+            let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+            let period = Duration::from_secs(1) / fps.max(1);
+            let mut sequence = 0;
+            let mut result = socket.send(Message::Text(layout.to_string()));
+
+            while result.is_ok() {
+                let sent_at = Instant::now();
+                result = match frames.next(sequence, FRAME_TIMEOUT) {
+                    Some((s, leds)) => {
+                        sequence = s;
+                        socket.send(Message::Binary(leds))
+                    },
+                    None => socket.send(Message::Ping(vec![])),
+                };
+                thread::sleep(period.saturating_sub(sent_at.elapsed()));
+            }
+
+            info!("Stopped streaming frames: {}", result.unwrap_err());
+            frames.clients.fetch_sub(1, Ordering::Relaxed);
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::net::{TcpListener, TcpStream};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::sinks::FrameBuffer;
+
+    /// A frame of two LEDs, the first one with some white
+    fn sink() -> FrameBuffer {
+        let mut sink = FrameBuffer::new(&[2]);
+        sink.leds_mut(0)[0] = [10, 20, 250, 10];
+        sink
+    }
+
+    #[test]
+    fn frames_are_only_published_while_watched() {
+        let frames = FrameStream::default();
+        frames.publish(&sink(), 1);
+        assert_eq!(frames.next(0, Duration::ZERO), None);
+
+        frames.clients.fetch_add(1, Ordering::Relaxed);
+        frames.publish(&sink(), 1);
+        assert_eq!(frames.next(0, Duration::ZERO), Some((1, vec![255, 30, 20, 0, 0, 0])));
+        assert_eq!(frames.next(1, Duration::from_millis(10)), None);
+    }
+
+    #[test]
+    fn streams_the_layout_then_the_frames() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let frames = FrameStream::default();
+        let layout = json!({"channels": [2]});
+        frames.serve(Box::new(server), layout.clone(), 50).unwrap();
+        frames.publish(&sink(), 1);
+
+        let mut socket = WebSocket::from_raw_socket(client, Role::Client, None);
+        assert_eq!(socket.read().unwrap(), Message::Text(layout.to_string()));
+        assert_eq!(socket.read().unwrap(), Message::Binary(vec![255, 30, 20, 0, 0, 0]));
+    }
+
+    #[test]
+    fn refuses_clients_over_the_limit() {
+        let frames = FrameStream::default();
+        frames.clients.store(MAX_CLIENTS, Ordering::Relaxed);
+
+        assert!(frames.serve(Box::new(Cursor::new(vec![])), json!({}), 50).is_err());
+        assert_eq!(frames.clients.load(Ordering::Relaxed), MAX_CLIENTS);
+    }
+}
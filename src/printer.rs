@@ -0,0 +1,454 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+use serde_json::Value;
+
+use crate::config::PrinterConfig;
+use crate::utils::parse_hex_color;
+
+/// A heater is still heating while it is further than this from its target, in °C
+const HEATING_MARGIN: f64 = 2.0;
+/// Longest time a request to the printer may take
+const POLL_TIMEOUT: Duration = Duration::from_secs(5);
+/// Bounds of the delay between two polls
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// The phases of a print, each shown in its own colour
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PrintPhase {
+    Idle,
+    Heating,
+    Printing,
+    Paused,
+    Done,
+    Error,
+}
+
+/// The colour of each phase of a print
+#[derive(Clone, Copy)]
+pub struct PhaseColors {
+    pub heating: (u8, u8, u8),
+    pub printing: (u8, u8, u8),
+    pub paused: (u8, u8, u8),
+    pub done: (u8, u8, u8),
+    pub error: (u8, u8, u8),
+}
+
+impl PhaseColors {
+    /// Reads the colours of the configuration, the invalid ones falling back to their default
+    pub fn new(config: &PrinterConfig) -> PhaseColors {
+        let defaults = PrinterConfig::default();
+        let parse = |name: &str, color: &str, default: &str| {
+            parse_hex_color(color).unwrap_or_else(|| {
+                warn!("Invalid {} colour `{}`, using {}", name, color, default);
+                parse_hex_color(default).unwrap_or((255, 255, 255))
+            })
+        };
+
+        PhaseColors {
+            heating: parse("heating", &config.heating_color, &defaults.heating_color),
+            printing: parse("printing", &config.printing_color, &defaults.printing_color),
+            paused: parse("paused", &config.paused_color, &defaults.paused_color),
+            done: parse("done", &config.done_color, &defaults.done_color),
+            error: parse("error", &config.error_color, &defaults.error_color),
+        }
+    }
+
+    /// Returns the colour of a phase, none when idle
+    pub fn of(&self, phase: PrintPhase) -> Option<(u8, u8, u8)> {
+        match phase {
+            PrintPhase::Idle => None,
+            PrintPhase::Heating => Some(self.heating),
+            PrintPhase::Printing => Some(self.printing),
+            PrintPhase::Paused => Some(self.paused),
+            PrintPhase::Done => Some(self.done),
+            PrintPhase::Error => Some(self.error),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Status {
+    /// The phase reported by the printer, which does not tell apart heating from printing
+    phase: Option<PrintPhase>,
+    /// Progress of the job, in percent
+    progress: f64,
+    /// The actual and target temperatures of each heater
+    heaters: BTreeMap<String, (f64, f64)>,
+}
+
+/// The progress of the print, updated from the printer and shown by the
+/// `progress` animation
+#[derive(Clone, Default)]
+pub struct PrintProgress {
+    status: Arc<RwLock<Status>>,
+}
+
+impl PrintProgress {
+    /// Returns the phase of the print and its progress in percent. While
+    /// heating, the progress is the one of the coldest heater.
+    pub fn get(&self) -> (PrintPhase, f64) {
+        let status = match self.status.read() {
+            Ok(s) => s,
+            Err(e) => e.into_inner(),
+        };
+
+        let phase = status.phase.unwrap_or(PrintPhase::Idle);
+        if phase != PrintPhase::Printing {
+            return (phase, status.progress);
+        }
+
+        let heating = status.heaters.values()
+            .filter(|(actual, target)| *target > 0.0 && *actual < target - HEATING_MARGIN)
+            .map(|(actual, target)| (actual / target * 100.0).max(0.0))
+            .reduce(f64::min);
+        match heating {
+            Some(progress) if status.progress <= 0.0 => (PrintPhase::Heating, progress),
+            _ => (PrintPhase::Printing, status.progress),
+        }
+    }
+
+    /// Updates the phase of the print, and its progress in percent if known
+    pub fn set_job(&self, phase: PrintPhase, progress: Option<f64>) {
+        let mut status = match self.status.write() {
+            Ok(s) => s,
+            Err(e) => e.into_inner(),
+        };
+
+        if status.phase != Some(phase) {
+            info!("Print is now {:?}", phase);
+        }
+        status.phase = Some(phase);
+        if let Some(progress) = progress {
+            status.progress = progress.clamp(0.0, 100.0);
+        }
+    }
+
+    /// Updates the progress of the print in percent, keeping its phase
+    fn set_progress(&self, progress: f64) {
+        let mut status = match self.status.write() {
+            Ok(s) => s,
+            Err(e) => e.into_inner(),
+        };
+        status.progress = progress.clamp(0.0, 100.0);
+    }
+
+    /// Updates the actual and target temperatures of a heater
+    pub fn set_heater(&self, heater: &str, actual: f64, target: f64) {
+        let mut status = match self.status.write() {
+            Ok(s) => s,
+            Err(e) => e.into_inner(),
+        };
+        status.heaters.insert(heater.to_string(), (actual, target));
+    }
+
+    /// Updates the progress from a message of the OctoPrint MQTT plugin
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic of the message, without the base topic of the plugin
+    /// * `payload` - The JSON payload of the message
+    pub fn update_octoprint(&self, topic: &str, payload: &str) -> Result<(), String> {
+        let payload: Value = serde_json::from_str(payload)
+            .map_err(|e| format!("invalid OctoPrint message on `{}`: {}", topic, e))?;
+
+        match topic.split_once('/') {
+            Some(("progress", "printing")) => {
+                let progress = payload["progress"].as_f64()
+                    .ok_or(format!("no progress in `{}`", payload))?;
+                self.set_progress(progress);
+            },
+            Some(("event", event)) => match event {
+                "PrintStarted" => self.set_job(PrintPhase::Printing, Some(0.0)),
+                "PrintResumed" => self.set_job(PrintPhase::Printing, None),
+                "PrintPaused" => self.set_job(PrintPhase::Paused, None),
+                "PrintDone" => self.set_job(PrintPhase::Done, Some(100.0)),
+                "PrintFailed" | "Error" => self.set_job(PrintPhase::Error, None),
+                "PrintCancelled" | "Disconnected" => self.set_job(PrintPhase::Idle, Some(0.0)),
+                _ => {},
+            },
+            Some(("temperature", heater)) => {
+                if let (Some(actual), Some(target)) = (payload["actual"].as_f64(), payload["target"].as_f64()) {
+                    self.set_heater(heater, actual, target);
+                }
+            },
+            _ => {},
+        }
+
+        Ok(())
+    }
+}
+
+/// The HTTP APIs the progress can be polled from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PrinterApi {
+    Moonraker,
+    OctoPrint,
+}
+
+impl PrinterApi {
+    pub fn from_name(name: &str) -> Option<PrinterApi> {
+        match name {
+            "moonraker" => Some(PrinterApi::Moonraker),
+            "octoprint" => Some(PrinterApi::OctoPrint),
+            _ => None,
+        }
+    }
+}
+
+/// Polls the progress of the print from Moonraker or OctoPrint
+pub struct PrinterPoller {
+    api: PrinterApi,
+    url: String,
+    api_key: Option<String>,
+    interval: Duration,
+    progress: PrintProgress,
+}
+
+impl PrinterPoller {
+    /// Creates a poller, checking the interval of the configuration
+    ///
+    /// # Returns
+    ///
+    /// * `Result<PrinterPoller, String>` - The poller, or the reason the configuration is invalid
+    pub fn new(api: PrinterApi, config: &PrinterConfig, progress: PrintProgress) -> Result<PrinterPoller, String> {
+        let interval = Duration::try_from_secs_f64(config.poll_interval)
+            .ok()
+            .filter(|i| (MIN_POLL_INTERVAL..=MAX_POLL_INTERVAL).contains(i))
+            .ok_or(format!("poll_interval {} must be a number of seconds between {} and {}",
+                           config.poll_interval, MIN_POLL_INTERVAL.as_secs_f64(), MAX_POLL_INTERVAL.as_secs()))?;
+
+        Ok(PrinterPoller {
+            api,
+            url: config.url.trim_end_matches('/').to_string(),
+            api_key: config.api_key.clone(),
+            interval,
+            progress,
+        })
+    }
+
+    /// Polls the printer forever, warning once when it stops answering
+    pub fn serve(&self) {
+        info!("Polling the print progress from {}", self.url);
+        let mut reachable = true;
+        loop {
+            match self.poll() {
+                Ok(_) if !reachable => {
+                    info!("Printer {} is answering again", self.url);
+                    reachable = true;
+                },
+                Ok(_) => {},
+                Err(e) if reachable => {
+                    warn!("Unable to poll the printer: {}", e);
+                    reachable = false;
+                },
+                Err(_) => {},
+            }
+            thread::sleep(self.interval);
+        }
+    }
+
+    /// Gets a JSON document from the API of the printer
+    fn get(&self, path: &str) -> Result<Value, String> {
+        let url = format!("{}{}", self.url, path);
+        let mut request = ureq::get(&url).timeout(POLL_TIMEOUT);
+        if let Some(key) = &self.api_key {
+            request = request.set("X-Api-Key", key);
+        }
+
+        request.call()
+            .map_err(|e| e.to_string())?
+            .into_json()
+            .map_err(|e| format!("invalid response from {}: {}", url, e))
+    }
+
+    fn poll(&self) -> Result<(), String> {
+        match self.api {
+            PrinterApi::Moonraker => self.poll_moonraker(),
+            PrinterApi::OctoPrint => self.poll_octoprint(),
+        }
+    }
+
+    fn poll_moonraker(&self) -> Result<(), String> {
+        let response = self.get("/printer/objects/query?print_stats&display_status&extruder&heater_bed")?;
+        let status = &response["result"]["status"];
+
+        let phase = match status["print_stats"]["state"].as_str() {
+            Some("printing") => PrintPhase::Printing,
+            Some("paused") => PrintPhase::Paused,
+            Some("complete") => PrintPhase::Done,
+            Some("error") => PrintPhase::Error,
+            Some(_) => PrintPhase::Idle,
+            None => return Err(format!("no print state in `{}`", response)),
+        };
+        let progress = status["display_status"]["progress"].as_f64().map(|p| p * 100.0);
+        self.progress.set_job(phase, progress);
+
+        for heater in ["extruder", "heater_bed"] {
+            if let (Some(actual), Some(target)) = (status[heater]["temperature"].as_f64(), status[heater]["target"].as_f64()) {
+                self.progress.set_heater(heater, actual, target);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn poll_octoprint(&self) -> Result<(), String> {
+        let job = self.get("/api/job")?;
+        let progress = job["progress"]["completion"].as_f64();
+
+        let phase = match job["state"].as_str() {
+            Some(state) if state.starts_with("Printing") => PrintPhase::Printing,
+            Some("Pausing" | "Paused") => PrintPhase::Paused,
+            Some("Finishing") => PrintPhase::Done,
+            Some("Operational") if progress.is_some_and(|p| p >= 100.0) => PrintPhase::Done,
+            Some(state) if state.contains("Error") || state.contains("error") => PrintPhase::Error,
+            Some(_) => PrintPhase::Idle,
+            None => return Err(format!("no job state in `{}`", job)),
+        };
+        self.progress.set_job(phase, progress);
+
+        // The temperatures are only available while connected to the printer
+        if let Ok(printer) = self.get("/api/printer?exclude=sd,state") {
+            if let Some(heaters) = printer["temperature"].as_object() {
+                for (heater, temperature) in heaters {
+                    if let (Some(actual), Some(target)) = (temperature["actual"].as_f64(), temperature["target"].as_f64()) {
+                        self.progress.set_heater(heater, actual, target);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tiny_http::{Header, Response, Server};
+
+    use super::*;
+
+    /// Serves the given JSON documents by path, checking the API key when given
+    fn mock(documents: Vec<(&'static str, Value)>, api_key: Option<&'static str>) -> String {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let authorized = api_key.is_none_or(|key| {
+                    request.headers().iter().any(|h| h.field.equiv("X-Api-Key") && h.value.as_str() == key)
+                });
+                let document = documents.iter().find(|(path, _)| request.url().starts_with(path));
+                let response = match document {
+                    Some((_, document)) if authorized => Response::from_string(document.to_string())
+                        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap()),
+                    Some(_) => Response::from_string("").with_status_code(403),
+                    None => Response::from_string("").with_status_code(404),
+                };
+                let _ = request.respond(response);
+            }
+        });
+        url
+    }
+
+    fn poller(api: PrinterApi, url: &str, api_key: Option<&str>) -> PrinterPoller {
+        let config = PrinterConfig { url: format!("{}/", url), api_key: api_key.map(String::from), ..PrinterConfig::default() };
+        PrinterPoller::new(api, &config, PrintProgress::default()).unwrap()
+    }
+
+    #[test]
+    fn follows_the_octoprint_messages() {
+        let progress = PrintProgress::default();
+        progress.update_octoprint("event/PrintStarted", r#"{"_event": "PrintStarted"}"#).unwrap();
+        assert_eq!(progress.get(), (PrintPhase::Printing, 0.0));
+
+        progress.update_octoprint("temperature/tool0", r#"{"actual": 50.0, "target": 200.0}"#).unwrap();
+        assert_eq!(progress.get(), (PrintPhase::Heating, 25.0));
+
+        progress.update_octoprint("progress/printing", r#"{"progress": 12}"#).unwrap();
+        assert_eq!(progress.get(), (PrintPhase::Printing, 12.0));
+
+        progress.update_octoprint("event/PrintPaused", "{}").unwrap();
+        assert_eq!(progress.get(), (PrintPhase::Paused, 12.0));
+        progress.update_octoprint("event/PrintDone", "{}").unwrap();
+        assert_eq!(progress.get(), (PrintPhase::Done, 100.0));
+        progress.update_octoprint("event/PrintCancelled", "{}").unwrap();
+        assert_eq!(progress.get(), (PrintPhase::Idle, 0.0));
+
+        assert!(progress.update_octoprint("progress/printing", "{}").is_err());
+        assert!(progress.update_octoprint("event/PrintDone", "not json").is_err());
+    }
+
+    #[test]
+    fn heating_lasts_until_the_print_progresses() {
+        let progress = PrintProgress::default();
+        progress.set_job(PrintPhase::Printing, Some(0.0));
+        progress.set_heater("extruder", 100.0, 200.0);
+        progress.set_heater("heater_bed", 30.0, 60.0);
+        progress.set_heater("chamber", 20.0, 0.0);
+        assert_eq!(progress.get(), (PrintPhase::Heating, 50.0));
+
+        // Heaters within the margin of their target are heated
+        progress.set_heater("extruder", 199.0, 200.0);
+        progress.set_heater("heater_bed", 59.0, 60.0);
+        assert_eq!(progress.get(), (PrintPhase::Printing, 0.0));
+
+        progress.set_heater("extruder", 20.0, 200.0);
+        progress.set_job(PrintPhase::Printing, Some(5.0));
+        assert_eq!(progress.get(), (PrintPhase::Printing, 5.0));
+    }
+
+    #[test]
+    fn polls_moonraker() {
+        let url = mock(vec![("/printer/objects/query", json!({"result": {"status": {
+            "print_stats": {"state": "printing"},
+            "display_status": {"progress": 0.42},
+            "extruder": {"temperature": 210.0, "target": 210.0},
+            "heater_bed": {"temperature": 60.0, "target": 60.0},
+        }}}))], None);
+        let poller = poller(PrinterApi::Moonraker, &url, None);
+
+        poller.poll().unwrap();
+        assert_eq!(poller.progress.get(), (PrintPhase::Printing, 42.0));
+    }
+
+    #[test]
+    fn polls_octoprint() {
+        let url = mock(vec![
+            ("/api/job", json!({"state": "Printing from SD", "progress": {"completion": 0.0}})),
+            ("/api/printer", json!({"temperature": {"tool0": {"actual": 150.0, "target": 200.0}, "bed": {"actual": 60.0, "target": 60.0}}})),
+        ], Some("secret"));
+
+        assert!(poller(PrinterApi::OctoPrint, &url, None).poll().is_err());
+
+        let authorized = poller(PrinterApi::OctoPrint, &url, Some("secret"));
+        authorized.poll().unwrap();
+        assert_eq!(authorized.progress.get(), (PrintPhase::Heating, 75.0));
+    }
+
+    #[test]
+    fn rejects_unexpected_responses() {
+        let url = mock(vec![("/printer/objects/query", json!({"result": {}}))], None);
+        assert!(poller(PrinterApi::Moonraker, &url, None).poll().is_err());
+        assert!(poller(PrinterApi::OctoPrint, &url, None).poll().is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_poll_intervals() {
+        let poller = |poll_interval: f64| {
+            let config = PrinterConfig { poll_interval, ..PrinterConfig::default() };
+            PrinterPoller::new(PrinterApi::Moonraker, &config, PrintProgress::default()).map(|p| p.interval)
+        };
+
+        assert_eq!(poller(2.0), Ok(Duration::from_secs(2)));
+        assert_eq!(poller(0.1), Ok(Duration::from_millis(100)));
+        for poll_interval in [0.0, -1.0, f64::NAN, f64::INFINITY, 1e30] {
+            assert!(poller(poll_interval).is_err(), "accepted {}", poll_interval);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::sinks::{LedSink, RawColor};
+
+struct Frame {
+    /// The LEDs of every channel
+    channels: Vec<Vec<RawColor>>,
+    /// Whether the source wrote each LED of every channel, the others showing the animations
+    written: Vec<Vec<bool>>,
+    /// Name of the protocol that sent the frame
+    source: &'static str,
+    expires_at: Option<Instant>,
+}
+
+/// Frames streamed by an external source, such as lighting software. While
+/// a source keeps sending frames, the LEDs it writes replace the animations.
+/// Once it stops for longer than its timeout, the animations are shown again.
+#[derive(Clone)]
+pub struct RealtimeFrame {
+    frame: Arc<Mutex<Frame>>,
+}
+
+impl RealtimeFrame {
+    /// Creates a frame for channels of the given lengths
+    pub fn new(lengths: &[i32]) -> RealtimeFrame {
+        RealtimeFrame {
+            frame: Arc::new(Mutex::new(Frame {
+                channels: lengths.iter().map(|l| vec![[0, 0, 0, 0]; (*l).max(0) as usize]).collect(),
+                written: lengths.iter().map(|l| vec![false; (*l).max(0) as usize]).collect(),
+                source: "",
+                expires_at: None,
+            })),
+        }
+    }
+
+    /// Writes LEDs received from a source, the LEDs out of the channel being ignored
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel the LEDs are on
+    /// * `start` - The index of the first LED written
+    /// * `leds` - The LEDs, in the driver's layout
+    /// * `source` - The name of the protocol that sent them
+    /// * `timeout` - How long the frame is shown without any new data
+    pub fn write(&self, channel: usize, start: usize, leds: &[RawColor], source: &'static str, timeout: Duration) {
+        let mut frame = match self.frame.lock() {
+            Ok(f) => f,
+            Err(e) => e.into_inner(),
+        };
+
+        // Another source, or the same one after a pause, starts without any LED written
+        let now = Instant::now();
+        if frame.source != source || frame.expires_at.is_none_or(|e| e <= now) {
+            frame.written.iter_mut().for_each(|c| c.fill(false));
+            frame.source = source;
+        }
+
+        let Frame { channels, written, .. } = &mut *frame;
+        if let (Some(target), Some(written)) = (channels.get_mut(channel), written.get_mut(channel)) {
+            for ((led, written), value) in target.iter_mut().zip(written.iter_mut()).skip(start).zip(leds) {
+                *led = *value;
+                *written = true;
+            }
+        }
+        frame.expires_at = Some(now + timeout);
+    }
+
+    /// Shows the animations again right away, if the frame comes from the given source
+    pub fn release(&self, source: &'static str) {
+        let mut frame = match self.frame.lock() {
+            Ok(f) => f,
+            Err(e) => e.into_inner(),
+        };
+        if frame.source == source {
+            frame.expires_at = None;
+        }
+    }
+
+    /// Copies the LEDs written by the source to the sink, if it is still sending
+    ///
+    /// # Returns
+    ///
+    /// * `Option<&'static str>` - The name of the source the frame comes from, none if it timed out
+    pub fn apply(&self, sink: &mut dyn LedSink, now: Instant) -> Option<&'static str> {
+        let frame = match self.frame.lock() {
+            Ok(f) => f,
+            Err(e) => e.into_inner(),
+        };
+        if frame.expires_at.is_none_or(|e| e <= now) {
+            return None;
+        }
+
+        for (channel, (leds, written)) in frame.channels.iter().zip(&frame.written).enumerate() {
+            for ((led, value), _) in sink.leds_mut(channel).iter_mut().zip(leds).zip(written).filter(|(_, w)| **w) {
+                *led = *value;
+            }
+        }
+
+        Some(frame.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sinks::FrameBuffer;
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+    const ANIMATION: RawColor = [1, 1, 1, 0];
+    const RED: RawColor = [0, 0, 255, 0];
+
+    fn animated() -> FrameBuffer {
+        let mut sink = FrameBuffer::new(&[6, 3]);
+        sink.leds_mut(0).fill(ANIMATION);
+        sink.leds_mut(1).fill(ANIMATION);
+        sink
+    }
+
+    #[test]
+    fn keeps_the_animations_where_nothing_was_written() {
+        let realtime = RealtimeFrame::new(&[6, 3]);
+        realtime.write(0, 2, &[RED, RED], "e131", TIMEOUT);
+        realtime.write(0, 5, &[RED, RED, RED], "e131", TIMEOUT);
+
+        let mut sink = animated();
+        assert_eq!(realtime.apply(&mut sink, Instant::now()), Some("e131"));
+        assert_eq!(sink.leds(0), &[ANIMATION, ANIMATION, RED, RED, ANIMATION, RED]);
+        assert_eq!(sink.leds(1), &[ANIMATION; 3]);
+    }
+
+    #[test]
+    fn shows_the_animations_once_timed_out() {
+        let realtime = RealtimeFrame::new(&[6, 3]);
+        realtime.write(1, 0, &[RED], "wled", Duration::from_secs(1));
+
+        let mut sink = animated();
+        assert_eq!(realtime.apply(&mut sink, Instant::now() + Duration::from_secs(2)), None);
+        assert_eq!(sink.leds(1), &[ANIMATION; 3]);
+
+        realtime.release("e131");
+        assert_eq!(realtime.apply(&mut sink, Instant::now()), Some("wled"));
+        realtime.release("wled");
+        assert_eq!(realtime.apply(&mut sink, Instant::now()), None);
+    }
+
+    #[test]
+    fn another_source_starts_from_the_animations() {
+        let realtime = RealtimeFrame::new(&[6, 3]);
+        realtime.write(0, 0, &[RED; 6], "e131", TIMEOUT);
+        realtime.write(1, 1, &[RED], "wled", TIMEOUT);
+
+        let mut sink = animated();
+        assert_eq!(realtime.apply(&mut sink, Instant::now()), Some("wled"));
+        assert_eq!(sink.leds(0), &[ANIMATION; 6]);
+        assert_eq!(sink.leds(1), &[ANIMATION, RED, ANIMATION]);
+    }
+}
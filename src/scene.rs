@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use serde_json::Value;
+
+use crate::command::{check_scene_name, Command, Targets};
+use crate::utils::write_atomically;
+
+/// The named scenes, each one being a list of payloads as accepted on the MQTT
+/// channel. The scenes saved at runtime are stored in their own file, and take
+/// precedence over the configured ones of the same name.
+pub struct Scenes {
+    configured: BTreeMap<String, Vec<Value>>,
+    saved: BTreeMap<String, Vec<Value>>,
+    /// The file the saved scenes are stored in
+    path: PathBuf,
+    /// Whether the saved scenes are written, or only kept in memory
+    persistent: bool,
+}
+
+impl Scenes {
+    /// Loads the saved scenes, the configured ones being given
+    ///
+    /// # Arguments
+    ///
+    /// * `configured` - The scenes of the configuration
+    /// * `path` - The file the saved scenes are stored in, which may not exist yet
+    pub fn load(configured: &BTreeMap<String, Vec<Value>>, path: &Path) -> Scenes {
+        let saved = match fs::read_to_string(path) {
+            Ok(content) => match toml::from_str(&content) {
+                Ok(scenes) => scenes,
+                Err(e) => {
+                    warn!("Ignoring the saved scenes of {}: {}", path.display(), e);
+                    BTreeMap::new()
+                }
+            },
+            Err(_) => BTreeMap::new(),
+        };
+
+        Scenes {
+            configured: configured.clone(),
+            saved,
+            path: path.to_path_buf(),
+            persistent: true,
+        }
+    }
+
+    /// Keeps the saved scenes in memory only, e.g. for a simulation that must
+    /// not replace the scenes of the controller
+    pub fn set_persistent(&mut self, persistent: bool) {
+        self.persistent = persistent;
+    }
+
+    /// Replaces the configured scenes, after the configuration is reloaded
+    pub fn set_configured(&mut self, configured: &BTreeMap<String, Vec<Value>>) {
+        self.configured = configured.clone();
+    }
+
+    /// Returns the names of the scenes that can be recalled, the ones shadowed
+    /// by an animation or a command being logged and left out
+    pub fn names(&self, targets: &Targets) -> Vec<String> {
+        let mut names: Vec<String> = self.configured.keys().chain(self.saved.keys()).cloned().collect();
+        names.sort();
+        names.dedup();
+        names.retain(|name| {
+            check_scene_name(name, targets)
+                .map_err(|e| warn!("Ignoring the scene: {}", e))
+                .is_ok()
+        });
+
+        names
+    }
+
+    /// Parses the commands of a scene
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the scene
+    /// * `targets` - The animations and segments the commands can refer to
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<Command>, String>` - The commands, or the reason the scene can not be recalled
+    pub fn get(&self, name: &str, targets: &Targets) -> Result<Vec<Command>, String> {
+        let payloads = self.saved.get(name).or(self.configured.get(name))
+            .ok_or(format!("unknown scene `{}`", name))?;
+
+        payloads.iter().map(|payload| {
+            let payload = match payload {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            match Command::parse(&payload, targets)? {
+                Command::Scene(_) | Command::SaveScene(_) | Command::Reload => {
+                    Err(format!("`{}` can not be part of a scene", payload))
+                },
+                command => Ok(command),
+            }
+        }).collect()
+    }
+
+    /// Saves a scene and writes all the saved scenes to their file. The file
+    /// is replaced atomically, so that it is never left half written.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the scene, replacing any scene of the same name
+    /// * `payloads` - The payloads of the commands of the scene
+    pub fn save(&mut self, name: &str, payloads: Vec<Value>) -> Result<(), String> {
+        let mut saved = self.saved.clone();
+        saved.insert(name.to_string(), payloads);
+
+        if self.persistent {
+            let content = toml::to_string(&saved).map_err(|e| format!("unable to serialize the scenes: {}", e))?;
+            write_atomically(&self.path, &content)
+                .map_err(|e| format!("unable to write {}: {}", self.path.display(), e))?;
+            info!("Saved scene {} to {}", name, self.path.display());
+        } else {
+            info!("Saved scene {} until exiting", name);
+        }
+        self.saved = saved;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::Segment;
+
+    fn targets() -> Targets {
+        Targets {
+            animations: ["off", "rainbow", "chase"].map(String::from).to_vec(),
+            segments: vec![Segment { name: "desk".to_string(), channel: 0, start: 0, length: 10, reversed: false }],
+            scenes: vec![],
+        }
+    }
+
+    #[test]
+    fn saved_scenes_are_restored() {
+        let path = env::temp_dir().join(format!("minileds-scenes-{}.toml", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let configured = BTreeMap::from([("evening".to_string(), vec![json!("chase")])]);
+        let payloads = vec![json!({"animation": "rainbow", "segment": "desk"}), json!("chase")];
+
+        Scenes::load(&configured, &path).save("evening", payloads.clone()).unwrap();
+
+        let scenes = Scenes::load(&configured, &path);
+        let expected = vec![
+            Command::parse(r#"{"animation":"rainbow","segment":"desk"}"#, &targets()).unwrap(),
+            Command::parse("chase", &targets()).unwrap(),
+        ];
+        assert_eq!(scenes.get("evening", &targets()), Ok(expected));
+        assert!(!path.with_extension("tmp").exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,605 @@
+use chrono::{Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Timelike, Weekday};
+use log::{error, info};
+
+use crate::command::{Command, Targets};
+use crate::config::ScheduleRule;
+use crate::sun::{sun_event, SunEvent};
+
+/// The source of the local time the schedule is evaluated at
+pub trait Clock {
+    fn now(&self) -> NaiveDateTime;
+    /// Converts a time in UTC, such as a sunset, to the local time
+    fn to_local(&self, utc: NaiveDateTime) -> NaiveDateTime;
+}
+
+/// The clock of the system, in its timezone
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> NaiveDateTime {
+        Local::now().naive_local()
+    }
+
+    fn to_local(&self, utc: NaiveDateTime) -> NaiveDateTime {
+        Local.from_utc_datetime(&utc).naive_local()
+    }
+}
+
+/// Names of the days of the week, as numbered by cron, sunday being both 0 and 7
+const WEEKDAYS: [&str; 8] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+
+/// A cron expression, `minute hour day-of-month month day-of-week`. Each
+/// field is `*` or a list of values, ranges and steps, e.g. `1-5` or `*/15`.
+struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Cron matches either day field when both are restricted
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// Parses a field of a cron expression into the bitmask of the values it matches
+///
+/// # Arguments
+///
+/// * `field` - The field, e.g. `1-5,*/15`
+/// * `min` - The smallest value of the field
+/// * `max` - The largest value of the field
+/// * `names` - The names of the values, from `min` on
+fn parse_cron_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    // A name ending a range is its last value, so that `mon-sun` is 1-7
+    let value = |v: &str, last: bool| -> Result<u32, String> {
+        let v = v.to_lowercase();
+        let position = if last { names.iter().rposition(|n| *n == v) } else { names.iter().position(|n| *n == v) };
+        position
+            .map(|i| i as u32 + min)
+            .or_else(|| v.parse().ok())
+            .filter(|v| (min..=max).contains(v))
+            .ok_or(format!("`{}` is not between {} and {}", v, min, max))
+    };
+
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| format!("invalid step `{}`", step))?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err("a step can not be 0".to_string());
+        }
+
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (value(first, false)?, value(last, true)?),
+            None if step > 1 => (value(range, false)?, max),
+            None => (value(range, false)?, value(range, false)?),
+        };
+        if last < first {
+            return Err(format!("range `{}` ends before it starts", range));
+        }
+        for v in (first..=last).step_by(step as usize) {
+            mask |= 1 << v;
+        }
+    }
+
+    Ok(mask)
+}
+
+impl Cron {
+    fn parse(expression: &str) -> Result<Cron, String> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("cron expression `{}` must have 5 fields", expression));
+        };
+
+        let mut weekdays_mask = parse_cron_field(weekdays, 0, 7, &WEEKDAYS)?;
+        // Both 0 and 7 are sunday
+        if weekdays_mask & (1 << 7) != 0 {
+            weekdays_mask |= 1;
+        }
+
+        Ok(Cron {
+            minutes: parse_cron_field(minutes, 0, 59, &[])?,
+            hours: parse_cron_field(hours, 0, 23, &[])?,
+            days: parse_cron_field(days, 1, 31, &[])?,
+            months: parse_cron_field(months, 1, 12, &MONTHS)?,
+            weekdays: weekdays_mask,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+
+    fn matches(&self, time: NaiveDateTime) -> bool {
+        let day = self.days & (1 << time.day()) != 0;
+        let weekday = self.weekdays & (1 << time.weekday().num_days_from_sunday()) != 0;
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+
+        self.minutes & (1 << time.minute()) != 0
+            && self.hours & (1 << time.hour()) != 0
+            && self.months & (1 << time.month()) != 0
+            && day_matches
+    }
+}
+
+/// A bound of a window, either a fixed time or relative to the sun
+enum TimeOfDay {
+    Fixed(NaiveTime),
+    Sun {
+        event: SunEvent,
+        offset: TimeDelta,
+    },
+}
+
+impl TimeOfDay {
+    /// Returns when the time of day falls on a given day, none when the sun
+    /// does not rise or set that day
+    fn on(&self, date: NaiveDate, location: Option<(f64, f64)>, clock: &dyn Clock) -> Option<NaiveDateTime> {
+        match self {
+            TimeOfDay::Fixed(time) => Some(date.and_time(*time)),
+            TimeOfDay::Sun { event, offset } => {
+                let (latitude, longitude) = location?;
+                sun_event(*event, date, latitude, longitude).map(|utc| clock.to_local(utc) + *offset)
+            },
+        }
+    }
+}
+
+/// When a rule runs its command
+enum Trigger {
+    /// Once when the window opens, the window ending the next day if it ends
+    /// before it starts. Days are the ones the window opens on, all if empty.
+    Window {
+        from: TimeOfDay,
+        to: TimeOfDay,
+        days: Vec<Weekday>,
+    },
+    /// At each minute matching the expression
+    Cron(Cron),
+}
+
+impl Trigger {
+    /// Returns the start and end of the window open at the given time, if any
+    fn open_window(&self, time: NaiveDateTime, location: Option<(f64, f64)>, clock: &dyn Clock) -> Option<(NaiveDateTime, NaiveDateTime)> {
+        let Trigger::Window { from, to, days } = self else {
+            return None;
+        };
+
+        // The window opened either today, or yesterday when it runs past midnight
+        [time.date(), time.date().pred_opt()?].into_iter()
+            .filter(|day| days.is_empty() || days.contains(&day.weekday()))
+            .filter_map(|day| {
+                let start = from.on(day, location, clock)?;
+                let end = match to.on(day, location, clock)? {
+                    end if end > start => end,
+                    _ => to.on(day.succ_opt()?, location, clock)?,
+                };
+                Some((start, end))
+            })
+            .find(|(start, end)| *start <= time && time < *end)
+    }
+}
+
+struct Rule {
+    name: String,
+    trigger: Trigger,
+    command: Command,
+    /// The brightness in percent when the window opens and when it closes
+    ramp: Option<(u8, u8)>,
+    /// Whether the window was open at the last evaluation
+    open: bool,
+    /// The brightness of the ramp last sent
+    brightness: Option<u8>,
+}
+
+/// Parses an offset such as `+30m`, `-1h` or `+1h30m`
+fn parse_offset(offset: &str) -> Result<TimeDelta, String> {
+    let invalid = || format!("invalid offset `{}`, expected e.g. +30m or -1h", offset);
+    let (sign, mut rest) = match offset.split_at_checked(1) {
+        Some(("+", rest)) if !rest.is_empty() => (1, rest),
+        Some(("-", rest)) if !rest.is_empty() => (-1, rest),
+        _ => return Err(invalid()),
+    };
+
+    let mut seconds = 0;
+    while !rest.is_empty() {
+        let unit = rest.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+        let value: i64 = rest[..unit].parse().map_err(|_| invalid())?;
+        seconds += value * match &rest[unit..unit + 1] {
+            "h" => 3600,
+            "m" => 60,
+            "s" => 1,
+            _ => return Err(invalid()),
+        };
+        rest = &rest[unit + 1..];
+    }
+
+    Ok(TimeDelta::seconds(sign * seconds))
+}
+
+/// Parses a `HH:MM` or `HH:MM:SS` time of the day, or a time relative to the
+/// sun such as `sunset-30m`
+fn parse_time(time: &str, location: Option<(f64, f64)>) -> Result<TimeOfDay, String> {
+    let split = time.find(['+', '-']).unwrap_or(time.len());
+    if let Some(event) = SunEvent::from_name(&time[..split]) {
+        if location.is_none() {
+            return Err(format!("`{}` needs the latitude and longitude in the configuration", time));
+        }
+        let offset = match &time[split..] {
+            "" => TimeDelta::zero(),
+            offset => parse_offset(offset)?,
+        };
+        return Ok(TimeOfDay::Sun { event, offset });
+    }
+
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M:%S"))
+        .map(TimeOfDay::Fixed)
+        .map_err(|_| format!("invalid time `{}`, expected HH:MM or e.g. sunset-30m", time))
+}
+
+impl Rule {
+    fn new(index: usize, rule: &ScheduleRule, targets: &Targets, location: Option<(f64, f64)>) -> Result<Rule, String> {
+        let name = rule.name.clone().unwrap_or(format!("#{}", index + 1));
+        let trigger = match (&rule.from, &rule.to, &rule.cron) {
+            (Some(from), Some(to), None) => Trigger::Window {
+                from: parse_time(from, location)?,
+                to: parse_time(to, location)?,
+                days: rule.days.iter()
+                    .map(|d| d.parse::<Weekday>().map_err(|_| format!("unknown day `{}`", d)))
+                    .collect::<Result<_, _>>()?,
+            },
+            (None, None, Some(cron)) => Trigger::Cron(Cron::parse(cron)?),
+            _ => return Err("a rule needs either `from` and `to`, or `cron`".to_string()),
+        };
+
+        // The command is a payload as accepted on the MQTT channel, either plain text or a table
+        let payload = match &rule.command {
+            serde_json::Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+
+        let command = Command::parse(&payload, targets)?;
+
+        let ramp = match rule.ramp {
+            Some([first, last]) if first > 100 || last > 100 => return Err("a ramp is in percent, up to 100".to_string()),
+            Some(_) if matches!(trigger, Trigger::Cron(_)) => return Err("only a window can ramp the brightness".to_string()),
+            Some(_) if !matches!(command, Command::SetAnimation(_)) => return Err("only an animation command can ramp the brightness".to_string()),
+            Some([first, last]) => Some((first, last)),
+            None => None,
+        };
+
+        Ok(Rule {
+            name,
+            trigger,
+            command,
+            ramp,
+            open: false,
+            brightness: None,
+        })
+    }
+}
+
+/// Runs the commands of the `[[schedule]]` rules. A window rule runs its
+/// command when the window opens, and again when the controller starts or the
+/// schedule resumes while it is open. A cron rule runs its command at each
+/// matching minute. Later rules win over earlier ones targeting the same segments.
+/// Windows can be bound to the sun, and ramp the brightness while they are open.
+pub struct Schedule {
+    rules: Vec<Rule>,
+    clock: Box<dyn Clock>,
+    /// The latitude and longitude the sun is followed at
+    location: Option<(f64, f64)>,
+    /// How long a manual command suspends the schedule
+    override_duration: TimeDelta,
+    suspended_until: Option<NaiveDateTime>,
+    /// The minute the cron rules were last evaluated at
+    last_minute: Option<NaiveDateTime>,
+}
+
+impl Schedule {
+    /// Builds the schedule, the invalid rules being logged and ignored
+    ///
+    /// # Arguments
+    ///
+    /// * `rules` - The rules of the configuration
+    /// * `targets` - The animations and segments the commands can refer to
+    /// * `location` - The latitude and longitude the sun is followed at, if configured
+    /// * `override_duration` - The number of seconds a manual command suspends the schedule for
+    /// * `clock` - The clock the rules are evaluated with
+    pub fn new(rules: &[ScheduleRule], targets: &Targets, location: Option<(f64, f64)>, override_duration: f64, clock: Box<dyn Clock>) -> Schedule {
+        let rules = rules.iter().enumerate().filter_map(|(i, rule)| {
+            Rule::new(i, rule, targets, location)
+                .map_err(|e| error!("Ignoring invalid schedule rule {}: {}", rule.name.clone().unwrap_or(format!("#{}", i + 1)), e))
+                .ok()
+        }).collect();
+
+        Schedule {
+            rules,
+            clock,
+            location,
+            override_duration: TimeDelta::milliseconds((override_duration * 1000.0) as i64),
+            suspended_until: None,
+            last_minute: None,
+        }
+    }
+
+    /// Suspends the schedule after a manual command, for the configured duration
+    pub fn suspend(&mut self) {
+        if self.rules.is_empty() {
+            return;
+        }
+
+        info!("Manual command received, suspending the schedule for {}s", self.override_duration.num_seconds());
+        self.suspended_until = Some(self.clock.now() + self.override_duration);
+    }
+
+    /// Evaluates the rules at the current time
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Command>` - The commands of the rules that trigger, in the order of the rules
+    pub fn poll(&mut self) -> Vec<Command> {
+        let now = self.clock.now();
+        if let Some(until) = self.suspended_until {
+            if now < until {
+                return vec![];
+            }
+
+            // The open windows run their command again, replacing the manual one
+            info!("Resuming the schedule");
+            self.suspended_until = None;
+            for rule in self.rules.iter_mut() {
+                rule.open = false;
+            }
+        }
+
+        let minute = now.with_second(0).and_then(|t| t.with_nanosecond(0)).unwrap_or(now);
+        let new_minute = self.last_minute != Some(minute);
+        self.last_minute = Some(minute);
+
+        let mut commands = vec![];
+        for rule in self.rules.iter_mut() {
+            let window = rule.trigger.open_window(now, self.location, self.clock.as_ref());
+            let triggered = match &rule.trigger {
+                Trigger::Window { .. } => window.is_some() && !rule.open,
+                Trigger::Cron(cron) => new_minute && cron.matches(now),
+            };
+            rule.open = window.is_some();
+
+            // The brightness of the ramp, from its first value when the window opens to its last when it closes
+            let brightness = window.zip(rule.ramp).map(|((start, end), (first, last))| {
+                let progress = (now - start).num_milliseconds() as f64 / (end - start).num_milliseconds().max(1) as f64;
+                (first as f64 + (last as f64 - first as f64) * progress).round() as u8
+            });
+
+            if triggered {
+                info!("Schedule rule {} triggered", rule.name);
+                let mut command = rule.command.clone();
+                if let (Command::SetAnimation(animation), Some(brightness)) = (&mut command, brightness) {
+                    animation.params.brightness = brightness;
+                }
+                commands.push(command);
+            } else if let (Command::SetAnimation(animation), Some(b)) = (&rule.command, brightness) {
+                if rule.brightness != Some(b) {
+                    commands.push(Command::SetBrightness {
+                        segment: animation.segment.clone(),
+                        channel: animation.channel,
+                        brightness: b,
+                    });
+                }
+            }
+            rule.brightness = brightness;
+        }
+
+        commands
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::config::Segment;
+
+    const PARIS: (f64, f64) = (48.8566, 2.3522);
+
+    /// A clock whose time is set by the test, in UTC
+    struct FakeClock(Rc<Cell<NaiveDateTime>>);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> NaiveDateTime {
+            self.0.get()
+        }
+
+        fn to_local(&self, utc: NaiveDateTime) -> NaiveDateTime {
+            utc
+        }
+    }
+
+    fn targets() -> Targets {
+        Targets {
+            animations: ["off", "rainbow", "chase"].map(String::from).to_vec(),
+            segments: vec![Segment { name: "strip".to_string(), channel: 0, start: 0, length: 10, reversed: false }],
+            scenes: vec![],
+        }
+    }
+
+    fn at(day: u32, time: &str) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 3, day).unwrap().and_time(NaiveTime::parse_from_str(time, "%H:%M:%S").unwrap())
+    }
+
+    fn window(from: &str, to: &str) -> ScheduleRule {
+        ScheduleRule {
+            name: None,
+            from: Some(from.to_string()),
+            to: Some(to.to_string()),
+            days: vec![],
+            cron: None,
+            command: "rainbow".into(),
+            ramp: None,
+        }
+    }
+
+    fn cron(expression: &str) -> ScheduleRule {
+        ScheduleRule {
+            name: None,
+            from: None,
+            to: None,
+            days: vec![],
+            cron: Some(expression.to_string()),
+            command: "chase".into(),
+            ramp: None,
+        }
+    }
+
+    /// Builds a schedule of a single rule, returning the time it is evaluated at
+    fn start(rule: ScheduleRule, start: NaiveDateTime) -> (Schedule, Rc<Cell<NaiveDateTime>>) {
+        let time = Rc::new(Cell::new(start));
+        let schedule = Schedule::new(&[rule], &targets(), Some(PARIS), 60.0, Box::new(FakeClock(time.clone())));
+        (schedule, time)
+    }
+
+    /// Returns the times at which the schedule runs a command, among the given ones
+    fn triggers(schedule: &mut Schedule, time: &Cell<NaiveDateTime>, times: &[NaiveDateTime]) -> Vec<NaiveDateTime> {
+        times.iter().copied().filter(|t| {
+            time.set(*t);
+            !schedule.poll().is_empty()
+        }).collect()
+    }
+
+    #[test]
+    fn window_runs_its_command_when_it_opens() {
+        let (mut schedule, time) = start(window("08:00", "09:00"), at(1, "07:59:00"));
+        assert!(schedule.poll().is_empty());
+
+        time.set(at(1, "08:00:00"));
+        assert_eq!(schedule.poll(), vec![Command::parse("rainbow", &targets()).unwrap()]);
+    }
+
+    #[test]
+    fn window_does_not_run_again_while_open() {
+        let (mut schedule, time) = start(window("08:00", "09:00"), at(1, "07:59:00"));
+        let times = [at(1, "08:00:00"), at(1, "08:00:30"), at(1, "08:30:00"), at(1, "08:59:59"), at(1, "09:00:00"), at(2, "08:00:00")];
+
+        assert_eq!(triggers(&mut schedule, &time, &times), vec![at(1, "08:00:00"), at(2, "08:00:00")]);
+    }
+
+    #[test]
+    fn window_runs_past_midnight() {
+        let (mut schedule, time) = start(window("22:00", "06:00"), at(1, "21:00:00"));
+        let times = [at(1, "21:59:59"), at(1, "22:00:00"), at(1, "23:59:00"), at(2, "00:00:00"), at(2, "05:59:00"), at(2, "06:00:00"), at(2, "22:00:00")];
+
+        assert_eq!(triggers(&mut schedule, &time, &times), vec![at(1, "22:00:00"), at(2, "22:00:00")]);
+    }
+
+    #[test]
+    fn window_open_since_yesterday_runs_on_start() {
+        let (mut schedule, _) = start(window("22:00", "06:00"), at(2, "03:00:00"));
+        assert_eq!(schedule.poll().len(), 1);
+
+        let (mut schedule, _) = start(window("22:00", "06:00"), at(2, "07:00:00"));
+        assert!(schedule.poll().is_empty());
+    }
+
+    #[test]
+    fn manual_commands_suspend_the_schedule() {
+        let (mut schedule, time) = start(window("08:00", "09:00"), at(1, "08:00:00"));
+        assert_eq!(schedule.poll().len(), 1);
+
+        // The override lasts 60 seconds, after which the open window runs its command again
+        time.set(at(1, "08:10:00"));
+        schedule.suspend();
+        let times = [at(1, "08:10:30"), at(1, "08:10:59"), at(1, "08:11:00"), at(1, "08:11:30"), at(1, "08:30:00")];
+        assert_eq!(triggers(&mut schedule, &time, &times), vec![at(1, "08:11:00")]);
+    }
+
+    #[test]
+    fn windows_opening_while_suspended_run_on_resume() {
+        let (mut schedule, time) = start(window("08:00", "09:00"), at(1, "07:59:30"));
+        schedule.suspend();
+        let times = [at(1, "08:00:00"), at(1, "08:00:29"), at(1, "08:00:30"), at(1, "08:01:00")];
+        assert_eq!(triggers(&mut schedule, &time, &times), vec![at(1, "08:00:30")]);
+
+        // A window closing while suspended does not run again
+        time.set(at(1, "08:59:30"));
+        schedule.suspend();
+        let times = [at(1, "09:00:00"), at(1, "09:00:30"), at(1, "09:01:00")];
+        assert!(triggers(&mut schedule, &time, &times).is_empty());
+    }
+
+    #[test]
+    fn windows_follow_the_sun() {
+        let sunset = sun_event(SunEvent::Sunset, at(21, "00:00:00").date(), PARIS.0, PARIS.1).unwrap();
+        let opens = sunset - TimeDelta::minutes(30);
+        let (mut schedule, time) = start(window("sunset-30m", "23:00"), at(21, "12:00:00"));
+
+        let times = [opens - TimeDelta::seconds(1), opens, sunset, at(21, "23:00:00"), at(22, "12:00:00")];
+        assert_eq!(triggers(&mut schedule, &time, &times), vec![opens]);
+    }
+
+    #[test]
+    fn parses_the_offsets() {
+        assert_eq!(parse_offset("+1h30m"), Ok(TimeDelta::minutes(90)));
+        assert_eq!(parse_offset("-30m"), Ok(TimeDelta::minutes(-30)));
+        assert_eq!(parse_offset("+45s"), Ok(TimeDelta::seconds(45)));
+        for offset in ["30m", "+", "+1x", "+m", "-1h30"] {
+            assert!(parse_offset(offset).is_err(), "accepted {}", offset);
+        }
+        assert!(parse_time("sunset-30m", None).is_err());
+        assert!(parse_time("noon", Some(PARIS)).is_err());
+    }
+
+    #[test]
+    fn ramps_the_brightness_across_the_window() {
+        let rule = ScheduleRule { ramp: Some([20, 100]), ..window("08:00", "10:00") };
+        let (mut schedule, time) = start(rule, at(1, "08:00:00"));
+        let brightness = |commands: Vec<Command>| match commands.as_slice() {
+            [Command::SetAnimation(animation)] => Some(animation.params.brightness),
+            [Command::SetBrightness { brightness, .. }] => Some(*brightness),
+            [] => None,
+            other => panic!("unexpected commands {:?}", other),
+        };
+
+        assert_eq!(brightness(schedule.poll()), Some(20));
+        time.set(at(1, "09:00:00"));
+        assert_eq!(brightness(schedule.poll()), Some(60));
+        time.set(at(1, "09:00:10"));
+        assert_eq!(brightness(schedule.poll()), None);
+        time.set(at(1, "09:59:59"));
+        assert_eq!(brightness(schedule.poll()), Some(100));
+        time.set(at(1, "10:00:00"));
+        assert_eq!(brightness(schedule.poll()), None);
+    }
+
+    #[test]
+    fn only_animation_windows_ramp() {
+        let ramped = |rule: ScheduleRule, command: &str, ramp: [u8; 2]| ScheduleRule { command: command.into(), ramp: Some(ramp), ..rule };
+
+        assert!(Rule::new(0, &ramped(window("08:00", "10:00"), "rainbow", [0, 100]), &targets(), None).is_ok());
+        assert!(Rule::new(0, &ramped(window("08:00", "10:00"), "rainbow", [0, 120]), &targets(), None).is_err());
+        assert!(Rule::new(0, &ramped(window("08:00", "10:00"), "stop", [0, 100]), &targets(), None).is_err());
+        assert!(Rule::new(0, &ramped(cron("0 8 * * *"), "rainbow", [0, 100]), &targets(), None).is_err());
+    }
+
+    #[test]
+    fn cron_runs_once_at_each_step() {
+        let (mut schedule, time) = start(cron("*/15 * * * *"), at(1, "09:59:00"));
+        let times = [
+            at(1, "10:00:00"), at(1, "10:00:30"), at(1, "10:01:00"), at(1, "10:14:59"), at(1, "10:15:00"),
+            at(1, "10:30:10"), at(1, "10:44:00"), at(1, "10:45:00"), at(1, "10:50:00"), at(1, "11:00:00"),
+        ];
+
+        assert_eq!(triggers(&mut schedule, &time, &times), vec![
+            at(1, "10:00:00"), at(1, "10:15:00"), at(1, "10:30:10"), at(1, "10:45:00"), at(1, "11:00:00"),
+        ]);
+    }
+}
@@ -0,0 +1,155 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+/// How often dropped frames are reported in the logs
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The source of the time the frames are paced with
+pub trait Timer {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+/// The monotonic clock of the system
+pub struct SystemTimer;
+
+impl Timer for SystemTimer {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Paces the animation loop at a fixed frame rate. Frames are scheduled on a
+/// fixed grid of ticks, so the time spent rendering does not delay the next
+/// ones, and the ticks missed by a slow frame are dropped rather than caught up.
+pub struct Scheduler {
+    timer: Box<dyn Timer>,
+    period: Duration,
+    next_tick: Instant,
+    last_frame: Instant,
+    dropped_frames: u64,
+    unreported_frames: u64,
+    reported_at: Instant,
+}
+
+impl Scheduler {
+    /// Creates a scheduler ticking the given number of times per second
+    pub fn new(fps: u32) -> Scheduler {
+        Scheduler::with_timer(fps, Box::new(SystemTimer))
+    }
+
+    /// Creates a scheduler ticking the given number of times per second of the given timer
+    pub fn with_timer(fps: u32, timer: Box<dyn Timer>) -> Scheduler {
+        let now = timer.now();
+        let period = Duration::from_secs(1) / fps.max(1);
+        Scheduler {
+            timer,
+            period,
+            next_tick: now + period,
+            last_frame: now,
+            dropped_frames: 0,
+            unreported_frames: 0,
+            reported_at: now,
+        }
+    }
+
+    /// Waits for the next tick
+    ///
+    /// # Returns
+    ///
+    /// * `Duration` - The time elapsed since the previous frame
+    pub fn wait(&mut self) -> Duration {
+        let now = self.timer.now();
+        if now < self.next_tick {
+            self.timer.sleep(self.next_tick - now);
+        } else {
+            // Skip the ticks that were missed while the previous frame was computed
+            let missed = ((now - self.next_tick).as_nanos() / self.period.as_nanos()) as u32;
+            self.next_tick += self.period * missed;
+            self.dropped_frames += missed as u64;
+            self.unreported_frames += missed as u64;
+        }
+        self.next_tick += self.period;
+
+        let now = self.timer.now();
+        if self.unreported_frames > 0 && now - self.reported_at >= REPORT_INTERVAL {
+            warn!("Dropped {} frames in the last {} seconds", self.unreported_frames, (now - self.reported_at).as_secs());
+            self.unreported_frames = 0;
+            self.reported_at = now;
+        }
+
+        let dt = now - self.last_frame;
+        self.last_frame = now;
+        dt
+    }
+
+    /// Returns the number of frames dropped since the scheduler started
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    /// A timer whose sleeps advance the time right away, the test advancing it too
+    struct FakeTimer(Rc<Cell<Instant>>);
+
+    impl Timer for FakeTimer {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.0.set(self.0.get() + duration);
+        }
+    }
+
+    fn scheduler(fps: u32) -> (Scheduler, Rc<Cell<Instant>>) {
+        let time = Rc::new(Cell::new(Instant::now()));
+        (Scheduler::with_timer(fps, Box::new(FakeTimer(time.clone()))), time)
+    }
+
+    fn ms(milliseconds: u64) -> Duration {
+        Duration::from_millis(milliseconds)
+    }
+
+    #[test]
+    fn frames_are_a_period_apart() {
+        let (mut scheduler, time) = scheduler(50);
+        let start = time.get();
+
+        assert_eq!(scheduler.wait(), ms(20));
+        time.set(time.get() + ms(5));
+        assert_eq!(scheduler.wait(), ms(20));
+        assert_eq!(time.get(), start + ms(40));
+        assert_eq!(scheduler.dropped_frames(), 0);
+    }
+
+    #[test]
+    fn slow_frames_drop_the_missed_ticks() {
+        let (mut scheduler, time) = scheduler(50);
+        let start = time.get();
+        assert_eq!(scheduler.wait(), ms(20));
+
+        // The frame takes 70ms, so the ticks at 40ms and 60ms are missed
+        time.set(time.get() + ms(70));
+        assert_eq!(scheduler.wait(), ms(70));
+        assert_eq!(scheduler.dropped_frames(), 2);
+
+        // The next frame is back on the grid of ticks
+        assert_eq!(scheduler.wait(), ms(10));
+        assert_eq!(time.get(), start + ms(100));
+        assert_eq!(scheduler.dropped_frames(), 2);
+    }
+}
@@ -0,0 +1,57 @@
+use super::{LedSink, RawColor};
+
+/// An in-memory LED buffer, useful to run animations without any hardware
+pub struct FrameBuffer {
+    channels: Vec<Vec<RawColor>>,
+    brightness: Vec<u8>,
+    frames: usize,
+}
+
+impl FrameBuffer {
+    /// Creates a new frame buffer with one channel per given length, all LEDs off
+    pub fn new(lengths: &[i32]) -> FrameBuffer {
+        FrameBuffer {
+            channels: lengths.iter().map(|l| vec![[0, 0, 0, 0]; (*l).max(0) as usize]).collect(),
+            brightness: vec![255; lengths.len()],
+            frames: 0,
+        }
+    }
+
+    /// Returns the brightness of the given channel
+    pub fn brightness(&self, channel: usize) -> u8 {
+        self.brightness.get(channel).copied().unwrap_or(0)
+    }
+
+    /// Returns the number of frames rendered so far
+    #[cfg(test)]
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+}
+
+impl LedSink for FrameBuffer {
+    fn leds(&self, channel: usize) -> &[RawColor] {
+        match self.channels.get(channel) {
+            Some(c) => c.as_slice(),
+            None => &[],
+        }
+    }
+
+    fn leds_mut(&mut self, channel: usize) -> &mut [RawColor] {
+        match self.channels.get_mut(channel) {
+            Some(c) => c.as_mut_slice(),
+            None => &mut [],
+        }
+    }
+
+    fn set_brightness(&mut self, channel: usize, brightness: u8) {
+        if let Some(b) = self.brightness.get_mut(channel) {
+            *b = brightness;
+        }
+    }
+
+    fn render(&mut self) -> Result<(), String> {
+        self.frames += 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+/// A single LED colour as stored by the ws281x driver
+pub type RawColor = [u8; 4];
+
+pub trait LedSink {
+    /// Returns the LEDs of the given channel
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel to read the LEDs from
+    fn leds(&self, channel: usize) -> &[RawColor];
+
+    /// Returns the LEDs of the given channel, allowing them to be modified
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel to write the LEDs to
+    fn leds_mut(&mut self, channel: usize) -> &mut [RawColor];
+
+    /// Sets the brightness of the given channel
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel to set the brightness of
+    /// * `brightness` - The brightness, from 0 to 255
+    fn set_brightness(&mut self, channel: usize, brightness: u8);
+
+    /// Pushes the current content of the sink to its output
+    fn render(&mut self) -> Result<(), String>;
+}
+
+mod memory;
+mod terminal;
+mod ws281x;
+
+pub use memory::FrameBuffer;
+pub use terminal::TerminalSink;
+pub use ws281x::strip_type;
@@ -0,0 +1,71 @@
+use std::io::{self, Write};
+
+use super::{FrameBuffer, LedSink, RawColor};
+use crate::config::Segment;
+
+/// Renders the strip as a row of true-colour blocks in the terminal
+pub struct TerminalSink {
+    buffer: FrameBuffer,
+    segments: Vec<Segment>,
+}
+
+impl TerminalSink {
+    /// Creates a terminal sink drawing the given segments side by side
+    ///
+    /// # Arguments
+    ///
+    /// * `lengths` - The number of LEDs of each channel
+    /// * `segments` - The segments to draw
+    pub fn new(lengths: &[i32], segments: &[Segment]) -> TerminalSink {
+        TerminalSink {
+            buffer: FrameBuffer::new(lengths),
+            segments: segments.to_vec(),
+        }
+    }
+}
+
+/// Formats a single LED as a coloured block. The driver stores colours as
+/// [blue, green, red, white], so they are read back in that order.
+fn block(led: &RawColor, brightness: u8) -> String {
+    let scale = |c: u8| (c as u16 * brightness as u16 / 255) as u8;
+    format!("\x1b[38;2;{};{};{}m█", scale(led[2]), scale(led[1]), scale(led[0]))
+}
+
+impl LedSink for TerminalSink {
+    fn leds(&self, channel: usize) -> &[RawColor] {
+        self.buffer.leds(channel)
+    }
+
+    fn leds_mut(&mut self, channel: usize) -> &mut [RawColor] {
+        self.buffer.leds_mut(channel)
+    }
+
+    fn set_brightness(&mut self, channel: usize, brightness: u8) {
+        self.buffer.set_brightness(channel, brightness)
+    }
+
+    fn render(&mut self) -> Result<(), String> {
+        let mut line = String::from("\r\x1b[2K");
+        for (index, segment) in self.segments.iter().enumerate() {
+            let leds = self.buffer.leds(segment.channel);
+            let brightness = self.buffer.brightness(segment.channel);
+            let start = (segment.start as usize).min(leds.len());
+            let end = ((segment.start + segment.length) as usize).min(leds.len());
+
+            if index > 0 {
+                line.push_str(" | ");
+            }
+            line.push_str(&segment.name);
+            line.push(' ');
+            line.extend(leds[start..end].iter().map(|led| block(led, brightness)));
+            line.push_str("\x1b[0m");
+        }
+
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(line.as_bytes())
+            .and_then(|_| stdout.flush())
+            .map_err(|e| e.to_string())?;
+
+        self.buffer.render()
+    }
+}
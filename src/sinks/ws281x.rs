@@ -0,0 +1,41 @@
+use rs_ws281x::{Controller, StripType};
+
+use super::{LedSink, RawColor};
+
+/// The ws281x controller, driving the physical strip
+impl LedSink for Controller {
+    fn leds(&self, channel: usize) -> &[RawColor] {
+        Controller::leds(self, channel)
+    }
+
+    fn leds_mut(&mut self, channel: usize) -> &mut [RawColor] {
+        Controller::leds_mut(self, channel)
+    }
+
+    fn set_brightness(&mut self, channel: usize, brightness: u8) {
+        Controller::set_brightness(self, channel, brightness)
+    }
+
+    fn render(&mut self) -> Result<(), String> {
+        Controller::render(self).map_err(|e| e.to_string())
+    }
+}
+
+/// Returns the strip type matching the given configuration name (e.g. `ws2811_grb`)
+pub fn strip_type(name: &str) -> Option<StripType> {
+    match name.to_lowercase().as_str() {
+        "ws2811_rgb" => Some(StripType::Ws2811Rgb),
+        "ws2811_rbg" => Some(StripType::Ws2811Rbg),
+        "ws2811_grb" => Some(StripType::Ws2811Grb),
+        "ws2811_gbr" => Some(StripType::Ws2811Gbr),
+        "ws2811_brg" => Some(StripType::Ws2811Brg),
+        "ws2811_bgr" => Some(StripType::Ws2811Bgr),
+        "sk6812_rgbw" => Some(StripType::Sk6812Rgbw),
+        "sk6812_rbgw" => Some(StripType::Sk6812Rbgw),
+        "sk6812_grbw" => Some(StripType::Sk6812Grbw),
+        "sk6812_gbrw" => Some(StripType::Sk6812Gbrw),
+        "sk6812_brgw" => Some(StripType::Sk6812Brgw),
+        "sk6812_bgrw" => Some(StripType::Sk6812Bgrw),
+        _ => None,
+    }
+}
@@ -0,0 +1,146 @@
+use std::sync::{mpsc, Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use log::warn;
+use rumqttc::{Client, QoS};
+use serde::Serialize;
+
+use crate::homeassistant;
+
+/// Payloads of the availability topic, the offline one being the last will of the connection
+pub const ONLINE: &str = "online";
+pub const OFFLINE: &str = "offline";
+
+/// Returns the topic telling whether the controller is connected to the broker
+pub fn availability_topic(mqtt_channel: &str) -> String {
+    format!("{}/availability", mqtt_channel)
+}
+
+/// The state of the controller, as published on the state topic
+#[derive(Serialize)]
+pub struct ControllerState<'a> {
+    pub animation: &'a str,
+    pub stopping: bool,
+    pub brightness: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<(u8, u8, u8)>,
+    pub uptime: u64,
+    /// Number of frames dropped because rendering could not keep up with the frame rate
+    pub dropped_frames: u64,
+    /// The protocol streaming the frames shown instead of the animations, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub realtime: Option<&'a str>,
+    pub segments: Vec<SegmentState<'a>>,
+}
+
+/// The state of a single segment
+#[derive(Serialize)]
+pub struct SegmentState<'a> {
+    pub segment: &'a str,
+    pub channel: usize,
+    pub animation: &'a str,
+    pub stopping: bool,
+    pub brightness: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<(u8, u8, u8)>,
+    pub speed: f64,
+    /// The animations running on the layers above the base one
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub overlays: Vec<&'a str>,
+}
+
+/// The last state of the controller, shared with the interfaces reporting it on request
+#[derive(Clone, Default)]
+pub struct SharedState {
+    state: Arc<RwLock<serde_json::Value>>,
+}
+
+impl SharedState {
+    /// Replaces the shared state with the given one
+    pub fn update(&self, state: &ControllerState) {
+        match (serde_json::to_value(state), self.state.write()) {
+            (Ok(value), Ok(mut shared)) => *shared = value,
+            (Err(e), _) => warn!("Unable to serialize controller state: {}", e),
+            (_, Err(e)) => warn!("Unable to update the shared state: {}", e),
+        }
+    }
+
+    /// Returns the last state of the controller, with an up to date uptime
+    pub fn get(&self, started_at: &Instant) -> serde_json::Value {
+        let mut state = match self.state.read() {
+            Ok(s) => s.clone(),
+            Err(e) => e.into_inner().clone(),
+        };
+        if let Some(object) = state.as_object_mut() {
+            object.insert("uptime".to_string(), uptime(started_at).into());
+        }
+
+        state
+    }
+}
+
+/// Publishes the state of the controller as a retained message on `<channel>/state`,
+/// and on the Home Assistant state topic when discovery is enabled
+pub struct StateReporter {
+    client: Client,
+    topic: String,
+    homeassistant_topic: Option<String>,
+    availability_topic: String,
+    /// Notified by the MQTT listener once the disconnection is sent, after the messages queued before it
+    disconnected: mpsc::Receiver<()>,
+}
+
+impl StateReporter {
+    pub fn new(client: Client, mqtt_channel: &str, homeassistant: bool, disconnected: mpsc::Receiver<()>) -> StateReporter {
+        StateReporter {
+            client,
+            topic: format!("{}/state", mqtt_channel),
+            homeassistant_topic: if homeassistant { Some(homeassistant::state_topic(mqtt_channel)) } else { None },
+            availability_topic: availability_topic(mqtt_channel),
+            disconnected,
+        }
+    }
+
+    /// Marks the controller offline and disconnects from the broker, waiting
+    /// for the messages published until then to be sent
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The longest time to wait for the messages to be sent
+    pub fn close(&mut self, timeout: Duration) {
+        let sent = self.client.try_publish(&self.availability_topic, QoS::AtLeastOnce, true, OFFLINE)
+            .and_then(|_| self.client.try_disconnect())
+            .map_err(|e| e.to_string())
+            .and_then(|_| self.disconnected.recv_timeout(timeout).map_err(|e| e.to_string()));
+        if let Err(e) = sent {
+            warn!("Unable to send the last state to the broker: {}", e);
+        }
+    }
+
+    /// Publishes the given state. This never blocks, the state is dropped
+    /// with a warning if the MQTT queue is full.
+    pub fn publish(&mut self, state: &ControllerState) {
+        let payload = match serde_json::to_string(state) {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("Unable to serialize controller state: {}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = self.client.try_publish(&self.topic, QoS::AtLeastOnce, true, payload) {
+            warn!("Unable to publish controller state: {}", e);
+        }
+
+        if let Some(topic) = &self.homeassistant_topic {
+            if let Err(e) = self.client.try_publish(topic, QoS::AtLeastOnce, true, homeassistant::state_payload(state)) {
+                warn!("Unable to publish Home Assistant state: {}", e);
+            }
+        }
+    }
+}
+
+/// Returns the number of seconds elapsed since the given instant
+pub fn uptime(started_at: &Instant) -> u64 {
+    started_at.elapsed().as_secs()
+}
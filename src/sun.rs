@@ -0,0 +1,128 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+
+/// Altitude of the centre of the sun at sunrise and sunset, accounting for refraction and its radius
+const SUNRISE_ALTITUDE: f64 = -0.833;
+/// Altitude of the centre of the sun at the start and end of the civil twilight
+const CIVIL_TWILIGHT_ALTITUDE: f64 = -6.0;
+/// Julian day of 2000-01-01 12:00 UTC
+const J2000: f64 = 2_451_545.0;
+/// Julian day of the unix epoch
+const UNIX_EPOCH_JULIAN_DAY: f64 = 2_440_587.5;
+const OBLIQUITY: f64 = 23.4397;
+
+/// The moments of the day set by the position of the sun
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SunEvent {
+    /// Start of the morning civil twilight
+    Dawn,
+    Sunrise,
+    Sunset,
+    /// End of the evening civil twilight
+    Dusk,
+}
+
+impl SunEvent {
+    pub fn from_name(name: &str) -> Option<SunEvent> {
+        match name {
+            "dawn" => Some(SunEvent::Dawn),
+            "sunrise" => Some(SunEvent::Sunrise),
+            "sunset" => Some(SunEvent::Sunset),
+            "dusk" => Some(SunEvent::Dusk),
+            _ => None,
+        }
+    }
+}
+
+/// Computes when an event happens on a given day, with the sunrise equation.
+/// The result is accurate to a minute or two, which is plenty for lighting.
+///
+/// # Arguments
+///
+/// * `event` - The event
+/// * `date` - The day, as seen from the place
+/// * `latitude` - The latitude of the place, in degrees north
+/// * `longitude` - The longitude of the place, in degrees east
+///
+/// # Returns
+///
+/// * `Option<NaiveDateTime>` - The time of the event in UTC, none when the sun
+///   does not reach its altitude that day (polar day or night)
+pub fn sun_event(event: SunEvent, date: NaiveDate, latitude: f64, longitude: f64) -> Option<NaiveDateTime> {
+    let epoch = NaiveDate::from_ymd_opt(2000, 1, 1)?;
+    let day = (date - epoch).num_days() as f64;
+
+    // Mean solar noon, then the position of the sun on the ecliptic
+    let mean_noon = day + 0.0009 - longitude / 360.0;
+    let anomaly = (357.5291 + 0.985_600_28 * mean_noon).rem_euclid(360.0).to_radians();
+    let center = 1.9148 * anomaly.sin() + 0.02 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+    let ecliptic_longitude = (anomaly.to_degrees() + center + 180.0 + 102.9372).rem_euclid(360.0).to_radians();
+    let transit = J2000 + mean_noon + 0.0053 * anomaly.sin() - 0.0069 * (2.0 * ecliptic_longitude).sin();
+    let declination = (ecliptic_longitude.sin() * OBLIQUITY.to_radians().sin()).asin();
+
+    let altitude = match event {
+        SunEvent::Dawn | SunEvent::Dusk => CIVIL_TWILIGHT_ALTITUDE,
+        SunEvent::Sunrise | SunEvent::Sunset => SUNRISE_ALTITUDE,
+    };
+    let latitude = latitude.to_radians();
+    let cos_hour_angle = (altitude.to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+    let julian_day = match event {
+        SunEvent::Dawn | SunEvent::Sunrise => transit - hour_angle / 360.0,
+        SunEvent::Sunset | SunEvent::Dusk => transit + hour_angle / 360.0,
+    };
+
+    let timestamp = ((julian_day - UNIX_EPOCH_JULIAN_DAY) * 86400.0).round() as i64;
+    DateTime::from_timestamp(timestamp, 0).map(|t| t.naive_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveTime;
+
+    use super::*;
+
+    const PARIS: (f64, f64) = (48.8566, 2.3522);
+    const TROMSO: (f64, f64) = (69.6492, 18.9553);
+
+    fn event(event: SunEvent, (year, month, day): (i32, u32, u32), (latitude, longitude): (f64, f64)) -> Option<NaiveDateTime> {
+        sun_event(event, NaiveDate::from_ymd_opt(year, month, day).unwrap(), latitude, longitude)
+    }
+
+    /// Checks that a time is within 2 minutes of the expected `HH:MM` of the same day
+    fn assert_near(time: Option<NaiveDateTime>, expected: &str) {
+        let time = time.expect("no event");
+        let expected = time.date().and_time(NaiveTime::parse_from_str(expected, "%H:%M").unwrap());
+        assert!((time - expected).num_seconds().abs() <= 120, "{} is not close to {}", time, expected);
+    }
+
+    #[test]
+    fn computes_the_sunrise_and_sunset() {
+        assert_near(event(SunEvent::Sunrise, (2024, 6, 21), PARIS), "03:47");
+        assert_near(event(SunEvent::Sunset, (2024, 6, 21), PARIS), "19:58");
+        assert_near(event(SunEvent::Sunrise, (2024, 12, 21), PARIS), "07:42");
+        assert_near(event(SunEvent::Sunset, (2024, 12, 21), PARIS), "15:56");
+    }
+
+    #[test]
+    fn twilight_surrounds_the_day() {
+        let day = (2024, 3, 20);
+        let times = [SunEvent::Dawn, SunEvent::Sunrise, SunEvent::Sunset, SunEvent::Dusk].map(|e| event(e, day, PARIS).unwrap());
+        assert!(times.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn polar_days_and_nights_have_no_sunrise() {
+        assert_eq!(event(SunEvent::Sunrise, (2024, 6, 21), TROMSO), None);
+        assert_eq!(event(SunEvent::Sunset, (2024, 6, 21), TROMSO), None);
+        assert_eq!(event(SunEvent::Sunrise, (2024, 12, 21), TROMSO), None);
+        assert_eq!(event(SunEvent::Sunset, (2024, 12, 21), TROMSO), None);
+
+        // The sun still gets close enough to the horizon for a civil twilight
+        assert!(event(SunEvent::Dawn, (2024, 12, 21), TROMSO).is_some());
+    }
+}
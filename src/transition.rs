@@ -0,0 +1,173 @@
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::animations::Animation;
+use crate::command::MAX_TRANSITION_DURATION;
+use crate::sinks::{FrameBuffer, LedSink, RawColor};
+
+/// How a layer switches from one animation to the next
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransitionKind {
+    /// The outgoing animation fades out before the incoming one starts
+    #[default]
+    Fade,
+    /// The outgoing animation is mixed into the incoming one
+    Crossfade,
+    /// The incoming animation replaces the outgoing one from the start of the segment to its end
+    Wipe,
+    /// The incoming animation replaces the outgoing one LED by LED, in a random order
+    Dissolve,
+    /// The incoming animation replaces the outgoing one right away
+    Instant,
+}
+
+/// An animation being replaced by the one of its layer. Both animations keep
+/// running until the end of the transition, and their frames are mixed.
+pub struct Transition {
+    kind: TransitionKind,
+    outgoing: Box<dyn Animation>,
+    buffer: FrameBuffer,
+    started_at: Instant,
+    duration: Duration,
+    /// The rank at which each LED switches to the incoming animation, for dissolves
+    order: Vec<usize>,
+}
+
+impl Transition {
+    /// Starts a transition away from the given animation
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - How the animations are mixed, must not be `Fade` nor `Instant`
+    /// * `outgoing` - The animation being replaced
+    /// * `buffer` - The last frame of the outgoing animation
+    /// * `duration` - The duration of the transition, in seconds, clamped to [0, MAX_TRANSITION_DURATION]
+    /// * `now` - When the transition starts
+    pub fn new(kind: TransitionKind, outgoing: Box<dyn Animation>, buffer: FrameBuffer, duration: f64, now: Instant) -> Transition {
+        let mut order: Vec<usize> = (0..buffer.leds(0).len()).collect();
+        if kind == TransitionKind::Dissolve {
+            order.shuffle(&mut rand::thread_rng());
+        }
+
+        Transition {
+            kind,
+            outgoing,
+            buffer,
+            started_at: now,
+            duration: Duration::try_from_secs_f64(duration.clamp(0.0, MAX_TRANSITION_DURATION)).unwrap_or_default(),
+            order,
+        }
+    }
+
+    /// Returns how far the transition is, between 0 and 1
+    fn progress(&self, now: Instant) -> f64 {
+        if self.duration.is_zero() {
+            return 1.0;
+        }
+        (now.saturating_duration_since(self.started_at).as_secs_f64() / self.duration.as_secs_f64()).min(1.0)
+    }
+
+    /// Returns true once the incoming animation fully replaced the outgoing one
+    pub fn done(&self, now: Instant) -> bool {
+        self.progress(now) >= 1.0
+    }
+
+    /// Computes the next frame of the outgoing animation
+    pub fn next_frame(&mut self, dt: Duration) {
+        self.outgoing.next_frame(&mut self.buffer, dt);
+    }
+
+    /// Mixes the frame of the outgoing animation into the one of the incoming animation
+    ///
+    /// # Arguments
+    ///
+    /// * `incoming` - The LEDs of the incoming animation, overwritten with the mix
+    /// * `now` - The time of the frame
+    pub fn mix(&self, incoming: &mut [RawColor], now: Instant) {
+        let progress = self.progress(now);
+        let switched = (progress * incoming.len() as f64) as usize;
+        for (index, (led, outgoing)) in incoming.iter_mut().zip(self.buffer.leds(0)).enumerate() {
+            match self.kind {
+                TransitionKind::Crossfade => {
+                    for i in 0..4 {
+                        led[i] = (outgoing[i] as f64 * (1.0 - progress) + led[i] as f64 * progress) as u8;
+                    }
+                },
+                TransitionKind::Wipe if index >= switched => *led = *outgoing,
+                TransitionKind::Dissolve if self.order[index] >= switched => *led = *outgoing,
+                _ => {},
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::animations::Off;
+
+    const OUTGOING: RawColor = [200, 0, 0, 0];
+    const INCOMING: RawColor = [0, 100, 0, 0];
+
+    /// Starts a transition of 2 seconds away from a frame of the outgoing colour
+    fn transition(kind: TransitionKind, now: Instant) -> Transition {
+        let mut buffer = FrameBuffer::new(&[4]);
+        buffer.leds_mut(0).fill(OUTGOING);
+        Transition::new(kind, Box::new(Off::new()), buffer, 2.0, now)
+    }
+
+    fn mix(transition: &Transition, now: Instant) -> Vec<RawColor> {
+        let mut incoming = vec![INCOMING; 4];
+        transition.mix(&mut incoming, now);
+        incoming
+    }
+
+    #[test]
+    fn crossfade_mixes_the_colours() {
+        let now = Instant::now();
+        let crossfade = transition(TransitionKind::Crossfade, now);
+
+        assert_eq!(mix(&crossfade, now), [OUTGOING; 4]);
+        assert_eq!(mix(&crossfade, now + Duration::from_secs(1)), [[100, 50, 0, 0]; 4]);
+        assert_eq!(mix(&crossfade, now + Duration::from_secs(2)), [INCOMING; 4]);
+        assert!(!crossfade.done(now + Duration::from_millis(1999)));
+        assert!(crossfade.done(now + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn wipe_replaces_the_leds_from_the_start() {
+        let now = Instant::now();
+        let wipe = transition(TransitionKind::Wipe, now);
+
+        assert_eq!(mix(&wipe, now), [OUTGOING; 4]);
+        assert_eq!(mix(&wipe, now + Duration::from_secs(1)), [INCOMING, INCOMING, OUTGOING, OUTGOING]);
+        assert_eq!(mix(&wipe, now + Duration::from_secs(3)), [INCOMING; 4]);
+    }
+
+    #[test]
+    fn dissolve_replaces_a_share_of_the_leds() {
+        let now = Instant::now();
+        let dissolve = transition(TransitionKind::Dissolve, now);
+        let incoming = |leds: Vec<RawColor>| leds.iter().filter(|l| **l == INCOMING).count();
+
+        assert_eq!(incoming(mix(&dissolve, now)), 0);
+        assert_eq!(incoming(mix(&dissolve, now + Duration::from_secs(1))), 2);
+        assert_eq!(incoming(mix(&dissolve, now + Duration::from_millis(1500))), 3);
+        assert_eq!(incoming(mix(&dissolve, now + Duration::from_secs(2))), 4);
+    }
+
+    #[test]
+    fn durations_are_clamped() {
+        let now = Instant::now();
+        let transition = |duration: f64| Transition::new(TransitionKind::Crossfade, Box::new(Off::new()), FrameBuffer::new(&[1]), duration, now);
+
+        assert!(transition(0.0).done(now));
+        assert!(transition(-1.0).done(now));
+        assert!(transition(f64::NAN).done(now));
+        assert!(!transition(1e300).done(now + Duration::from_secs(59)));
+        assert!(transition(1e300).done(now + Duration::from_secs(60)));
+    }
+}
@@ -1,3 +1,8 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
 pub fn hue_to_rgb(h: f64, s: f64, l: f64) -> (u8, u8, u8) {
     let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
     let x = c * (1.0 - ((h / 60.0) % 2.0 - 1.0).abs());
@@ -22,4 +27,73 @@ pub fn hue_to_rgb(h: f64, s: f64, l: f64) -> (u8, u8, u8) {
         ((g + m) * 255.0) as u8,
         ((b + m) * 255.0) as u8,
     )
-}
\ No newline at end of file
+}
+
+/// Parses a colour formatted as `#rrggbb` into its (red, green, blue) components
+pub fn parse_hex_color(color: &str) -> Option<(u8, u8, u8)> {
+    let hex = color.strip_prefix('#')?;
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+
+    Some((
+        u8::from_str_radix(&hex[0..2], 16).ok()?,
+        u8::from_str_radix(&hex[2..4], 16).ok()?,
+        u8::from_str_radix(&hex[4..6], 16).ok()?,
+    ))
+}
+
+/// Converts a (red, green, blue) colour to the driver's [blue, green, red, white] layout
+pub fn rgb_to_raw(color: (u8, u8, u8)) -> [u8; 4] {
+    [color.2, color.1, color.0, 0]
+}
+
+/// Scales a frame duration by the given speed multiplier
+pub fn scaled_wait_time(wait_time: u64, speed: f64) -> u64 {
+    (wait_time as f64 / speed).round() as u64
+}
+
+/// Writes a file through a temporary file renamed over it, so that it is never
+/// left half written. Both the file and the rename are synced to the disk
+/// before returning, so that a power loss keeps either the old or the new content.
+pub fn write_atomically(path: &Path, content: &str) -> io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    let mut file = File::create(&temporary)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&temporary, path)?;
+
+    // The rename is only durable once the directory holding the file is synced
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(directory)?.sync_all()
+}
+
+/// Turns the time elapsed between frames into a number of animation steps,
+/// so that animations run at the same speed whatever the frame rate
+#[derive(Default)]
+pub struct Ticker {
+    elapsed: Duration,
+}
+
+impl Ticker {
+    /// Longest time caught up at once, e.g. after the process was suspended
+    const MAX_CATCH_UP: Duration = Duration::from_secs(1);
+
+    /// Returns the number of steps to run for the given elapsed time, keeping the remainder for the next frame
+    ///
+    /// # Arguments
+    ///
+    /// * `dt` - The time elapsed since the previous frame
+    /// * `step` - The duration of a step, in milliseconds
+    pub fn steps(&mut self, dt: Duration, step: u64) -> u32 {
+        let step = Duration::from_millis(step.max(1));
+        self.elapsed = (self.elapsed + dt).min(Ticker::MAX_CATCH_UP);
+        let steps = (self.elapsed.as_nanos() / step.as_nanos()) as u32;
+        self.elapsed -= step * steps;
+        steps
+    }
+}
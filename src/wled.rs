@@ -0,0 +1,639 @@
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::command::{AnimationCommand, Command, Targets, MAX_SPEED, MAX_TRANSITION_DURATION};
+use crate::config::Segment;
+use crate::homeassistant::DEFAULT_EFFECT;
+use crate::realtime::RealtimeFrame;
+use crate::sinks::RawColor;
+use crate::transition::TransitionKind;
+use crate::utils::parse_hex_color;
+
+/// Version of WLED reported to the clients, recent enough for them to use the JSON API
+const WLED_VERSION: &str = "0.14.0";
+const WLED_BUILD: u32 = 2310130;
+
+/// WLED has palettes where minileds has none
+const PALETTES: [&str; 1] = ["Default"];
+
+/// Name of the UDP realtime source, in the state
+const SOURCE: &str = "wled";
+
+/// UDP realtime protocols, given by the first byte of a packet
+const WARLS: u8 = 1;
+const DRGB: u8 = 2;
+const DRGBW: u8 = 3;
+const DNRGB: u8 = 4;
+
+/// Timeout byte asking to show the frame until released, and how long that is here
+const HOLD: u8 = 255;
+const HOLD_TIMEOUT: Duration = Duration::from_secs(365 * 24 * 3600);
+
+/// What the WLED API reports about the controller
+pub struct WledInfo {
+    pub name: String,
+    /// The number of LEDs of each channel, numbered one after the other by WLED
+    pub channels: Vec<usize>,
+    pub fps: u32,
+    /// Port of the UDP realtime receiver, 0 if disabled
+    pub udp_port: u16,
+}
+
+impl WledInfo {
+    /// Returns the WLED index of the first LED of a channel
+    fn offset(&self, channel: usize) -> usize {
+        self.channels.iter().take(channel).sum()
+    }
+}
+
+/// WLED speeds go from 0 to 255, 128 being the natural speed of the animation
+/// and every 32 steps doubling or halving it
+fn speed_from_wled(sx: u8) -> f64 {
+    2f64.powf((sx as f64 - 128.0) / 32.0).min(MAX_SPEED)
+}
+
+fn speed_to_wled(speed: f64) -> u8 {
+    (128.0 + 32.0 * speed.log2()).round().clamp(0.0, 255.0) as u8
+}
+
+fn brightness_from_wled(bri: u8) -> u8 {
+    ((bri as u16 * 100 + 127) / 255) as u8
+}
+
+fn brightness_to_wled(brightness: u8) -> u8 {
+    (brightness.min(100) as u16 * 255 / 100) as u8
+}
+
+/// Returns the effects, WLED referring to them by their index in this list
+pub fn effects(targets: &Targets) -> Vec<String> {
+    let mut effects = targets.animations.clone();
+    effects.sort();
+    effects
+}
+
+/// What the state of the controller reports about a segment
+struct SegmentStatus {
+    animation: String,
+    on: bool,
+    brightness: u8,
+    color: Option<(u8, u8, u8)>,
+    speed: f64,
+}
+
+impl SegmentStatus {
+    /// Reads the status of a segment from the state, a segment not reported yet being off
+    fn read(state: &Value, segment: &Segment) -> SegmentStatus {
+        let reported = state["segments"].as_array()
+            .and_then(|segments| segments.iter().find(|s| s["segment"] == segment.name.as_str()));
+        let field = |key: &str| reported.map_or(Value::Null, |s| s[key].clone());
+
+        let animation = field("animation").as_str().unwrap_or("off").to_string();
+        SegmentStatus {
+            on: animation != "off" && !field("stopping").as_bool().unwrap_or(false),
+            brightness: field("brightness").as_u64().map_or(100, |b| b.min(100) as u8),
+            color: serde_json::from_value(field("color")).ok(),
+            speed: field("speed").as_f64().unwrap_or(1.0),
+            animation,
+        }
+    }
+}
+
+/// Builds the WLED state, one WLED segment per segment of the strip
+pub fn state(state: &Value, targets: &Targets, info: &WledInfo) -> Value {
+    let effects = effects(targets);
+    let statuses: Vec<SegmentStatus> = targets.segments.iter().map(|s| SegmentStatus::read(state, s)).collect();
+
+    let segments: Vec<Value> = targets.segments.iter().zip(&statuses).enumerate().map(|(id, (segment, status))| {
+        let start = info.offset(segment.channel) + segment.start.max(0) as usize;
+        let length = segment.length.max(0) as usize;
+        let (r, g, b) = status.color.unwrap_or((255, 255, 255));
+        json!({
+            "id": id,
+            "n": segment.name,
+            "start": start,
+            "stop": start + length,
+            "len": length,
+            "grp": 1,
+            "spc": 0,
+            "of": 0,
+            "on": status.on,
+            "frz": false,
+            "bri": brightness_to_wled(status.brightness),
+            "cct": 127,
+            "col": [[r, g, b], [0, 0, 0], [0, 0, 0]],
+            "fx": effects.iter().position(|e| *e == status.animation).unwrap_or(0),
+            "sx": speed_to_wled(status.speed),
+            "ix": 128,
+            "pal": 0,
+            "sel": true,
+            "rev": segment.reversed,
+            "mi": false,
+        })
+    }).collect();
+
+    json!({
+        "on": statuses.iter().any(|s| s.on),
+        "bri": brightness_to_wled(state["brightness"].as_u64().map_or(100, |b| b.min(100) as u8)),
+        "transition": 10,
+        "ps": -1,
+        "pl": -1,
+        "nl": {"on": false, "dur": 60, "mode": 1, "tbri": 0, "rem": -1},
+        "udpn": {"send": false, "recv": false},
+        "lor": 0,
+        "mainseg": 0,
+        "seg": segments,
+    })
+}
+
+/// Builds the WLED info, describing the strip and the controller
+pub fn info(state: &Value, targets: &Targets, info: &WledInfo) -> Value {
+    let live_mode = match state["realtime"].as_str() {
+        Some("e131") => "E1.31",
+        Some("artnet") => "Art-Net",
+        Some(SOURCE) => "UDP",
+        Some(_) | None => "",
+    };
+
+    json!({
+        "ver": WLED_VERSION,
+        "vid": WLED_BUILD,
+        "leds": {
+            "count": info.channels.iter().sum::<usize>(),
+            "rgbw": false,
+            "wv": 0,
+            "cct": 0,
+            "pwr": 0,
+            "fps": info.fps,
+            "maxpwr": 0,
+            "maxseg": targets.segments.len(),
+            "seglc": vec![1; targets.segments.len()],
+            "lc": 1,
+        },
+        "str": false,
+        "name": info.name,
+        "udpport": info.udp_port,
+        "live": !live_mode.is_empty(),
+        "liveseg": -1,
+        "lm": live_mode,
+        "lip": "",
+        "ws": -1,
+        "fxcount": targets.animations.len(),
+        "palcount": PALETTES.len(),
+        "arch": "minileds",
+        "core": env!("CARGO_PKG_VERSION"),
+        "brand": "WLED",
+        "product": "MiniLEDs",
+        "mac": "",
+        "ip": "",
+        "uptime": state["uptime"],
+    })
+}
+
+/// Returns the palettes, for `/json/pal`
+pub fn palettes() -> Value {
+    json!(PALETTES)
+}
+
+/// `true`, `false` or `"t"` to toggle
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Switch {
+    Set(bool),
+    Toggle(String),
+}
+
+impl Switch {
+    fn resolve(&self, current: bool) -> Result<bool, String> {
+        match self {
+            Switch::Set(on) => Ok(*on),
+            Switch::Toggle(t) if t == "t" => Ok(!current),
+            Switch::Toggle(other) => Err(format!("invalid `on` value `{}`", other)),
+        }
+    }
+}
+
+/// `[r, g, b]`, `[r, g, b, w]` or `"rrggbb"`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WledColor {
+    Rgb(Vec<u8>),
+    Hex(String),
+}
+
+impl WledColor {
+    fn rgb(&self) -> Option<(u8, u8, u8)> {
+        match self {
+            WledColor::Rgb(c) if c.len() >= 3 => Some((c[0], c[1], c[2])),
+            WledColor::Rgb(_) => None,
+            WledColor::Hex(hex) => parse_hex_color(&format!("#{}", hex.get(..6)?)),
+        }
+    }
+}
+
+/// The fields of a WLED segment update minileds understands, the others being ignored
+#[derive(Deserialize)]
+struct WledSegment {
+    id: Option<usize>,
+    on: Option<Switch>,
+    bri: Option<u8>,
+    col: Option<Vec<WledColor>>,
+    fx: Option<usize>,
+    sx: Option<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WledSegments {
+    One(WledSegment),
+    Many(Vec<WledSegment>),
+}
+
+/// The fields of a WLED state update minileds understands. The clients send
+/// many more, e.g. for presets or nightlights, which are ignored.
+#[derive(Deserialize)]
+struct WledState {
+    on: Option<Switch>,
+    bri: Option<u8>,
+    /// Transition duration, in tenths of a second
+    transition: Option<u32>,
+    /// Transition duration of this update only
+    tt: Option<u32>,
+    seg: Option<WledSegments>,
+}
+
+/// Parses a WLED state update into commands for the segments it changes.
+/// Changing only the brightness does not restart the animation, and a segment
+/// turned on without an effect starts the one Home Assistant would start.
+///
+/// # Arguments
+///
+/// * `body` - The JSON body of the request
+/// * `state` - The current state of the controller
+/// * `targets` - The animations and segments commands can refer to
+pub fn parse_state(body: &str, state: &Value, targets: &Targets) -> Result<Vec<Command>, String> {
+    let raw: WledState = serde_json::from_str(body)
+        .map_err(|e| format!("invalid WLED state: {}", e))?;
+    let effects = effects(targets);
+
+    let transition = raw.tt.or(raw.transition).map(|t| match t {
+        0 => (TransitionKind::Instant, 0.0),
+        t => (TransitionKind::Crossfade, (t as f64 / 10.0).min(MAX_TRANSITION_DURATION)),
+    });
+
+    let updates = match raw.seg {
+        Some(WledSegments::One(segment)) => vec![segment],
+        Some(WledSegments::Many(segments)) => segments,
+        None => vec![],
+    };
+    if let Some(id) = updates.iter().filter_map(|u| u.id).find(|id| *id >= targets.segments.len()) {
+        return Err(format!("unknown segment id {}", id));
+    }
+
+    let mut commands = vec![];
+    for (id, segment) in targets.segments.iter().enumerate() {
+        let status = SegmentStatus::read(state, segment);
+
+        // The fields of the segment take precedence over the global ones
+        let update = updates.iter().find(|u| u.id.is_none_or(|i| i == id));
+        let on = match update.and_then(|u| u.on.as_ref()).or(raw.on.as_ref()) {
+            Some(switch) => Some(switch.resolve(status.on)?),
+            None => None,
+        };
+        let bri = update.and_then(|u| u.bri).or(raw.bri);
+        let fx = update.and_then(|u| u.fx);
+        let sx = update.and_then(|u| u.sx);
+        let color = match update.and_then(|u| u.col.as_ref()).and_then(|c| c.first()) {
+            Some(color) => Some(color.rgb().ok_or(format!("invalid colour for segment {}", id))?),
+            None => None,
+        };
+
+        // WLED turns the light off at brightness 0
+        if on == Some(false) || bri == Some(0) {
+            if status.on {
+                let mut command = AnimationCommand::new("off");
+                command.segment = Some(segment.name.clone());
+                command.channel = Some(segment.channel);
+                if let Some((kind, duration)) = transition {
+                    command.transition = kind;
+                    command.transition_duration = duration;
+                }
+                commands.push(Command::SetAnimation(command));
+            }
+            continue;
+        }
+
+        let restart = fx.is_some() || sx.is_some() || color.is_some() || (on == Some(true) && !status.on);
+        if !restart {
+            if let Some(bri) = bri {
+                commands.push(Command::SetBrightness {
+                    segment: Some(segment.name.clone()),
+                    channel: Some(segment.channel),
+                    brightness: brightness_from_wled(bri),
+                });
+            }
+            continue;
+        }
+
+        let animation = match fx {
+            Some(fx) => effects.get(fx).ok_or(format!("unknown effect {}", fx))?.clone(),
+            None if status.animation == "off" => DEFAULT_EFFECT.to_string(),
+            None => status.animation.clone(),
+        };
+        let mut command = AnimationCommand::new(&animation);
+        command.segment = Some(segment.name.clone());
+        command.channel = Some(segment.channel);
+        command.params.speed = sx.map_or(status.speed, speed_from_wled);
+        command.params.color = color.or(status.color);
+        command.params.brightness = bri.map_or(status.brightness, brightness_from_wled);
+        if let Some((kind, duration)) = transition {
+            command.transition = kind;
+            command.transition_duration = duration;
+        }
+
+        command.validate(targets)?;
+        commands.push(Command::SetAnimation(command));
+    }
+
+    Ok(commands)
+}
+
+/// The LEDs of a UDP realtime packet
+#[derive(Debug, PartialEq)]
+enum Leds {
+    /// LEDs given with their index
+    Indexed(Vec<(usize, RawColor)>),
+    /// Consecutive LEDs from the given index
+    Range(usize, Vec<RawColor>),
+}
+
+/// Parses a packet of one of the WLED UDP realtime protocols
+///
+/// # Returns
+///
+/// * `Option<(u8, Leds)>` - The timeout byte and the LEDs, none if the protocol is not supported
+fn parse_realtime(packet: &[u8]) -> Option<(u8, Leds)> {
+    let (protocol, timeout) = (*packet.first()?, *packet.get(1)?);
+    let data = &packet[2..];
+    let rgb = |c: &[u8]| [c[2], c[1], c[0], 0];
+
+    let leds = match protocol {
+        WARLS => Leds::Indexed(data.chunks_exact(4).map(|c| (c[0] as usize, rgb(&c[1..]))).collect()),
+        DRGB => Leds::Range(0, data.chunks_exact(3).map(rgb).collect()),
+        DRGBW => Leds::Range(0, data.chunks_exact(4).map(|c| [c[2], c[1], c[0], c[3]]).collect()),
+        DNRGB => {
+            // Without a start index, the packet can only release the strip
+            let start = match data {
+                [high, low, ..] => u16::from_be_bytes([*high, *low]) as usize,
+                _ => 0,
+            };
+            Leds::Range(start, data.get(2..).unwrap_or_default().chunks_exact(3).map(rgb).collect())
+        },
+        _ => return None,
+    };
+
+    Some((timeout, leds))
+}
+
+/// Receives the WLED UDP realtime protocols and writes them to the realtime frame
+pub struct WledReceiver {
+    /// The number of LEDs of each channel, numbered one after the other by WLED
+    channels: Vec<usize>,
+    realtime: RealtimeFrame,
+}
+
+impl WledReceiver {
+    pub fn new(channels: Vec<usize>, realtime: RealtimeFrame) -> WledReceiver {
+        WledReceiver {
+            channels,
+            realtime,
+        }
+    }
+
+    /// Receives packets until the socket fails
+    ///
+    /// # Arguments
+    ///
+    /// * `bind` - The address and port to listen on
+    pub fn serve(&self, bind: &str) {
+        let socket = match UdpSocket::bind(bind) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Unable to listen for WLED realtime packets on {}: {}", bind, e);
+                return;
+            }
+        };
+        info!("Listening for WLED realtime packets on {}", bind);
+
+        let mut buffer = [0; 1500];
+        loop {
+            match socket.recv(&mut buffer) {
+                Ok(size) => self.receive(&buffer[..size]),
+                Err(e) => {
+                    warn!("Unable to receive WLED realtime packets: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Writes the LEDs of a packet to the realtime frame, the unsupported packets being ignored
+    fn receive(&self, packet: &[u8]) {
+        let (timeout, leds) = match parse_realtime(packet) {
+            Some(p) => p,
+            None => return,
+        };
+        // A timeout of 0 hands the strip back to the animations
+        let timeout = match timeout {
+            0 => {
+                self.realtime.release(SOURCE);
+                return;
+            },
+            HOLD => HOLD_TIMEOUT,
+            seconds => Duration::from_secs(seconds as u64),
+        };
+
+        match leds {
+            Leds::Indexed(leds) => {
+                for (index, led) in leds {
+                    self.write(index, &[led], timeout);
+                }
+            },
+            Leds::Range(start, leds) => self.write(start, &leds, timeout),
+        }
+    }
+
+    /// Writes consecutive LEDs, which may span several channels
+    fn write(&self, start: usize, leds: &[RawColor], timeout: Duration) {
+        let mut offset = 0;
+        for (channel, length) in self.channels.iter().enumerate() {
+            let first = start.max(offset);
+            let last = (start + leds.len()).min(offset + length);
+            if first < last {
+                self.realtime.write(channel, first - offset, &leds[first - start..last - start], SOURCE, timeout);
+            }
+            offset += length;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use crate::sinks::{FrameBuffer, LedSink};
+
+    const OFF: RawColor = [0, 0, 0, 0];
+
+    #[test]
+    fn parses_the_realtime_protocols() {
+        assert_eq!(
+            parse_realtime(&[WARLS, 2, 0, 255, 0, 0, 9, 0, 0, 255]),
+            Some((2, Leds::Indexed(vec![(0, [0, 0, 255, 0]), (9, [255, 0, 0, 0])])))
+        );
+        assert_eq!(parse_realtime(&[DRGB, 1, 1, 2, 3, 4, 5, 6]), Some((1, Leds::Range(0, vec![[3, 2, 1, 0], [6, 5, 4, 0]]))));
+        assert_eq!(parse_realtime(&[DRGBW, 255, 1, 2, 3, 4]), Some((255, Leds::Range(0, vec![[3, 2, 1, 4]]))));
+        assert_eq!(parse_realtime(&[DNRGB, 5, 0x01, 0x02, 1, 2, 3]), Some((5, Leds::Range(258, vec![[3, 2, 1, 0]]))));
+        assert_eq!(parse_realtime(&[0, 5, 1, 2, 3]), None);
+        assert_eq!(parse_realtime(&[DRGB]), None);
+    }
+
+    #[test]
+    fn ignores_truncated_leds() {
+        let cases: [(&[u8], Leds); 6] = [
+            (&[WARLS, 2, 0, 255, 0, 0, 1, 255], Leds::Indexed(vec![(0, [0, 0, 255, 0])])),
+            (&[DRGB, 2, 1, 2, 3, 4, 5], Leds::Range(0, vec![[3, 2, 1, 0]])),
+            (&[DRGBW, 2, 1, 2, 3], Leds::Range(0, vec![])),
+            (&[DNRGB, 2, 0, 1, 1, 2], Leds::Range(1, vec![])),
+            (&[DNRGB, 2, 0], Leds::Range(0, vec![])),
+            (&[DRGB, 2], Leds::Range(0, vec![])),
+        ];
+        for (packet, expected) in cases {
+            assert_eq!(parse_realtime(packet), Some((2, expected)), "packet {:?}", packet);
+        }
+    }
+
+    /// Feeds packets to a receiver for channels of 4 and 2 LEDs, and returns the LEDs shown
+    fn received(packets: &[&[u8]]) -> (Option<&'static str>, Vec<RawColor>, Vec<RawColor>) {
+        let realtime = RealtimeFrame::new(&[4, 2]);
+        let receiver = WledReceiver::new(vec![4, 2], realtime.clone());
+        for packet in packets {
+            receiver.receive(packet);
+        }
+
+        let mut sink = FrameBuffer::new(&[4, 2]);
+        let source = realtime.apply(&mut sink, Instant::now());
+        (source, sink.leds(0).to_vec(), sink.leds(1).to_vec())
+    }
+
+    #[test]
+    fn writes_across_the_channels() {
+        let (source, channel0, channel1) = received(&[&[DNRGB, 2, 0, 3, 1, 1, 1, 2, 2, 2, 3, 3, 3]]);
+        assert_eq!(source, Some(SOURCE));
+        assert_eq!(channel0, vec![OFF, OFF, OFF, [1, 1, 1, 0]]);
+        assert_eq!(channel1, vec![[2, 2, 2, 0], [3, 3, 3, 0]]);
+    }
+
+    #[test]
+    fn ignores_the_leds_out_of_range() {
+        let (_, channel0, channel1) = received(&[&[WARLS, 2, 5, 9, 9, 9, 6, 8, 8, 8, 200, 7, 7, 7]]);
+        assert_eq!(channel0, vec![OFF; 4]);
+        assert_eq!(channel1, vec![OFF, [9, 9, 9, 0]]);
+
+        let (_, channel0, channel1) = received(&[&[DNRGB, 2, 0, 5, 1, 1, 1, 2, 2, 2], &[DNRGB, 2, 1, 0, 3, 3, 3]]);
+        assert_eq!(channel0, vec![OFF; 4]);
+        assert_eq!(channel1, vec![OFF, [1, 1, 1, 0]]);
+    }
+
+    #[test]
+    fn hands_the_strip_back_on_a_null_timeout() {
+        assert_eq!(received(&[&[DRGB, 2, 1, 2, 3], &[DRGB, 0]]).0, None);
+        assert_eq!(received(&[&[DRGB, 2, 1, 2, 3], &[DRGB, 0, 1, 2, 3]]).0, None);
+        assert_eq!(received(&[&[DRGB, 0], &[DRGB, 255, 1, 2, 3]]).0, Some(SOURCE));
+    }
+
+    fn targets() -> Targets {
+        Targets {
+            animations: ["srainbow", "rainbow", "off", "chase"].map(String::from).to_vec(),
+            segments: vec![
+                Segment { name: "wheel".to_string(), channel: 0, start: 0, length: 4, reversed: false },
+                Segment { name: "plate".to_string(), channel: 0, start: 4, length: 2, reversed: false },
+            ],
+            scenes: vec![],
+        }
+    }
+
+    /// The wheel runs chase, the plate is off
+    fn state() -> Value {
+        json!({
+            "segments": [
+                {"segment": "wheel", "animation": "chase", "stopping": false, "brightness": 40, "color": [1, 2, 3], "speed": 2.0},
+                {"segment": "plate", "animation": "off", "stopping": false, "brightness": 100, "speed": 1.0},
+            ],
+        })
+    }
+
+    fn brightness(segment: &str, brightness: u8) -> Command {
+        Command::SetBrightness { segment: Some(segment.to_string()), channel: Some(0), brightness }
+    }
+
+    fn animation(command: &Command) -> &AnimationCommand {
+        match command {
+            Command::SetAnimation(c) => c,
+            other => panic!("expected an animation, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn brightness_keeps_the_animations() {
+        let commands = parse_state(r#"{"bri":128}"#, &state(), &targets()).unwrap();
+        assert_eq!(commands, vec![brightness("wheel", 50), brightness("plate", 50)]);
+
+        let commands = parse_state(r#"{"seg":{"id":0,"bri":255}}"#, &state(), &targets()).unwrap();
+        assert_eq!(commands, vec![brightness("wheel", 100)]);
+    }
+
+    #[test]
+    fn on_turns_the_segments_on_and_off() {
+        let commands = parse_state(r#"{"on":false}"#, &state(), &targets()).unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!((animation(&commands[0]).animation.as_str(), animation(&commands[0]).segment.as_deref()), ("off", Some("wheel")));
+
+        let commands = parse_state(r#"{"bri":0}"#, &state(), &targets()).unwrap();
+        assert_eq!(animation(&commands[0]).animation, "off");
+
+        // Toggling turns the wheel off and starts the default effect on the plate
+        let commands = parse_state(r#"{"on":"t"}"#, &state(), &targets()).unwrap();
+        let toggled: Vec<(&str, Option<&str>)> = commands.iter().map(|c| (animation(c).animation.as_str(), animation(c).segment.as_deref())).collect();
+        assert_eq!(toggled, vec![("off", Some("wheel")), (DEFAULT_EFFECT, Some("plate"))]);
+
+        assert!(parse_state(r#"{"on":"x"}"#, &state(), &targets()).is_err());
+    }
+
+    #[test]
+    fn segment_fields_restart_the_animation() {
+        // The effects are sorted by name: chase, off, rainbow, srainbow
+        let commands = parse_state(r#"{"seg":{"id":1,"fx":2,"col":[[255,0,0]]}}"#, &state(), &targets()).unwrap();
+        assert_eq!(commands.len(), 1);
+        let command = animation(&commands[0]);
+        assert_eq!((command.animation.as_str(), command.segment.as_deref()), ("rainbow", Some("plate")));
+        assert_eq!((command.params.color, command.params.brightness, command.params.speed), (Some((255, 0, 0)), 100, 1.0));
+
+        let commands = parse_state(r#"{"tt":5,"seg":[{"id":0,"sx":160,"col":["00ff00"]}]}"#, &state(), &targets()).unwrap();
+        let command = animation(&commands[0]);
+        assert_eq!(command.animation, "chase");
+        assert_eq!((command.params.color, command.params.brightness, command.params.speed), (Some((0, 255, 0)), 40, 2.0));
+        assert_eq!((command.transition, command.transition_duration), (TransitionKind::Crossfade, 0.5));
+    }
+
+    #[test]
+    fn rejects_unknown_segments_and_effects() {
+        assert!(parse_state(r#"{"seg":{"id":2,"bri":10}}"#, &state(), &targets()).is_err());
+        assert!(parse_state(r#"{"seg":{"fx":4}}"#, &state(), &targets()).is_err());
+        assert!(parse_state(r#"{"seg":{"id":0,"col":[[1]]}}"#, &state(), &targets()).is_err());
+        assert!(parse_state(r#"{"bri":"max"}"#, &state(), &targets()).is_err());
+    }
+}